@@ -2,7 +2,7 @@ use log::*;
 use url::Url;
 
 use tungstenite::client::connect_with_config;
-use tungstenite::extensions::deflate::{DeflateConfigBuilder, DeflateExt};
+use tungstenite::extensions::deflate::DeflateConfigBuilder;
 use tungstenite::protocol::WebSocketConfig;
 use tungstenite::{connect, Error, Message, Result};
 
@@ -40,11 +40,7 @@ fn run_test(case: u32) -> Result<()> {
 
     let (mut socket, _) = connect_with_config(
         case_url,
-        Some(WebSocketConfig {
-            max_send_queue: None,
-            max_frame_size: Some(16 << 20),
-            encoder: DeflateExt::new(deflate_config),
-        }),
+        Some(WebSocketConfig::with_deflate(deflate_config)),
     )?;
 
     loop {
@@ -2,10 +2,9 @@ use std::net::{TcpListener, TcpStream};
 use std::thread::spawn;
 
 use log::*;
-use tungstenite::extensions::deflate::{DeflateExt, DeflateConfigBuilder};
+use tungstenite::extensions::deflate::DeflateConfigBuilder;
 use tungstenite::handshake::HandshakeRole;
-use tungstenite::protocol::WebSocketConfig;
-use tungstenite::server::accept_with_config;
+use tungstenite::server::accept_with_deflate;
 use tungstenite::{Error, HandshakeError, Message, Result};
 
 fn must_not_block<Role: HandshakeRole>(err: HandshakeError<Role>) -> Error {
@@ -20,15 +19,7 @@ fn handle_client(stream: TcpStream) -> Result<()> {
         .max_message_size(None)
         .build();
 
-    let mut socket = accept_with_config(
-        stream,
-        Some(WebSocketConfig {
-            max_send_queue: None,
-            max_frame_size: Some(16 << 20),
-            encoder: DeflateExt::new(deflate_config),
-        }),
-    )
-    .map_err(must_not_block)?;
+    let mut socket = accept_with_deflate(stream, deflate_config).map_err(must_not_block)?;
     info!("Running test");
     loop {
         match socket.read_message()? {
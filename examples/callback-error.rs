@@ -9,6 +9,7 @@ fn main() {
     let server = TcpListener::bind("127.0.0.1:3012").unwrap();
     for stream in server.incoming() {
         spawn(move || {
+            #[allow(clippy::result_large_err)]
             let callback = |_req: &Request, _resp| {
                 let resp = Response::builder()
                     .status(StatusCode::FORBIDDEN)
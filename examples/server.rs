@@ -9,6 +9,7 @@ fn main() {
     let server = TcpListener::bind("127.0.0.1:3012").unwrap();
     for stream in server.incoming() {
         spawn(move || {
+            #[allow(clippy::result_large_err)]
             let callback = |req: &Request, mut response: Response| {
                 println!("Received a new ws handshake");
                 println!("The request's path is: {}", req.uri().path());
@@ -1,8 +1,12 @@
 //! Methods to connect to a WebSocket as a client.
 
-use std::io::{Read, Write};
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use http::Uri;
 use log::*;
@@ -12,39 +16,19 @@ use url::Url;
 use crate::handshake::client::{Request, Response};
 use crate::protocol::WebSocketConfig;
 
-#[cfg(feature = "tls")]
-mod encryption {
-    pub use native_tls::TlsStream;
-    use native_tls::{HandshakeError as TlsHandshakeError, TlsConnector};
-    use std::net::TcpStream;
+mod auth;
+mod cookies;
+mod happy_eyeballs;
+mod proxy;
 
-    pub use crate::stream::Stream as StreamSwitcher;
-    /// TCP stream switcher (plain/TLS).
-    pub type AutoStream = StreamSwitcher<TcpStream, TlsStream<TcpStream>>;
-
-    use crate::error::Result;
-    use crate::stream::Mode;
+pub use self::auth::{Authenticator, Challenge, Credentials};
+pub use self::cookies::CookieJar;
+pub use self::proxy::Proxy;
 
-    pub fn wrap_stream(stream: TcpStream, domain: &str, mode: Mode) -> Result<AutoStream> {
-        match mode {
-            Mode::Plain => Ok(StreamSwitcher::Plain(stream)),
-            Mode::Tls => {
-                let connector = TlsConnector::builder().build()?;
-                connector
-                    .connect(domain, stream)
-                    .map_err(|e| match e {
-                        TlsHandshakeError::Failure(f) => f.into(),
-                        TlsHandshakeError::WouldBlock(_) => {
-                            panic!("Bug: TLS handshake not blocked")
-                        }
-                    })
-                    .map(StreamSwitcher::Tls)
-            }
-        }
-    }
-}
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+mod encryption;
 
-#[cfg(not(feature = "tls"))]
+#[cfg(not(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls")))]
 mod encryption {
     use std::net::TcpStream;
 
@@ -54,7 +38,21 @@ mod encryption {
     /// TLS support is nod compiled in, this is just standard `TcpStream`.
     pub type AutoStream = TcpStream;
 
-    pub fn wrap_stream(stream: TcpStream, _domain: &str, mode: Mode) -> Result<AutoStream> {
+    /// A configured TLS connector for `wss://` connections. TLS support is not compiled in, so
+    /// there are none.
+    #[derive(Debug, Clone, Copy)]
+    pub enum Connector {}
+
+    pub fn build_connector(_options: &super::ConnectOptions) -> Result<Connector> {
+        Err(Error::Url("TLS support not compiled in.".into()))
+    }
+
+    pub fn wrap_stream(
+        stream: TcpStream,
+        _domain: &str,
+        mode: Mode,
+        _connector: Option<&Connector>,
+    ) -> Result<AutoStream> {
         match mode {
             Mode::Plain => Ok(stream),
             Mode::Tls => Err(Error::Url("TLS support not compiled in.".into())),
@@ -62,8 +60,8 @@ mod encryption {
     }
 }
 
-use self::encryption::wrap_stream;
-pub use self::encryption::AutoStream;
+use self::encryption::{build_connector, wrap_stream};
+pub use self::encryption::{AutoStream, Connector};
 
 use crate::error::{Error, Result};
 use crate::extensions::uncompressed::UncompressedExt;
@@ -71,7 +69,510 @@ use crate::extensions::WebSocketExtension;
 use crate::handshake::client::ClientHandshake;
 use crate::handshake::HandshakeError;
 use crate::protocol::WebSocket;
-use crate::stream::{Mode, NoDelay};
+use crate::stream::{Mode, PeerCertificates, SetTimeout};
+
+/// Options for establishing the connection in `connect_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Connect through this proxy instead of directly. The default value is `None`.
+    pub proxy: Option<Proxy>,
+    /// Pick the proxy from the standard environment variables, see `Proxy::from_env`. Only
+    /// used if `proxy` is not set. The default value is `false`.
+    pub proxy_from_env: bool,
+    /// Send cookies from this jar with the handshake request and store the cookies set by the
+    /// server in it. The default value is `None`.
+    pub cookie_jar: Option<CookieJar>,
+    /// Supplies credentials if the server answers the handshake request with
+    /// `401 Unauthorized`, to send the request again with them. The default value is `None`.
+    pub authenticator: Option<Authenticator>,
+    /// If the server does not support version 13 of the WebSocket protocol but lists version 8,
+    /// the handshake is retried with that version; otherwise `Error::UnsupportedVersion` tells
+    /// which versions the server supports. The default value is `false`.
+    pub version_fallback: bool,
+    /// The maximum time the TLS and WebSocket handshakes may take together once the connection
+    /// is established, so that a server which accepts the connection but never answers the
+    /// ClientHello or the upgrade request cannot block forever. `None` means no limit. The default value is `None`.
+    pub handshake_timeout: Option<Duration>,
+    /// Resolve host names with this resolver instead of the system one. The default value is
+    /// `None`.
+    pub resolver: Option<Resolver>,
+    /// When the host name resolves to both IPv4 and IPv6 addresses, connect the RFC 8305 way:
+    /// alternate between the address families and start a new attempt every 250 ms, in
+    /// parallel, instead of trying one address after another. The default value is `false`.
+    pub happy_eyeballs: bool,
+    /// The maximum time a single TCP connection attempt may take, so that filtered ports fail
+    /// fast instead of hanging until the system gives up. Applies to every resolved address
+    /// tried and to the connection to a proxy. Must not be zero. `None` means the system
+    /// default. The default value is `None`.
+    pub connect_timeout: Option<Duration>,
+    /// Options applied to the TCP socket before TLS and the WebSocket handshake.
+    pub socket: SocketOptions,
+    /// Additional URL schemes to accept, or different default ports for `ws` and `wss`. The
+    /// default value is empty, i.e. only `ws` on port 80 and `wss` on port 443.
+    pub schemes: Vec<Scheme>,
+    /// Use TLS or not regardless of the URL scheme, e.g. to speak `wss://` through a tunnel that
+    /// already encrypts the traffic. `None` means the scheme decides. The default value is
+    /// `None`.
+    pub mode: Option<Mode>,
+    /// Do TLS with this connector instead of one with the default settings, e.g. to trust
+    /// custom root certificates or to restrict the protocol versions. The default value is
+    /// `None`.
+    pub connector: Option<Connector>,
+    /// Present this certificate to the server during the TLS handshake, for servers requiring
+    /// mutual TLS. Ignored if `connector` is set: configure the identity on the connector
+    /// then. The default value is `None`.
+    pub identity: Option<ClientIdentity>,
+    /// Offer these application protocols with ALPN during the TLS handshake, most preferred
+    /// first, e.g. `"http/1.1"`. The one the server picked can be read with
+    /// `stream::NegotiatedAlpn` afterwards. Ignored if `connector` is set: configure ALPN on the
+    /// connector then. The default value is empty.
+    pub alpn_protocols: Vec<String>,
+    /// The name of the server for TLS, sent with SNI and checked against its certificate,
+    /// instead of the host of the `Host` header or the URL, e.g. to connect to an IP address
+    /// with a certificate for a known name. The default value is `None`.
+    pub server_name: Option<String>,
+    /// Accept any certificate of the server, even expired or self-signed ones, e.g. for local
+    /// development. This leaves the connection open to man-in-the-middle attacks. Ignored if
+    /// `connector` is set. The default value is `false`.
+    pub danger_accept_invalid_certs: bool,
+    /// Accept a certificate of the server which is valid but for another name. This leaves the
+    /// connection open to man-in-the-middle attacks. Ignored if `connector` is set. The default
+    /// value is `false`.
+    pub danger_accept_invalid_hostnames: bool,
+    /// Check the certificates of the server after the TLS handshake, before the WebSocket
+    /// handshake, e.g. to pin them. Used with `connector` as well. The default value is `None`.
+    pub certificate_verifier: Option<CertificateVerifier>,
+    /// Do TLS with this backend, which has to be enabled with its cargo feature. Ignored if
+    /// `connector` is set. `None` means native-tls if enabled, else rustls, else openssl. The
+    /// default value is `None`.
+    pub tls_backend: Option<TlsBackend>,
+}
+
+/// A TLS backend for `ConnectOptions::tls_backend`.
+///
+/// All backends are listed whatever the enabled cargo features are, so they can be picked
+/// without conditional code; connecting fails if the picked one is not enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsBackend {
+    /// native-tls, enabled with the "tls" feature.
+    NativeTls,
+    /// rustls, enabled with one of the "rustls-tls-*-roots" features.
+    Rustls,
+    /// openssl, enabled with the "openssl-tls" feature.
+    OpenSsl,
+}
+
+impl ConnectOptions {
+    /// Returns true if the options ask for a TLS connector other than the default one.
+    fn customizes_tls(&self) -> bool {
+        self.identity.is_some()
+            || !self.alpn_protocols.is_empty()
+            || self.danger_accept_invalid_certs
+            || self.danger_accept_invalid_hostnames
+            || self.tls_backend.is_some()
+    }
+}
+
+/// A client certificate with its private key, for mutual TLS.
+#[derive(Clone)]
+pub struct ClientIdentity(IdentityKind);
+
+// Not every TLS backend supports every kind, and without TLS none is used.
+#[allow(dead_code)]
+#[derive(Clone)]
+enum IdentityKind {
+    Pkcs12 { der: Vec<u8>, password: String },
+    Pem { cert_chain: Vec<u8>, key: Vec<u8> },
+}
+
+impl ClientIdentity {
+    /// An identity from a DER-encoded PKCS #12 archive, which is not supported with rustls.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Self {
+        ClientIdentity(IdentityKind::Pkcs12 {
+            der: der.to_vec(),
+            password: password.to_owned(),
+        })
+    }
+
+    /// An identity from a PEM-encoded certificate chain, starting with the certificate of the
+    /// client, and a PEM-encoded PKCS #8 private key.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Self {
+        ClientIdentity(IdentityKind::Pem {
+            cert_chain: cert_chain.to_vec(),
+            key: key.to_vec(),
+        })
+    }
+}
+
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep the key and the password out of logs.
+        match self.0 {
+            IdentityKind::Pkcs12 { .. } => f.write_str("ClientIdentity(PKCS #12)"),
+            IdentityKind::Pem { .. } => f.write_str("ClientIdentity(PEM)"),
+        }
+    }
+}
+
+/// A URL scheme for `connect_with_options`.
+#[derive(Debug, Clone)]
+pub struct Scheme {
+    /// The name of the scheme, e.g. `wss+internal`. Matched case-insensitively.
+    pub name: String,
+    /// Whether to use TLS.
+    pub mode: Mode,
+    /// The port to connect to if the URL does not name one.
+    pub default_port: u16,
+}
+
+impl Scheme {
+    /// Create a scheme mapping.
+    pub fn new<N: Into<String>>(name: N, mode: Mode, default_port: u16) -> Self {
+        Scheme {
+            name: name.into(),
+            mode,
+            default_port,
+        }
+    }
+
+    /// Get the mode and the default port for the scheme of `uri`, looking at the custom
+    /// `schemes` before the standard ones.
+    fn lookup(schemes: &[Scheme], uri: &Uri) -> Result<(Mode, u16)> {
+        let scheme = uri.scheme_str().unwrap_or("");
+        if let Some(custom) = schemes.iter().find(|s| s.name.eq_ignore_ascii_case(scheme)) {
+            return Ok((custom.mode, custom.default_port));
+        }
+        Ok(match uri_mode(uri)? {
+            Mode::Plain => (Mode::Plain, 80),
+            Mode::Tls => (Mode::Tls, 443),
+        })
+    }
+}
+
+/// TCP socket options for `connect_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`), so that small messages are sent right away.
+    /// The default value is `true`.
+    pub nodelay: bool,
+    /// Enable TCP keepalive (`SO_KEEPALIVE`), sending the first probe after the connection
+    /// was idle for the given time. `None` leaves the system setting alone. The default value
+    /// is `None`.
+    pub keepalive: Option<Duration>,
+    /// The size of the send buffer (`SO_SNDBUF`). `None` leaves the system setting alone. The
+    /// default value is `None`.
+    pub send_buffer_size: Option<usize>,
+    /// The size of the receive buffer (`SO_RCVBUF`). `None` leaves the system setting alone.
+    /// The default value is `None`.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Apply the options to a connected stream.
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = socket2::SockRef::from(stream);
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(time))?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// A host name resolver for `connect_with_options`.
+///
+/// Wraps a function turning a host name and a port into the socket addresses to try, which
+/// allows caching resolvers, DNS-over-HTTPS, service discovery and the like to be plugged in.
+#[derive(Clone)]
+pub struct Resolver(Arc<dyn Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Send + Sync>);
+
+impl Resolver {
+    /// Create a resolver from a function.
+    pub fn new<F>(resolve: F) -> Self
+    where
+        F: Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Send + Sync + 'static,
+    {
+        Resolver(Arc::new(resolve))
+    }
+
+    /// Resolve a host name, with the system resolver if `resolver` is `None`.
+    fn resolve(resolver: Option<&Resolver>, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match resolver {
+            Some(resolver) => (resolver.0)(host, port),
+            None => Ok((host, port).to_socket_addrs()?.collect()),
+        }
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Resolver(..)")
+    }
+}
+
+/// A check of the certificates of the server for `connect_with_options`, in addition to the
+/// verification of the TLS connector.
+///
+/// Wraps a function receiving the DER-encoded certificates, the one of the server first, see
+/// `stream::PeerCertificates`, and returning why it rejects them, if it does. Such a function
+/// can compare the certificates or their public keys against pinned ones:
+///
+/// ```
+/// use tungstenite::client::CertificateVerifier;
+///
+/// let pinned: Vec<u8> = Vec::new(); // The DER-encoded certificate of the server.
+/// let verifier = CertificateVerifier::new(move |certificates| {
+///     match certificates.first() {
+///         Some(certificate) if *certificate == pinned => Ok(()),
+///         _ => Err("Not the pinned certificate".into()),
+///     }
+/// });
+/// ```
+#[derive(Clone)]
+pub struct CertificateVerifier(Arc<dyn Fn(&[Vec<u8>]) -> StdResult<(), String> + Send + Sync>);
+
+impl CertificateVerifier {
+    /// Create a verifier from a function.
+    pub fn new<F>(verify: F) -> Self
+    where
+        F: Fn(&[Vec<u8>]) -> StdResult<(), String> + Send + Sync + 'static,
+    {
+        CertificateVerifier(Arc::new(verify))
+    }
+
+    /// Check the certificates of the server on `stream`.
+    fn verify<S: PeerCertificates>(&self, stream: &S) -> Result<()> {
+        (self.0)(&stream.peer_certificates()).map_err(|reason| {
+            debug!("Server certificates rejected: {}", reason);
+            Error::CertificateRejected(reason.into())
+        })
+    }
+}
+
+impl fmt::Debug for CertificateVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CertificateVerifier(..)")
+    }
+}
+
+/// Connect to the given WebSocket in blocking mode.
+///
+/// Works like `connect_with_config`, with `options` controlling how the underlying
+/// connection is established, e.g. through a proxy. With a proxy, the tunnel is set up before
+/// TLS and the WebSocket handshake, so both happen end-to-end with the server.
+pub fn connect_with_options<Req, Ext>(
+    request: Req,
+    config: Option<WebSocketConfig<Ext>>,
+    options: ConnectOptions,
+) -> Result<(WebSocket<AutoStream, Ext>, Response)>
+where
+    Req: IntoClientRequest,
+    Ext: WebSocketExtension,
+{
+    let mut request: Request = request.into_client_request()?;
+    if let Some(ref jar) = options.cookie_jar {
+        jar.add_to_request(&mut request);
+    }
+    let uri = request.uri();
+    let (mode, default_port) = Scheme::lookup(&options.schemes, uri)?;
+    let mode = options.mode.unwrap_or(mode);
+    let host = request
+        .uri()
+        .host()
+        .ok_or_else(|| Error::Url("No host name in the URL".into()))?;
+    // A `Host` header set on the request is the name of the server for TLS as well, while the
+    // address to connect to still comes from the URL.
+    let tls_host = match request.headers().get(http::header::HOST) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<http::uri::Authority>().ok())
+            .ok_or_else(|| Error::Url("Invalid Host header".into()))?,
+        None => uri
+            .authority()
+            .expect("Bug: no authority with a host")
+            .clone(),
+    };
+    // IPv6 literals come in brackets, which are only needed in URLs and Host headers.
+    let addr_host = host.trim_start_matches('[').trim_end_matches(']');
+    let domain = match options.server_name {
+        Some(ref name) => name.as_str(),
+        None => tls_host
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+    let port = uri.port_u16().unwrap_or(default_port);
+    let built_connector = match (mode, &options.connector) {
+        (Mode::Tls, None) if options.customizes_tls() => Some(build_connector(&options)?),
+        _ => None,
+    };
+    let proxy = match options.proxy {
+        Some(proxy) => Some(proxy),
+        None if options.proxy_from_env => Proxy::from_env(uri)?,
+        None => None,
+    };
+    let socket_options = options.socket;
+    let connect_timeout = options.connect_timeout;
+    let handshake_timeout = options.handshake_timeout;
+    let connector = options.connector.as_ref().or(built_connector.as_ref());
+
+    let timed_out = || {
+        Error::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Handshake timed out",
+        ))
+    };
+    // The handshake timeout covers TLS as well, so that a server which never answers the
+    // ClientHello cannot block forever either. It starts once the TCP connection is up.
+    let deadline = Cell::new(None);
+    let wrap = |mut stream: TcpStream| {
+        socket_options.apply(&stream)?;
+        if let Some(timeout) = handshake_timeout {
+            deadline.set(Some(Instant::now() + timeout));
+            stream.set_timeout(Some(timeout))?;
+        }
+        wrap_stream(stream, domain, mode, connector).map_err(|e| match e {
+            // Timeouts show up as WouldBlock on some platforms.
+            Error::Io(ref e)
+                if handshake_timeout.is_some()
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                timed_out()
+            }
+            e => e,
+        })
+    };
+    let stream = match proxy {
+        Some(ref proxy) => {
+            let resolver = options.resolver.as_ref();
+            let resolve = |host: &str, port| Resolver::resolve(resolver, host, port);
+            wrap(proxy.connect(host, port, resolve, connect_timeout)?)?
+        }
+        None => {
+            let addrs = Resolver::resolve(options.resolver.as_ref(), addr_host, port)?;
+            let dual_stack =
+                addrs.iter().any(SocketAddr::is_ipv4) && addrs.iter().any(SocketAddr::is_ipv6);
+            if options.happy_eyeballs && dual_stack {
+                wrap(happy_eyeballs::connect(&addrs, connect_timeout)?)?
+            } else {
+                connect_to_some(&addrs, request.uri(), connect_timeout, wrap)?
+            }
+        }
+    };
+    if let (Mode::Tls, Some(verifier)) = (mode, &options.certificate_verifier) {
+        verifier.verify(&stream)?;
+    }
+
+    let uri = request.uri().clone();
+    let mut handshake = ClientHandshake::start(stream, request, config)?;
+    if let Some(jar) = options.cookie_jar {
+        handshake = handshake.with_cookie_jar(jar, uri);
+    }
+    if let Some(authenticator) = options.authenticator {
+        handshake = handshake.with_authenticator(authenticator);
+    }
+    if options.version_fallback {
+        handshake = handshake.with_version_fallback();
+    }
+    let deadline = match deadline.get() {
+        Some(deadline) => deadline,
+        None => {
+            return handshake.handshake().map_err(|e| match e {
+                HandshakeError::Failure(f) => f,
+                HandshakeError::Interrupted(_) => panic!("Bug: blocking handshake not blocked"),
+            })
+        }
+    };
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(timed_out());
+        }
+        handshake.get_mut().get_mut().set_timeout(Some(remaining))?;
+        match handshake.handshake() {
+            Ok((mut socket, response)) => {
+                socket.get_mut().set_timeout(None)?;
+                return Ok((socket, response));
+            }
+            // Timeouts show up as WouldBlock on some platforms.
+            Err(HandshakeError::Interrupted(mid)) => handshake = mid,
+            Err(HandshakeError::Failure(Error::Io(e))) if e.kind() == io::ErrorKind::TimedOut => {
+                return Err(timed_out())
+            }
+            Err(HandshakeError::Failure(e)) => return Err(e),
+        }
+    }
+}
+
+/// Connect to the first of several equivalent endpoints that accepts the connection, in
+/// blocking mode.
+///
+/// The endpoints are tried in order, e.g. a primary URL followed by its backups, each with
+/// `connect_with_options`; set `options.connect_timeout` and `options.handshake_timeout` to
+/// bound every attempt. `config` is called for each attempt to provide its configuration.
+/// Returns the index of the endpoint connected to along with the socket and the response, or
+/// the error of the last attempt if all of them fail.
+///
+/// ```no_run
+/// use tungstenite::client::{connect_failover, ConnectOptions};
+/// use tungstenite::extensions::uncompressed::UncompressedExt;
+/// use std::time::Duration;
+///
+/// let options = ConnectOptions {
+///     connect_timeout: Some(Duration::from_secs(3)),
+///     handshake_timeout: Some(Duration::from_secs(5)),
+///     ..ConnectOptions::default()
+/// };
+/// let endpoints = ["wss://primary.example.com/feed", "wss://backup.example.com/feed"];
+/// let (socket, response, index) =
+///     connect_failover::<_, UncompressedExt, _>(endpoints.iter().copied(), || None, options)
+///         .unwrap();
+/// println!("Connected to {}", endpoints[index]);
+/// ```
+pub fn connect_failover<I, Ext, F>(
+    endpoints: I,
+    mut config: F,
+    options: ConnectOptions,
+) -> Result<(WebSocket<AutoStream, Ext>, Response, usize)>
+where
+    I: IntoIterator,
+    I::Item: IntoClientRequest,
+    Ext: WebSocketExtension,
+    F: FnMut() -> Option<WebSocketConfig<Ext>>,
+{
+    let mut last_error = None;
+    for (index, endpoint) in endpoints.into_iter().enumerate() {
+        match connect_with_options(endpoint, config(), options.clone()) {
+            Ok((socket, response)) => return Ok((socket, response, index)),
+            Err(e) => {
+                debug!("Failed to connect to endpoint {}: {}", index, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| Error::Url("No endpoints to connect to".into())))
+}
 
 /// Connect to the given WebSocket in blocking mode.
 ///
@@ -79,15 +580,16 @@ use crate::stream::{Mode, NoDelay};
 /// equal to calling `connect()` function.
 ///
 /// The URL may be either ws:// or wss://.
-/// To support wss:// URLs, feature "tls" must be turned on.
+/// To support wss:// URLs, feature "tls", "openssl-tls" or one of the "rustls-tls-*-roots"
+/// features must be turned on.
 ///
 /// This function "just works" for those who wants a simple blocking solution
 /// similar to `std::net::TcpStream`. If you want a non-blocking or other
 /// custom stream, call `client` instead.
 ///
-/// This function uses `native_tls` to do TLS. If you want to use other TLS libraries,
-/// use `client` instead. There is no need to enable the "tls" feature if you don't call
-/// `connect` since it's the only function that uses native_tls.
+/// This function uses `native_tls` to do TLS, or `rustls` without the "tls" feature, or
+/// `openssl` without either. If you want to use other TLS libraries, use `client` instead. There is no need to enable the TLS
+/// features if you don't call `connect` since it's the only function that uses them.
 pub fn connect_with_config<Req, Ext>(
     request: Req,
     config: Option<WebSocketConfig<Ext>>,
@@ -96,57 +598,156 @@ where
     Req: IntoClientRequest,
     Ext: WebSocketExtension,
 {
-    let request: Request = request.into_client_request()?;
-    let uri = request.uri();
-    let mode = uri_mode(uri)?;
-    let host = request
-        .uri()
-        .host()
-        .ok_or_else(|| Error::Url("No host name in the URL".into()))?;
-    let port = uri.port_u16().unwrap_or(match mode {
-        Mode::Plain => 80,
-        Mode::Tls => 443,
-    });
-    let addrs = (host, port).to_socket_addrs()?;
-    let mut stream = connect_to_some(addrs.as_slice(), &request.uri(), mode)?;
-    NoDelay::set_nodelay(&mut stream, true)?;
-    client_with_config(request, stream, config).map_err(|e| match e {
-        HandshakeError::Failure(f) => f,
-        HandshakeError::Interrupted(_) => panic!("Bug: blocking handshake not blocked"),
-    })
+    connect_with_options(request, config, ConnectOptions::default())
 }
 
 /// Connect to the given WebSocket in blocking mode.
 ///
 /// The URL may be either ws:// or wss://.
-/// To support wss:// URLs, feature "tls" must be turned on.
+/// To support wss:// URLs, feature "tls", "openssl-tls" or one of the "rustls-tls-*-roots"
+/// features must be turned on.
 ///
 /// This function "just works" for those who wants a simple blocking solution
 /// similar to `std::net::TcpStream`. If you want a non-blocking or other
 /// custom stream, call `client` instead.
 ///
-/// This function uses `native_tls` to do TLS. If you want to use other TLS libraries,
-/// use `client` instead. There is no need to enable the "tls" feature if you don't call
-/// `connect` since it's the only function that uses native_tls.
+/// This function uses `native_tls` to do TLS, or `rustls` without the "tls" feature, or
+/// `openssl` without either. If you want to use other TLS libraries, use `client` instead. There is no need to enable the TLS
+/// features if you don't call `connect` since it's the only function that uses them.
+///
+/// Along with the socket, the server's handshake response is returned, so the status code and
+/// any headers the server sent, such as `Set-Cookie` or `Sec-WebSocket-Extensions`, can be
+/// inspected.
 pub fn connect<Req: IntoClientRequest>(
     request: Req,
 ) -> Result<(WebSocket<AutoStream, UncompressedExt>, Response)> {
     connect_with_config(request, None)
 }
 
-fn connect_to_some(addrs: &[SocketAddr], uri: &Uri, mode: Mode) -> Result<AutoStream> {
-    let domain = uri
-        .host()
-        .ok_or_else(|| Error::Url("No host name in the URL".into()))?;
+/// Connect to the given WebSocket in blocking mode, doing TLS with `connector`.
+///
+/// This function does the same as `connect()`, but with a TLS connector configured by the
+/// application, e.g. with custom root certificates, protocol versions or cipher suites,
+/// instead of one with the default settings:
+///
+/// ```no_run
+/// # #[cfg(feature = "tls")]
+/// # {
+/// use tungstenite::client::{connect_tls_with_connector, Connector};
+///
+/// let connector = native_tls::TlsConnector::builder()
+///     .min_protocol_version(Some(native_tls::Protocol::Tlsv12))
+///     .build()
+///     .unwrap();
+/// let (socket, _) =
+///     connect_tls_with_connector("wss://example.com/socket", Connector::NativeTls(connector))
+///         .unwrap();
+/// # }
+/// ```
+pub fn connect_tls_with_connector<Req: IntoClientRequest>(
+    request: Req,
+    connector: Connector,
+) -> Result<(WebSocket<AutoStream, UncompressedExt>, Response)> {
+    let options = ConnectOptions {
+        connector: Some(connector),
+        ..ConnectOptions::default()
+    };
+    connect_with_options(request, None, options)
+}
+
+/// Connect to the given WebSocket in blocking mode, at one of the given addresses.
+///
+/// No name resolution takes place: the addresses are tried in order until a connection is
+/// established. The request still provides the `Host` header and, for `wss://` URLs, the
+/// server name for TLS. Passing `None` as configuration uses the default one.
+pub fn connect_addrs<Req, Ext>(
+    addrs: &[SocketAddr],
+    request: Req,
+    config: Option<WebSocketConfig<Ext>>,
+) -> Result<(WebSocket<AutoStream, Ext>, Response)>
+where
+    Req: IntoClientRequest,
+    Ext: WebSocketExtension,
+{
+    let addrs = addrs.to_vec();
+    let options = ConnectOptions {
+        resolver: Some(Resolver::new(move |_, _| Ok(addrs.clone()))),
+        ..ConnectOptions::default()
+    };
+    connect_with_options(request, config, options)
+}
+
+/// Connect to the given WebSocket in blocking mode, offering permessage-deflate compression
+/// (RFC 7692) with the default settings.
+///
+/// Compression is only used if the server accepts it. Use `connect_with_config` with
+/// `WebSocketConfig::with_deflate` to change the settings.
+#[cfg(feature = "__deflate")]
+pub fn connect_deflate<Req: IntoClientRequest>(
+    request: Req,
+) -> Result<(
+    WebSocket<AutoStream, crate::extensions::deflate::DeflateExt>,
+    Response,
+)> {
+    let config = WebSocketConfig::with_deflate(Default::default());
+    connect_with_config(request, Some(config))
+}
+
+/// Connect to a WebSocket server listening on a Unix domain socket, in blocking mode.
+///
+/// The request URI is only used for the handshake request, e.g. `ws://localhost/events` sends
+/// `Host: localhost` and requests `/events`. Passing `None` as configuration uses the default
+/// one.
+#[cfg(unix)]
+pub fn connect_unix<P, Req, Ext>(
+    path: P,
+    request: Req,
+    config: Option<WebSocketConfig<Ext>>,
+) -> Result<(WebSocket<std::os::unix::net::UnixStream, Ext>, Response)>
+where
+    P: AsRef<std::path::Path>,
+    Req: IntoClientRequest,
+    Ext: WebSocketExtension,
+{
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    client_with_config(request, stream, config).map_err(|e| match e {
+        HandshakeError::Failure(f) => f,
+        HandshakeError::Interrupted(_) => panic!("Bug: blocking handshake not blocked"),
+    })
+}
+
+/// Connect to the first address that accepts the connection, setting it up with `wrap`.
+///
+/// If `wrap` fails for every address, its last error is returned.
+fn connect_to_some<F>(
+    addrs: &[SocketAddr],
+    uri: &Uri,
+    timeout: Option<Duration>,
+    wrap: F,
+) -> Result<AutoStream>
+where
+    F: Fn(TcpStream) -> Result<AutoStream>,
+{
+    let mut last_error = None;
     for addr in addrs {
         debug!("Trying to contact {} at {}...", uri, addr);
-        if let Ok(raw_stream) = TcpStream::connect(addr) {
-            if let Ok(stream) = wrap_stream(raw_stream, domain, mode) {
-                return Ok(stream);
-            }
+        match tcp_connect(addr, timeout) {
+            Ok(raw_stream) => match wrap(raw_stream) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            },
+            Err(e) => last_error = Some(Error::Io(e)),
         }
     }
-    Err(Error::Url(format!("Unable to connect to {}", uri).into()))
+    Err(last_error.unwrap_or_else(|| Error::Url(format!("Unable to connect to {}", uri).into())))
+}
+
+/// Open a TCP connection, giving up after `timeout` if one is given.
+fn tcp_connect(addr: &SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+        None => TcpStream::connect(addr),
+    }
 }
 
 /// Get the mode of the given URL.
@@ -185,6 +786,26 @@ where
 /// Use this function if you need a nonblocking handshake support or if you
 /// want to use a custom stream like `mio::tcp::TcpStream` or `openssl::ssl::SslStream`.
 /// Any stream supporting `Read + Write` will do.
+///
+/// Nothing but the HTTP upgrade happens here: no name resolution, no TCP connection and no
+/// TLS, so the stream may be connected, tuned and encrypted any way you like beforehand. The
+/// request URI only provides the request target and the `Host` header; its scheme is not
+/// checked. Headers set on the request are sent as they are:
+///
+/// ```no_run
+/// use std::net::TcpStream;
+/// use tungstenite::http::Request;
+///
+/// let stream = TcpStream::connect("10.0.0.5:8080").unwrap();
+/// stream.set_nodelay(true).unwrap();
+/// let request = Request::builder()
+///     .uri("ws://chat.example.com/socket")
+///     .header("Authorization", "Bearer 123")
+///     .header("Sec-WebSocket-Protocol", "chat");
+/// let (socket, response) = tungstenite::client(request, stream).unwrap();
+/// ```
+///
+/// Use `client_with_config` to enable extensions such as permessage-deflate.
 pub fn client<Stream, Req>(
     request: Req,
     stream: Stream,
@@ -201,20 +822,35 @@ where
 
 /// Trait for converting various types into HTTP requests used for a client connection.
 ///
-/// This trait is implemented by default for string slices, strings, `url::Url`, `http::Uri` and
-/// `http::Request<()>`.
+/// This trait is implemented by default for string slices, strings, `url::Url`, `http::Uri`,
+/// `http::Request<()>` and `http::request::Builder`. Use the latter two to send custom headers,
+/// such as `Authorization`, with the handshake request:
+///
+/// ```no_run
+/// use tungstenite::http::Request;
+///
+/// let request = Request::builder()
+///     .uri("ws://localhost:3012/socket")
+///     .header("Authorization", "Bearer 123")
+///     .header("X-Api-Key", "secret");
+/// let (socket, response) = tungstenite::connect(request).unwrap();
+/// ```
+///
+/// Subprotocols are offered the same way, with one or more `Sec-WebSocket-Protocol` headers. The
+/// handshake fails if the server picks one that was not offered; the selected one is available
+/// from `WebSocket::protocol` afterwards.
 pub trait IntoClientRequest {
     /// Convert into a `Request` that can be used for a client connection.
     fn into_client_request(self) -> Result<Request>;
 }
 
-impl<'a> IntoClientRequest for &'a str {
+impl IntoClientRequest for &str {
     fn into_client_request(self) -> Result<Request> {
         self.parse::<Uri>()?.into_client_request()
     }
 }
 
-impl<'a> IntoClientRequest for &'a String {
+impl IntoClientRequest for &String {
     fn into_client_request(self) -> Result<Request> {
         <&str as IntoClientRequest>::into_client_request(self)
     }
@@ -226,7 +862,7 @@ impl IntoClientRequest for String {
     }
 }
 
-impl<'a> IntoClientRequest for &'a Uri {
+impl IntoClientRequest for &Uri {
     fn into_client_request(self) -> Result<Request> {
         self.clone().into_client_request()
     }
@@ -238,7 +874,7 @@ impl IntoClientRequest for Uri {
     }
 }
 
-impl<'a> IntoClientRequest for &'a Url {
+impl IntoClientRequest for &Url {
     fn into_client_request(self) -> Result<Request> {
         self.as_str().into_client_request()
     }
@@ -256,9 +892,182 @@ impl IntoClientRequest for Request {
     }
 }
 
+impl IntoClientRequest for http::request::Builder {
+    fn into_client_request(self) -> Result<Request> {
+        Ok(self.body(())?)
+    }
+}
+
 impl<'h, 'b> IntoClientRequest for httparse::Request<'h, 'b> {
     fn into_client_request(self) -> Result<Request> {
         use crate::handshake::headers::FromHttparse;
         Request::from_httparse(self)
     }
 }
+
+/// Builder for client handshake requests, for the common cases not worth building an
+/// `http::Request` by hand for.
+///
+/// ```no_run
+/// use tungstenite::client::ClientRequestBuilder;
+///
+/// let request = ClientRequestBuilder::new("wss://api.example.com/stream".parse().unwrap())
+///     .with_bearer("secret-token")
+///     .with_sub_protocol("v2.stream");
+/// let (socket, response) = tungstenite::connect(request).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientRequestBuilder {
+    uri: Uri,
+    /// Additional headers, in the order they were added.
+    headers: Vec<(String, String)>,
+    /// Subprotocols to offer to the server.
+    subprotocols: Vec<String>,
+}
+
+impl ClientRequestBuilder {
+    /// Start building a request to the given URI.
+    pub fn new(uri: Uri) -> Self {
+        ClientRequestBuilder {
+            uri,
+            headers: Vec::new(),
+            subprotocols: Vec::new(),
+        }
+    }
+
+    /// Add a header to the request.
+    pub fn with_header<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Authenticate with a bearer token (RFC 6750) in the `Authorization` header.
+    pub fn with_bearer<T: AsRef<str>>(self, token: T) -> Self {
+        let value = format!("Bearer {}", token.as_ref());
+        self.with_header("Authorization", value)
+    }
+
+    /// Send the given `Host` header instead of the host from the URI.
+    ///
+    /// `connect` and friends still connect to the host from the URI, but use this host name for
+    /// TLS (SNI and certificate verification), e.g. to reach a fronting host by IP address.
+    pub fn with_host<H: Into<String>>(self, host: H) -> Self {
+        self.with_header("Host", host)
+    }
+
+    /// Use the given nonce for the `Sec-WebSocket-Key` header instead of a random one.
+    pub fn with_key(self, nonce: [u8; 16]) -> Self {
+        self.with_header("Sec-WebSocket-Key", base64::encode(nonce))
+    }
+
+    /// Offer a subprotocol to the server. May be called multiple times, in order of preference.
+    pub fn with_sub_protocol<P: Into<String>>(mut self, protocol: P) -> Self {
+        self.subprotocols.push(protocol.into());
+        self
+    }
+}
+
+impl IntoClientRequest for ClientRequestBuilder {
+    fn into_client_request(self) -> Result<Request> {
+        let mut request = self.uri.into_client_request()?;
+        let headers = request.headers_mut();
+        for (key, value) in self.headers {
+            headers.append(
+                http::header::HeaderName::from_bytes(key.as_bytes())?,
+                value.parse()?,
+            );
+        }
+        if !self.subprotocols.is_empty() {
+            headers.insert(
+                "Sec-WebSocket-Protocol",
+                self.subprotocols.join(", ").parse()?,
+            );
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_to_some, ClientRequestBuilder, IntoClientRequest, Scheme, SocketOptions};
+    use crate::{error::Error, stream::Mode};
+    use std::io;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    #[test]
+    fn scheme_lookup() {
+        let schemes = [
+            Scheme::new("wss+internal", Mode::Tls, 8443),
+            Scheme::new("ws", Mode::Plain, 8080),
+        ];
+        let lookup = |uri: &str| {
+            Scheme::lookup(&schemes, &uri.parse().unwrap())
+                .map(|(mode, port)| (matches!(mode, Mode::Tls), port))
+                .ok()
+        };
+        assert_eq!(lookup("WSS+internal://host/"), Some((true, 8443)));
+        assert_eq!(lookup("ws://host/"), Some((false, 8080)));
+        assert_eq!(lookup("wss://host/"), Some((true, 443)));
+        assert_eq!(lookup("http://host/"), None);
+    }
+
+    #[test]
+    fn socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let options = SocketOptions {
+            nodelay: false,
+            keepalive: Some(Duration::from_secs(30)),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+        };
+        options.apply(&stream).unwrap();
+
+        let socket = socket2::SockRef::from(&stream);
+        assert!(!socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        // The system may round the buffer sizes up.
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        SocketOptions::default().apply(&stream).unwrap();
+        assert!(socket.nodelay().unwrap());
+    }
+
+    #[test]
+    fn connect_error() {
+        // Nothing listens on the port once the listener is dropped.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let uri = format!("ws://{}/", addr).parse().unwrap();
+        let result = connect_to_some(&[addr], &uri, None, |_| unreachable!());
+        match result {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            _ => panic!("expected a connection error"),
+        }
+    }
+
+    #[test]
+    fn request_builder() {
+        let request = ClientRequestBuilder::new("ws://localhost/".parse().unwrap())
+            .with_bearer("token")
+            .with_header("X-Trace", "1")
+            .with_header("X-Trace", "2")
+            .with_sub_protocol("chat")
+            .with_sub_protocol("superchat")
+            .into_client_request()
+            .unwrap();
+        let headers = request.headers();
+        assert_eq!(headers["Authorization"], "Bearer token");
+        let traces: Vec<_> = headers.get_all("X-Trace").iter().collect();
+        assert_eq!(traces, vec!["1", "2"]);
+        assert_eq!(headers["Sec-WebSocket-Protocol"], "chat, superchat");
+    }
+}
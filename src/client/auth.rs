@@ -0,0 +1,393 @@
+//! HTTP authentication (RFC 7235) for client handshakes.
+
+use std::fmt;
+use std::sync::Arc;
+
+use http::header::WWW_AUTHENTICATE;
+use http::HeaderValue;
+use log::*;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::handshake::client::{Request, Response};
+
+/// An authentication challenge from a `WWW-Authenticate` header of a server response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    scheme: String,
+    /// Parameters with lower case names and unquoted values.
+    params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    /// The authentication scheme, e.g. `Basic` or `Digest`.
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// The protection space the credentials are asked for, if the server named one.
+    pub fn realm(&self) -> Option<&str> {
+        self.param("realm")
+    }
+
+    /// Get a parameter of the challenge. The name is matched case-insensitively.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is_scheme(&self, scheme: &str) -> bool {
+        self.scheme.eq_ignore_ascii_case(scheme)
+    }
+}
+
+/// A user name and password to answer a challenge with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// The user name.
+    pub username: String,
+    /// The password.
+    pub password: String,
+}
+
+impl Credentials {
+    /// Create credentials from a user name and a password.
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Credentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// Supplies credentials when the server answers the handshake request with `401 Unauthorized`.
+///
+/// The function gets the challenge to answer and returns the credentials to retry the handshake
+/// with, or `None` to give up. `Digest` challenges (RFC 7616, with the MD5 and SHA-256
+/// algorithms) are preferred over `Basic` ones (RFC 7617); the function is called for each
+/// supported challenge in that order until it returns credentials.
+#[derive(Clone)]
+pub struct Authenticator(Arc<dyn Fn(&Challenge) -> Option<Credentials> + Send + Sync>);
+
+impl Authenticator {
+    /// Create an authenticator from a function.
+    pub fn new<F>(credentials: F) -> Self
+    where
+        F: Fn(&Challenge) -> Option<Credentials> + Send + Sync + 'static,
+    {
+        Authenticator(Arc::new(credentials))
+    }
+
+    /// Compute the `Authorization` header answering the challenges of a `401` response to
+    /// `request`, if there is a supported challenge to get credentials for.
+    pub(crate) fn authorization(
+        &self,
+        request: &Request,
+        response: &Response,
+    ) -> Option<HeaderValue> {
+        let mut challenges: Vec<Challenge> = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(parse_challenges)
+            .filter(|challenge| challenge.is_scheme("Digest") || challenge.is_scheme("Basic"))
+            .collect();
+        challenges.sort_by_key(|challenge| !challenge.is_scheme("Digest"));
+
+        for challenge in challenges {
+            let credentials = match (self.0)(&challenge) {
+                Some(credentials) => credentials,
+                None => continue,
+            };
+            let header = if challenge.is_scheme("Digest") {
+                match digest_authorization(&challenge, &credentials, request) {
+                    Some(header) => header,
+                    None => {
+                        debug!("Unsupported digest challenge {:?}", challenge);
+                        continue;
+                    }
+                }
+            } else {
+                let user_pass = format!("{}:{}", credentials.username, credentials.password);
+                format!("Basic {}", base64::encode(user_pass))
+            };
+            match HeaderValue::from_str(&header) {
+                Ok(value) => return Some(value),
+                Err(_) => warn!("Credentials are not valid in a header"),
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Authenticator(..)")
+    }
+}
+
+/// Split a header value at the commas outside of quoted strings.
+fn split_list(header: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in header.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&header[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&header[start..]);
+    items
+}
+
+/// Parse a `name=value` parameter, unquoting the value if needed.
+fn parse_param(param: &str) -> Option<(String, String)> {
+    let (name, value) = param.split_once('=')?;
+    let value = value.trim();
+    let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                unquoted.push(if c == '\\' { chars.next()? } else { c });
+            }
+            unquoted
+        }
+        None => value.to_owned(),
+    };
+    Some((name.trim().to_ascii_lowercase(), value))
+}
+
+/// Parse the challenges in a `WWW-Authenticate` header (RFC 7235, section 4.1).
+fn parse_challenges(header: &str) -> Vec<Challenge> {
+    let mut challenges: Vec<Challenge> = Vec::new();
+    for item in split_list(header) {
+        let item = item.trim();
+        if item.is_empty() {
+            continue;
+        }
+        // A challenge starts with the scheme, followed by a space and its first parameter;
+        // parameters are `name=value` pairs without spaces before the `=`.
+        let param = match item.find([' ', '=']) {
+            Some(idx) if item[idx..].starts_with('=') => item,
+            Some(idx) => {
+                challenges.push(Challenge {
+                    scheme: item[..idx].to_owned(),
+                    params: Vec::new(),
+                });
+                item[idx..].trim()
+            }
+            None => {
+                challenges.push(Challenge {
+                    scheme: item.to_owned(),
+                    params: Vec::new(),
+                });
+                continue;
+            }
+        };
+        match (challenges.last_mut(), parse_param(param)) {
+            (Some(challenge), Some(param)) => challenge.params.push(param),
+            _ => debug!("Ignoring authentication parameter {:?}", param),
+        }
+    }
+    challenges
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compute the response of a digest (RFC 7616, section 3.4.1).
+#[allow(clippy::too_many_arguments)]
+fn digest_response(
+    hash: fn(&[u8]) -> String,
+    credentials: &Credentials,
+    realm: &str,
+    uri: &str,
+    nonce: &str,
+    nc: &str,
+    cnonce: &str,
+    qop: Option<&str>,
+) -> String {
+    let a1 = format!(
+        "{}:{}:{}",
+        credentials.username, realm, credentials.password
+    );
+    let a2 = format!("GET:{}", uri);
+    let (ha1, ha2) = (hash(a1.as_bytes()), hash(a2.as_bytes()));
+    let data = match qop {
+        Some(qop) => format!("{}:{}:{}:{}:{}:{}", ha1, nonce, nc, cnonce, qop, ha2),
+        None => format!("{}:{}:{}", ha1, nonce, ha2),
+    };
+    hash(data.as_bytes())
+}
+
+/// Quote a string for use as a parameter value.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Answer a digest challenge (RFC 7616).
+fn digest_authorization(
+    challenge: &Challenge,
+    credentials: &Credentials,
+    request: &Request,
+) -> Option<String> {
+    let realm = challenge.realm().unwrap_or("");
+    let nonce = challenge.param("nonce")?;
+    let algorithm = challenge.param("algorithm").unwrap_or("MD5");
+    let hash: fn(&[u8]) -> String = if algorithm.eq_ignore_ascii_case("MD5") {
+        |data| to_hex(&Md5::digest(data))
+    } else if algorithm.eq_ignore_ascii_case("SHA-256") {
+        |data| to_hex(&Sha256::digest(data))
+    } else {
+        return None;
+    };
+    // Only the "auth" quality of protection is supported, or none at all (RFC 2069).
+    let qop = match challenge.param("qop") {
+        Some(qop) if qop.split(',').any(|q| q.trim() == "auth") => Some("auth"),
+        Some(_) => return None,
+        None => None,
+    };
+    let uri = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let nc = "00000001";
+    let cnonce = to_hex(&rand::random::<[u8; 16]>());
+    let response = digest_response(hash, credentials, realm, uri, nonce, nc, &cnonce, qop);
+
+    let mut header = format!(
+        "Digest username={}, realm={}, nonce={}, uri={}, algorithm={}, response=\"{}\"",
+        quote(&credentials.username),
+        quote(realm),
+        quote(nonce),
+        quote(uri),
+        algorithm,
+        response
+    );
+    if let Some(qop) = qop {
+        header.push_str(&format!(", qop={}, nc={}, cnonce=\"{}\"", qop, nc, cnonce));
+    }
+    if let Some(opaque) = challenge.param("opaque") {
+        header.push_str(&format!(", opaque={}", quote(opaque)));
+    }
+    Some(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest_response, parse_challenges, to_hex, Authenticator, Credentials};
+    use crate::handshake::client::{Request, Response};
+    use md5::Md5;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn challenge_parsing() {
+        let challenges = parse_challenges(
+            "Newauth realm=\"apps\", type=1, title=\"Login to \\\"apps\\\"\", Basic realm=\"simple\"",
+        );
+        assert_eq!(challenges.len(), 2);
+        assert_eq!(challenges[0].scheme(), "Newauth");
+        assert_eq!(challenges[0].realm(), Some("apps"));
+        assert_eq!(challenges[0].param("Type"), Some("1"));
+        assert_eq!(challenges[0].param("title"), Some("Login to \"apps\""));
+        assert_eq!(challenges[1].scheme(), "Basic");
+        assert_eq!(challenges[1].realm(), Some("simple"));
+
+        let challenges = parse_challenges("Negotiate, Basic realm=\"a, b\"");
+        assert_eq!(challenges[0].scheme(), "Negotiate");
+        assert_eq!(challenges[1].realm(), Some("a, b"));
+    }
+
+    #[test]
+    fn digest_rfc7616_example() {
+        // RFC 7616, section 3.9.1.
+        let credentials = Credentials::new("Mufasa", "Circle of Life");
+        let args = (
+            "http-auth@example.org",
+            "/dir/index.html",
+            "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+            "00000001",
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+        );
+        let md5 = digest_response(
+            |data| to_hex(&Md5::digest(data)),
+            &credentials,
+            args.0,
+            args.1,
+            args.2,
+            args.3,
+            args.4,
+            Some("auth"),
+        );
+        assert_eq!(md5, "8ca523f5e9506fed4657c9700eebdbec");
+        let sha256 = digest_response(
+            |data| to_hex(&Sha256::digest(data)),
+            &credentials,
+            args.0,
+            args.1,
+            args.2,
+            args.3,
+            args.4,
+            Some("auth"),
+        );
+        assert_eq!(
+            sha256,
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+    }
+
+    #[test]
+    fn authorization() {
+        let request = Request::get("ws://localhost/dir/index.html")
+            .body(())
+            .unwrap();
+        let response = Response::builder()
+            .status(401)
+            .header("WWW-Authenticate", "Basic realm=\"WallyWorld\"")
+            .header(
+                "WWW-Authenticate",
+                "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", \
+                 algorithm=SHA-256, nonce=\"7ypf\", opaque=\"FQhe\"",
+            )
+            .body(())
+            .unwrap();
+
+        let authenticator = Authenticator::new(|challenge| {
+            Some(Credentials::new("Mufasa", challenge.scheme().to_owned()))
+        });
+        let header = authenticator.authorization(&request, &response).unwrap();
+        let header = header.to_str().unwrap();
+        assert!(header.starts_with(
+            "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", nonce=\"7ypf\", \
+             uri=\"/dir/index.html\", algorithm=SHA-256, response=\""
+        ));
+        assert!(header.contains(", qop=auth, nc=00000001, cnonce=\""));
+        assert!(header.ends_with(", opaque=\"FQhe\""));
+
+        // Declining the digest challenge falls back to basic authentication.
+        let authenticator = Authenticator::new(|challenge| {
+            if challenge.scheme() == "Basic" {
+                Some(Credentials::new("Aladdin", "open sesame"))
+            } else {
+                None
+            }
+        });
+        let header = authenticator.authorization(&request, &response).unwrap();
+        assert_eq!(header, "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+
+        let authenticator = Authenticator::new(|_| None);
+        assert!(authenticator.authorization(&request, &response).is_none());
+    }
+}
@@ -0,0 +1,290 @@
+//! Cookie storage for client handshakes.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use http::header::{COOKIE, SET_COOKIE};
+use http::{HeaderValue, Uri};
+use log::*;
+
+use crate::handshake::client::{Request, Response};
+
+/// A cookie received from a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// The domain the cookie belongs to, in lower case.
+    domain: String,
+    /// If `true`, the cookie is only sent to `domain` itself, not to its subdomains.
+    host_only: bool,
+    path: String,
+    /// Only send the cookie over TLS.
+    secure: bool,
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    /// Parse a `Set-Cookie` header value received in response to a request to `uri`
+    /// (RFC 6265, section 5.2).
+    fn parse(header: &str, uri: &Uri) -> Option<Self> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let mut parts = header.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(uri),
+            secure: false,
+            expires: None,
+        };
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (attribute.trim(), ""),
+            };
+            if key.eq_ignore_ascii_case("Domain") && !value.is_empty() {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                // Refuse cookies for domains the server does not belong to.
+                if !domain_match(&host, &domain) {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            } else if key.eq_ignore_ascii_case("Path") && value.starts_with('/') {
+                cookie.path = value.to_owned();
+            } else if key.eq_ignore_ascii_case("Secure") {
+                cookie.secure = true;
+            } else if key.eq_ignore_ascii_case("Max-Age") {
+                // Values too large for an i64 are still a very long time.
+                max_age = match value.parse::<i64>() {
+                    Ok(max_age) => Some(max_age),
+                    Err(_) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                        Some(i64::MAX)
+                    }
+                    Err(_) => None,
+                };
+            } else if key.eq_ignore_ascii_case("Expires") {
+                cookie.expires = cookie
+                    .expires
+                    .or_else(|| httpdate::parse_http_date(value).ok());
+            }
+        }
+        // Max-Age takes precedence over Expires. A time beyond what the system can represent
+        // means the cookie never expires.
+        if let Some(max_age) = max_age {
+            cookie.expires = if max_age <= 0 {
+                Some(SystemTime::UNIX_EPOCH)
+            } else {
+                SystemTime::now().checked_add(Duration::from_secs(max_age as u64))
+            };
+        }
+        Some(cookie)
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
+
+    /// Tell if the cookie is to be sent with a request to `uri`.
+    fn matches(&self, uri: &Uri) -> bool {
+        let host = match uri.host() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(&host, &self.domain)
+        };
+        domain_ok && path_match(uri.path(), &self.path) && (!self.secure || is_secure(uri))
+    }
+}
+
+/// Domain matching (RFC 6265, section 5.1.3).
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+/// Path matching (RFC 6265, section 5.1.4).
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+/// The default cookie path (RFC 6265, section 5.1.4).
+fn default_path(uri: &Uri) -> String {
+    match uri.path().rfind('/') {
+        Some(0) | None => "/".to_owned(),
+        Some(idx) => uri.path()[..idx].to_owned(),
+    }
+}
+
+fn is_secure(uri: &Uri) -> bool {
+    matches!(uri.scheme_str(), Some("wss") | Some("https"))
+}
+
+/// A store for cookies set by servers, sent back with subsequent handshake requests.
+///
+/// Clones share the same storage, so one jar can be handed to every connection that should
+/// keep the session, e.g. across reconnects.
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<Cookie>>>,
+}
+
+impl CookieJar {
+    /// Create an empty cookie jar.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the cookies matching the request URI to a `Cookie` header of the request.
+    pub fn add_to_request(&self, request: &mut Request) {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().expect("Bug: poisoned cookie jar");
+        cookies.retain(|cookie| !cookie.is_expired(now));
+
+        let mut matching: Vec<_> = cookies
+            .iter()
+            .filter(|cookie| cookie.matches(request.uri()))
+            .collect();
+        if matching.is_empty() {
+            return;
+        }
+        // Cookies with longer paths are listed first (RFC 6265, section 5.4).
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let header = matching
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        match HeaderValue::from_str(&header) {
+            Ok(value) => {
+                request.headers_mut().append(COOKIE, value);
+            }
+            Err(_) => warn!("Skipping cookies that are not valid header values"),
+        }
+    }
+
+    /// Store the cookies from the `Set-Cookie` headers of a response to a request to `uri`.
+    ///
+    /// This works for any response, so cookies set along with a redirect or an error are kept
+    /// as well.
+    pub fn store_response(&self, uri: &Uri, response: &Response) {
+        let mut cookies = self.cookies.lock().expect("Bug: poisoned cookie jar");
+        for header in response.headers().get_all(SET_COOKIE) {
+            let cookie = match header.to_str().ok().and_then(|h| Cookie::parse(h, uri)) {
+                Some(cookie) => cookie,
+                None => {
+                    debug!("Ignoring invalid cookie {:?}", header);
+                    continue;
+                }
+            };
+            cookies.retain(|c| {
+                (&c.name, &c.domain, &c.path) != (&cookie.name, &cookie.domain, &cookie.path)
+            });
+            if !cookie.is_expired(SystemTime::now()) {
+                cookies.push(cookie);
+            }
+        }
+    }
+
+    /// Remove all cookies.
+    pub fn clear(&self) {
+        self.cookies
+            .lock()
+            .expect("Bug: poisoned cookie jar")
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CookieJar;
+    use crate::handshake::client::{Request, Response};
+
+    fn response(cookies: &[&str]) -> Response {
+        let mut response = Response::builder().status(302);
+        for cookie in cookies {
+            response = response.header("Set-Cookie", *cookie);
+        }
+        response.body(()).unwrap()
+    }
+
+    fn cookies_for(jar: &CookieJar, uri: &str) -> Option<String> {
+        let mut request = Request::get(uri).body(()).unwrap();
+        jar.add_to_request(&mut request);
+        request
+            .headers()
+            .get("Cookie")
+            .map(|h| h.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn cookie_jar() {
+        let jar = CookieJar::new();
+        let uri = "wss://api.example.com/v1/socket".parse().unwrap();
+        jar.store_response(
+            &uri,
+            &response(&[
+                "session=abc; Path=/; Secure; HttpOnly",
+                "shared=1; Domain=.example.com; Path=/",
+                "local=2",
+                "evil=3; Domain=other.com",
+                "gone=4; Max-Age=0",
+            ]),
+        );
+
+        assert_eq!(
+            cookies_for(&jar, "wss://api.example.com/v1/socket").as_deref(),
+            Some("local=2; session=abc; shared=1")
+        );
+        assert_eq!(
+            cookies_for(&jar, "ws://api.example.com/").as_deref(),
+            Some("shared=1")
+        );
+        assert_eq!(
+            cookies_for(&jar, "wss://www.example.com/").as_deref(),
+            Some("shared=1")
+        );
+        assert_eq!(cookies_for(&jar, "wss://other.com/"), None);
+
+        // A clone shares the storage.
+        jar.clone().store_response(
+            &uri,
+            &response(&["session=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Path=/"]),
+        );
+        assert_eq!(
+            cookies_for(&jar, "wss://api.example.com/").as_deref(),
+            Some("shared=1")
+        );
+    }
+
+    #[test]
+    fn huge_max_age() {
+        let jar = CookieJar::new();
+        let uri = "wss://example.com/".parse().unwrap();
+        jar.store_response(
+            &uri,
+            &response(&[
+                "a=1; Max-Age=18446744073709551615",
+                "b=2; Max-Age=9223372036854775807",
+                "c=3; Max-Age=99999999999999999999999",
+            ]),
+        );
+        assert_eq!(
+            cookies_for(&jar, "wss://example.com/").as_deref(),
+            Some("a=1; b=2; c=3")
+        );
+    }
+}
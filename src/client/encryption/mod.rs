@@ -0,0 +1,100 @@
+//! TLS for `wss://` connections, with any of the backends enabled by the cargo features.
+
+use std::net::TcpStream;
+#[cfg(feature = "__rustls-tls")]
+use std::sync::Arc;
+
+pub use crate::stream::Stream as StreamSwitcher;
+/// TCP stream switcher (plain/TLS). Its `get_ref()` and `get_mut()` reach the `TcpStream`
+/// either way.
+pub type AutoStream = StreamSwitcher<TcpStream, TlsStream<TcpStream>>;
+
+use super::{ConnectOptions, TlsBackend};
+use crate::error::{Error, Result};
+use crate::stream::{Mode, TlsStream};
+
+#[cfg(feature = "tls")]
+mod native_backend;
+#[cfg(feature = "openssl-tls")]
+mod openssl_backend;
+#[cfg(feature = "__rustls-tls")]
+mod rustls_backend;
+
+/// The backend used unless `ConnectOptions::tls_backend` tells otherwise.
+#[cfg(feature = "tls")]
+const DEFAULT_BACKEND: TlsBackend = TlsBackend::NativeTls;
+#[cfg(all(feature = "__rustls-tls", not(feature = "tls")))]
+const DEFAULT_BACKEND: TlsBackend = TlsBackend::Rustls;
+#[cfg(all(
+    feature = "openssl-tls",
+    not(any(feature = "tls", feature = "__rustls-tls"))
+))]
+const DEFAULT_BACKEND: TlsBackend = TlsBackend::OpenSsl;
+
+/// A configured TLS connector for `wss://` connections.
+///
+/// There is a variant for each TLS backend enabled with the cargo features, so matching on
+/// them needs a wildcard.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub enum Connector {
+    /// A `native_tls` connector.
+    #[cfg(feature = "tls")]
+    NativeTls(native_tls::TlsConnector),
+    /// A `rustls` client configuration.
+    #[cfg(feature = "__rustls-tls")]
+    Rustls(Arc<rustls::ClientConfig>),
+    /// An `openssl` connector.
+    #[cfg(feature = "openssl-tls")]
+    OpenSsl(openssl::ssl::SslConnector),
+}
+
+/// A connector of the chosen backend with the TLS settings of `options`.
+pub fn build_connector(options: &ConnectOptions) -> Result<Connector> {
+    match options.tls_backend.unwrap_or(DEFAULT_BACKEND) {
+        #[cfg(feature = "tls")]
+        TlsBackend::NativeTls => native_backend::build_connector(options),
+        #[cfg(feature = "__rustls-tls")]
+        TlsBackend::Rustls => rustls_backend::build_connector(options),
+        #[cfg(feature = "openssl-tls")]
+        TlsBackend::OpenSsl => openssl_backend::build_connector(options),
+        #[allow(unreachable_patterns)]
+        backend => Err(Error::Url(
+            format!("TLS backend {:?} not compiled in", backend).into(),
+        )),
+    }
+}
+
+pub fn wrap_stream(
+    stream: TcpStream,
+    domain: &str,
+    mode: Mode,
+    connector: Option<&Connector>,
+) -> Result<AutoStream> {
+    match mode {
+        Mode::Plain => Ok(StreamSwitcher::Plain(stream)),
+        Mode::Tls => {
+            let default;
+            let connector = match connector {
+                Some(connector) => connector,
+                None => {
+                    default = build_connector(&ConnectOptions::default())?;
+                    &default
+                }
+            };
+            let stream = match *connector {
+                #[cfg(feature = "tls")]
+                Connector::NativeTls(ref connector) => {
+                    native_backend::connect(connector, domain, stream)?
+                }
+                #[cfg(feature = "__rustls-tls")]
+                Connector::Rustls(ref config) => rustls_backend::connect(config, domain, stream)?,
+                #[cfg(feature = "openssl-tls")]
+                Connector::OpenSsl(ref connector) => {
+                    openssl_backend::connect(connector, domain, stream)?
+                }
+            };
+            Ok(StreamSwitcher::Tls(stream))
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! TLS with native-tls.
+
+use std::io;
+use std::net::TcpStream;
+
+use native_tls::{HandshakeError as TlsHandshakeError, Identity, TlsConnector};
+
+use super::Connector;
+use crate::client::{ConnectOptions, IdentityKind};
+use crate::error::{Error, Result};
+use crate::stream::TlsStream;
+
+pub fn build_connector(options: &ConnectOptions) -> Result<Connector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ref identity) = options.identity {
+        let identity = match identity.0 {
+            IdentityKind::Pkcs12 {
+                ref der,
+                ref password,
+            } => Identity::from_pkcs12(der, password)?,
+            IdentityKind::Pem {
+                ref cert_chain,
+                ref key,
+            } => Identity::from_pkcs8(cert_chain, key)?,
+        };
+        builder.identity(identity);
+    }
+    let protocols: Vec<&str> = options.alpn_protocols.iter().map(String::as_str).collect();
+    builder
+        .request_alpns(&protocols)
+        .danger_accept_invalid_certs(options.danger_accept_invalid_certs)
+        .danger_accept_invalid_hostnames(options.danger_accept_invalid_hostnames);
+    Ok(Connector::NativeTls(builder.build()?))
+}
+
+pub fn connect(
+    connector: &TlsConnector,
+    domain: &str,
+    stream: TcpStream,
+) -> Result<TlsStream<TcpStream>> {
+    connector
+        .connect(domain, stream)
+        .map_err(|e| match e {
+            TlsHandshakeError::Failure(f) => f.into(),
+            TlsHandshakeError::WouldBlock(_) => {
+                // The stream is blocking, so this is a read or write timeout expiring.
+                Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                ))
+            }
+        })
+        .map(TlsStream::NativeTls)
+}
@@ -0,0 +1,96 @@
+//! TLS with openssl.
+
+use std::io;
+use std::net::TcpStream;
+
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::ssl::{HandshakeError, SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+
+use super::Connector;
+use crate::client::{ConnectOptions, IdentityKind};
+use crate::error::{Error, Result};
+use crate::stream::TlsStream;
+
+/// `X509_V_ERR_HOSTNAME_MISMATCH`, the verification error for a certificate of another name.
+const HOSTNAME_MISMATCH: i32 = 62;
+/// `X509_V_ERR_IP_ADDRESS_MISMATCH`, the same error when connecting to an IP address.
+const IP_ADDRESS_MISMATCH: i32 = 64;
+
+pub fn build_connector(options: &ConnectOptions) -> Result<Connector> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    if let Some(ref identity) = options.identity {
+        match identity.0 {
+            IdentityKind::Pkcs12 {
+                ref der,
+                ref password,
+            } => {
+                let parsed = Pkcs12::from_der(der)?.parse2(password)?;
+                if let Some(ref cert) = parsed.cert {
+                    builder.set_certificate(cert)?;
+                }
+                if let Some(ref key) = parsed.pkey {
+                    builder.set_private_key(key)?;
+                }
+                for cert in parsed.ca.into_iter().flatten() {
+                    builder.add_extra_chain_cert(cert)?;
+                }
+            }
+            IdentityKind::Pem {
+                ref cert_chain,
+                ref key,
+            } => {
+                let cert = X509::from_pem(cert_chain)?;
+                builder.set_certificate(&cert)?;
+                for cert in X509::stack_from_pem(cert_chain)?.into_iter().skip(1) {
+                    builder.add_extra_chain_cert(cert)?;
+                }
+                let key = PKey::private_key_from_pem(key)?;
+                builder.set_private_key(&key)?;
+            }
+        }
+    }
+    if !options.alpn_protocols.is_empty() {
+        // The wire format: each protocol prefixed with its length.
+        let mut protocols = Vec::new();
+        for protocol in &options.alpn_protocols {
+            if protocol.is_empty() || protocol.len() > 255 {
+                return Err(Error::Url("Invalid ALPN protocol name".into()));
+            }
+            protocols.push(protocol.len() as u8);
+            protocols.extend_from_slice(protocol.as_bytes());
+        }
+        builder.set_alpn_protos(&protocols)?;
+    }
+    if options.danger_accept_invalid_certs {
+        builder.set_verify(SslVerifyMode::NONE);
+    } else if options.danger_accept_invalid_hostnames {
+        builder.set_verify_callback(SslVerifyMode::PEER, |valid, context| {
+            let error = context.error().as_raw();
+            valid || error == HOSTNAME_MISMATCH || error == IP_ADDRESS_MISMATCH
+        });
+    }
+    Ok(Connector::OpenSsl(builder.build()))
+}
+
+pub fn connect(
+    connector: &SslConnector,
+    domain: &str,
+    stream: TcpStream,
+) -> Result<TlsStream<TcpStream>> {
+    connector
+        .connect(domain, stream)
+        .map_err(|e| match e {
+            HandshakeError::SetupFailure(e) => e.into(),
+            HandshakeError::Failure(mid) => mid.into_error().into(),
+            HandshakeError::WouldBlock(_) => {
+                // The stream is blocking, so this is a read or write timeout expiring.
+                Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                ))
+            }
+        })
+        .map(TlsStream::OpenSsl)
+}
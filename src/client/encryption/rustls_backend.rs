@@ -0,0 +1,169 @@
+//! TLS with rustls.
+
+use std::convert::TryFrom;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+
+use super::Connector;
+use crate::client::{ConnectOptions, IdentityKind};
+use crate::error::{Error, Result};
+use crate::stream::{complete_rustls_handshake, TlsStream};
+
+/// The root certificates trusted by the enabled `rustls-tls-*-roots` features.
+fn root_store() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    #[cfg(feature = "rustls-tls-native-roots")]
+    {
+        use log::*;
+        let native = rustls_native_certs::load_native_certs();
+        for error in native.errors {
+            warn!("Failed to load a native root certificate: {}", error);
+        }
+        let (added, ignored) = roots.add_parsable_certificates(native.certs);
+        debug!(
+            "Added {} native root certificates, ignored {}",
+            added, ignored
+        );
+    }
+    #[cfg(feature = "rustls-tls-webpki-roots")]
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    roots
+}
+
+/// Verifies server certificates as loosely as the `danger_*` options ask for.
+#[derive(Debug)]
+struct DangerousVerifier {
+    /// The verifier for the certificates, `None` to accept any.
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for DangerousVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        intermediates: &[CertificateDer],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let webpki = match self.webpki {
+            Some(ref webpki) => webpki,
+            None => return Ok(ServerCertVerified::assertion()),
+        };
+        match webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName))
+            | Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForNameContext {
+                ..
+            })) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+pub fn build_connector(options: &ConnectOptions) -> Result<Connector> {
+    // Use ring explicitly: the process-wide default provider is ambiguous, and rustls panics,
+    // as soon as another crate enables a second one.
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if options.danger_accept_invalid_certs || options.danger_accept_invalid_hostnames
+    {
+        let webpki = if options.danger_accept_invalid_certs {
+            None
+        } else {
+            let webpki =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(root_store()), provider)
+                    .build()
+                    .map_err(|e| Error::Rustls(rustls::Error::General(e.to_string())))?;
+            Some(webpki)
+        };
+        let verifier = DangerousVerifier {
+            webpki,
+            provider: builder.crypto_provider().clone(),
+        };
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+    } else {
+        builder.with_root_certificates(root_store())
+    };
+    let mut config = match options.identity.as_ref().map(|identity| &identity.0) {
+        Some(IdentityKind::Pem { cert_chain, key }) => {
+            let invalid = |e: rustls::pki_types::pem::Error| {
+                Error::Rustls(rustls::Error::General(format!(
+                    "Invalid client identity: {}",
+                    e
+                )))
+            };
+            let cert_chain = CertificateDer::pem_slice_iter(cert_chain)
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(invalid)?;
+            let key = PrivateKeyDer::from_pem_slice(key).map_err(invalid)?;
+            builder.with_client_auth_cert(cert_chain, key)?
+        }
+        Some(IdentityKind::Pkcs12 { .. }) => {
+            return Err(Error::Rustls(rustls::Error::General(
+                "PKCS #12 identities are not supported with rustls".into(),
+            )))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = options
+        .alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    Ok(Connector::Rustls(Arc::new(config)))
+}
+
+pub fn connect(
+    config: &Arc<ClientConfig>,
+    domain: &str,
+    stream: TcpStream,
+) -> Result<TlsStream<TcpStream>> {
+    let name = ServerName::try_from(domain.to_owned())
+        .map_err(|_| Error::Url("Invalid DNS name for TLS".into()))?;
+    let connection = ClientConnection::new(config.clone(), name)?;
+    let mut stream = StreamOwned::new(connection, stream);
+    // Complete the TLS handshake here, so that it fails before the WebSocket one.
+    complete_rustls_handshake(&mut *stream.conn, &mut stream.sock)?;
+    Ok(TlsStream::RustlsClient(Box::new(stream)))
+}
@@ -0,0 +1,120 @@
+//! Dual-stack connection establishment, "Happy Eyeballs" (RFC 8305).
+
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use super::tcp_connect;
+
+use log::*;
+
+/// The delay between starting connection attempts recommended by RFC 8305, section 5.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Order addresses so that the address families alternate, starting with the family of the
+/// first address (RFC 8305, section 4).
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return Vec::new(),
+    };
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+/// Connect to one of the addresses, starting a new attempt whenever the previous one failed
+/// or did not succeed within the attempt delay, and return the first connection established.
+///
+/// Each attempt gives up after `timeout`, if one is given.
+pub(crate) fn connect(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let addrs = interleave(addrs);
+    let mut pending = addrs.iter();
+    let mut in_flight = 0;
+    let mut last_error = None;
+    let (tx, rx) = channel();
+
+    loop {
+        if let Some(&addr) = pending.next() {
+            debug!("Trying to connect to {}...", addr);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                // The receiver is gone if another attempt won already.
+                let _ = tx.send((addr, tcp_connect(&addr, timeout)));
+            });
+            in_flight += 1;
+        } else if in_flight == 0 {
+            break;
+        }
+
+        // Wait for the attempts in flight until it is time to start the next one.
+        let result = if pending.len() > 0 {
+            rx.recv_timeout(CONNECTION_ATTEMPT_DELAY)
+        } else {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        match result {
+            Ok((addr, Ok(stream))) => {
+                debug!("Connected to {}", addr);
+                return Ok(stream);
+            }
+            Ok((addr, Err(e))) => {
+                debug!("Failed to connect to {}: {}", addr, e);
+                in_flight -= 1;
+                last_error = Some(e);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => unreachable!("Bug: sender dropped"),
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No addresses to try")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, interleave};
+    use std::net::{SocketAddr, TcpListener};
+
+    #[test]
+    fn address_interleaving() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "1.1.1.1:1", "2.2.2.2:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave(&addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            ordered,
+            vec!["[::1]:1", "1.1.1.1:1", "[::2]:1", "2.2.2.2:1", "[::3]:1"]
+        );
+    }
+
+    #[test]
+    fn first_successful_connection_wins() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        // Nothing listens there, so the attempt fails right away.
+        let bad = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let stream = connect(&[bad, good], None).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(connect(&[bad], None).is_err());
+    }
+}
@@ -11,6 +11,7 @@ use std::string;
 use http;
 use httparse;
 
+use crate::protocol::frame::coding::CloseCode;
 use crate::protocol::Message;
 
 #[cfg(feature = "tls")]
@@ -51,12 +52,29 @@ pub enum Error {
     #[cfg(feature = "tls")]
     /// TLS error
     Tls(tls::Error),
+    #[cfg(feature = "__rustls-tls")]
+    /// TLS error from rustls
+    Rustls(rustls::Error),
+    #[cfg(feature = "openssl-tls")]
+    /// TLS error from openssl
+    OpenSsl(openssl::ssl::Error),
     /// - When reading: buffer capacity exhausted.
     /// - When writing: your message is bigger than the configured max message size
     ///   (64MB by default).
     Capacity(Cow<'static, str>),
+    /// An incoming message or frame is bigger than the configured limit. The connection is
+    /// failed with close code 1009 (Message Too Big) before this is returned.
+    MessageTooBig {
+        /// The size of the offending message or frame, as far as it is known.
+        size: usize,
+        /// The configured maximum size.
+        max_size: usize,
+    },
     /// Protocol violation.
     Protocol(Cow<'static, str>),
+    /// The peer violated a configured policy, such as sending control frames too often. The
+    /// connection is failed with close code 1008 (Policy Violation) before this is returned.
+    Policy(Cow<'static, str>),
     /// Message send queue full.
     SendQueueFull(Message),
     /// UTF coding error
@@ -65,10 +83,27 @@ pub enum Error {
     Url(Cow<'static, str>),
     /// HTTP error.
     Http(http::StatusCode),
+    /// The server received a request that is not a WebSocket upgrade and answered it with the
+    /// fallback handler, sending a response with this status.
+    HttpFallback(http::StatusCode),
+    /// The server does not support the requested version of the WebSocket protocol. Contains
+    /// the versions it does support, from its `Sec-WebSocket-Version` header. A server returns
+    /// this after answering such a request with `426 Upgrade Required`.
+    UnsupportedVersion(Vec<u8>),
     /// HTTP format error.
     HttpFormat(http::Error),
     /// An error from a WebSocket extension.
     ExtensionError(Cow<'static, str>),
+    /// An extension failed on a message, e.g. one which could not be decompressed. Reading fails
+    /// the connection with the close code suggested by the extension before this is returned.
+    ExtensionFailure {
+        /// The close code to fail the connection with.
+        code: CloseCode,
+        /// What went wrong.
+        reason: Cow<'static, str>,
+    },
+    /// The certificate verifier of the client rejected the certificates of the server.
+    CertificateRejected(Cow<'static, str>),
 }
 
 impl fmt::Display for Error {
@@ -79,14 +114,37 @@ impl fmt::Display for Error {
             Error::Io(ref err) => write!(f, "IO error: {}", err),
             #[cfg(feature = "tls")]
             Error::Tls(ref err) => write!(f, "TLS error: {}", err),
+            #[cfg(feature = "__rustls-tls")]
+            Error::Rustls(ref err) => write!(f, "TLS error: {}", err),
+            #[cfg(feature = "openssl-tls")]
+            Error::OpenSsl(ref err) => write!(f, "TLS error: {}", err),
             Error::Capacity(ref msg) => write!(f, "Space limit exceeded: {}", msg),
+            Error::MessageTooBig { size, max_size } => write!(
+                f,
+                "Space limit exceeded: Message too big: {} > {}",
+                size, max_size
+            ),
             Error::Protocol(ref msg) => write!(f, "WebSocket protocol error: {}", msg),
+            Error::Policy(ref msg) => write!(f, "Policy violation: {}", msg),
             Error::SendQueueFull(_) => write!(f, "Send queue is full"),
             Error::Utf8 => write!(f, "UTF-8 encoding error"),
             Error::Url(ref msg) => write!(f, "URL error: {}", msg),
             Error::Http(code) => write!(f, "HTTP error: {}", code),
+            Error::HttpFallback(code) => write!(f, "Plain HTTP request answered: {}", code),
+            Error::UnsupportedVersion(ref versions) => {
+                let versions: Vec<String> = versions.iter().map(u8::to_string).collect();
+                write!(
+                    f,
+                    "WebSocket version not supported, the server supports: {}",
+                    versions.join(", ")
+                )
+            }
             Error::HttpFormat(ref err) => write!(f, "HTTP format error: {}", err),
             Error::ExtensionError(ref e) => write!(f, "Extension error: {}", e),
+            Error::ExtensionFailure { code, ref reason } => {
+                write!(f, "Extension error: {} (close code {})", reason, code)
+            }
+            Error::CertificateRejected(ref msg) => write!(f, "Certificate rejected: {}", msg),
         }
     }
 }
@@ -154,6 +212,27 @@ impl From<tls::Error> for Error {
     }
 }
 
+#[cfg(feature = "__rustls-tls")]
+impl From<rustls::Error> for Error {
+    fn from(err: rustls::Error) -> Self {
+        Error::Rustls(err)
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl From<openssl::ssl::Error> for Error {
+    fn from(err: openssl::ssl::Error) -> Self {
+        Error::OpenSsl(err)
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        Error::OpenSsl(err.into())
+    }
+}
+
 impl From<httparse::Error> for Error {
     fn from(err: httparse::Error) -> Self {
         match err {
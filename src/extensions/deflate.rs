@@ -2,22 +2,24 @@
 
 use std::fmt::{Display, Formatter};
 
+use crate::extensions::header::{self, Extension};
 use crate::extensions::uncompressed::UncompressedExt;
-use crate::extensions::WebSocketExtension;
-use crate::protocol::frame::coding::{Data, OpCode};
+use crate::extensions::{ReservedBits, WebSocketExtension};
+use crate::protocol::frame::coding::{CloseCode, Data, OpCode};
 use crate::protocol::frame::Frame;
 use crate::protocol::MAX_MESSAGE_SIZE;
 use crate::Message;
-use bytes::BufMut;
 use flate2::{
     Compress, CompressError, Compression, Decompress, DecompressError, FlushCompress,
     FlushDecompress, Status,
 };
-use http::header::{InvalidHeaderValue, SEC_WEBSOCKET_EXTENSIONS};
+use http::header::{HeaderMap, InvalidHeaderValue, SEC_WEBSOCKET_EXTENSIONS};
 use http::{HeaderValue, Request, Response};
 use std::borrow::Cow;
-use std::mem::replace;
+use std::fmt;
+use std::iter;
 use std::slice;
+use std::sync::Arc;
 
 /// The WebSocket Extension Identifier as per the IANA registry.
 const EXT_IDENT: &str = "permessage-deflate";
@@ -30,16 +32,50 @@ const LZ77_MIN_WINDOW_SIZE: u8 = 8;
 /// 32,768 bytes. RFC 7692 7.1.2.1.
 const LZ77_MAX_WINDOW_SIZE: u8 = 15;
 
+/// Whether the compression backend supports LZ77 windows smaller than the maximum. miniz always
+/// compresses with the largest window, so it cannot honour a smaller one agreed on.
+const CUSTOM_WINDOW_BITS: bool = cfg!(feature = "__deflate-zlib");
+
+/// The kinds of messages compressed by `DeflateExt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressedMessages {
+    /// Compress text and binary messages.
+    All,
+    /// Compress text messages only, e.g. JSON, and send binary messages as they are, e.g.
+    /// images or video which are compressed already.
+    Text,
+    /// Compress binary messages only.
+    Binary,
+}
+
+/// A filter deciding from the headers of a request whether the server accepts
+/// permessage-deflate for it, see `DeflateConfigBuilder::offer_filter`.
+pub type OfferFilter = Arc<dyn Fn(&HeaderMap) -> bool + Send + Sync>;
+
+/// An `OfferFilter` in a configuration.
+#[derive(Clone)]
+struct FilterFn(OfferFilter);
+
+impl fmt::Debug for FilterFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OfferFilter(..)")
+    }
+}
+
 /// A permessage-deflate configuration.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct DeflateConfig {
     /// The maximum size of a message. The default value is 64 MiB which should be reasonably big
     /// for all normal use-cases but small enough to prevent memory eating by a malicious user.
     max_message_size: usize,
-    /// The LZ77 sliding window size. Negotiated during the HTTP upgrade. In client mode, this
-    /// conforms to RFC 7692 7.1.2.1. In server mode, this conforms to RFC 7692 7.1.2.2. Must be in
-    /// range 8..15 inclusive.
-    max_window_bits: u8,
+    /// The LZ77 sliding window size the client compresses with, so the one the server inflates
+    /// with - RFC 7692 7.1.2.2. Negotiated during the HTTP upgrade. Must be in range 8..15
+    /// inclusive.
+    client_max_window_bits: u8,
+    /// The LZ77 sliding window size the server compresses with, so the one the client inflates
+    /// with - RFC 7692 7.1.2.1. Negotiated during the HTTP upgrade. Must be in range 8..15
+    /// inclusive.
+    server_max_window_bits: u8,
     /// Request that the server resets the LZ77 sliding window between messages - RFC 7692 7.1.1.1.
     request_no_context_takeover: bool,
     /// Whether to accept `no_context_takeover`.
@@ -51,6 +87,23 @@ pub struct DeflateConfig {
     /// The active compression level. The integer here is typically on a scale of 0-9 where 0 means
     /// "no compression" and 9 means "take as long as you'd like".
     compression_level: Compression,
+    /// Messages smaller than this many bytes are sent uncompressed, as compressing them costs
+    /// more than it saves.
+    min_size_to_compress: usize,
+    /// The maximum factor by which a received message may expand when decompressed. `None`
+    /// means no limit other than `max_message_size`.
+    max_expansion_ratio: Option<usize>,
+    /// Decides from the headers of a request whether the server accepts permessage-deflate
+    /// for it at all. `None` accepts every acceptable offer.
+    offer_filter: Option<FilterFn>,
+    /// Reset the compression context after this many messages compressed with it. `None`
+    /// means no limit.
+    reset_context_after_messages: Option<usize>,
+    /// Reset the compression context after this many bytes compressed with it. `None` means no
+    /// limit.
+    reset_context_after_bytes: Option<usize>,
+    /// The kinds of messages to compress; the others are sent as they are.
+    compressed_messages: CompressedMessages,
 }
 
 impl DeflateConfig {
@@ -68,9 +121,14 @@ impl DeflateConfig {
         self.max_message_size
     }
 
-    /// Returns the maximum LZ77 window size permitted.
-    pub fn max_window_bits(&self) -> u8 {
-        self.max_window_bits
+    /// Returns the maximum LZ77 window size the client compresses with.
+    pub fn client_max_window_bits(&self) -> u8 {
+        self.client_max_window_bits
+    }
+
+    /// Returns the maximum LZ77 window size the server compresses with.
+    pub fn server_max_window_bits(&self) -> u8 {
+        self.server_max_window_bits
     }
 
     /// Returns whether `no_context_takeover` has been requested.
@@ -98,15 +156,57 @@ impl DeflateConfig {
         self.compression_level
     }
 
+    /// Returns the size below which messages are sent uncompressed.
+    pub fn min_size_to_compress(&self) -> usize {
+        self.min_size_to_compress
+    }
+
+    /// Returns the maximum factor by which a received message may expand when decompressed.
+    pub fn max_expansion_ratio(&self) -> Option<usize> {
+        self.max_expansion_ratio
+    }
+
+    /// Returns the filter deciding whether the server accepts permessage-deflate for a request.
+    pub fn offer_filter(&self) -> Option<&OfferFilter> {
+        self.offer_filter.as_ref().map(|filter| &filter.0)
+    }
+
+    /// Returns the number of messages after which the compression context is reset.
+    pub fn reset_context_after_messages(&self) -> Option<usize> {
+        self.reset_context_after_messages
+    }
+
+    /// Returns the number of bytes after which the compression context is reset.
+    pub fn reset_context_after_bytes(&self) -> Option<usize> {
+        self.reset_context_after_bytes
+    }
+
+    /// Returns the kinds of messages compressed.
+    pub fn compressed_messages(&self) -> CompressedMessages {
+        self.compressed_messages
+    }
+
     /// Sets the maximum message size permitted.
     pub fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
         self.max_message_size = max_message_size.unwrap_or_else(usize::max_value);
     }
 
-    /// Sets the LZ77 sliding window size.
+    /// Sets the LZ77 sliding window size of both directions.
     pub fn set_max_window_bits(&mut self, max_window_bits: u8) {
-        assert!((LZ77_MIN_WINDOW_SIZE..=LZ77_MAX_WINDOW_SIZE).contains(&max_window_bits));
-        self.max_window_bits = max_window_bits;
+        self.set_client_max_window_bits(max_window_bits);
+        self.set_server_max_window_bits(max_window_bits);
+    }
+
+    /// Sets the LZ77 sliding window size the client compresses with.
+    pub fn set_client_max_window_bits(&mut self, client_max_window_bits: u8) {
+        assert_window_bits(client_max_window_bits);
+        self.client_max_window_bits = client_max_window_bits;
+    }
+
+    /// Sets the LZ77 sliding window size the server compresses with.
+    pub fn set_server_max_window_bits(&mut self, server_max_window_bits: u8) {
+        assert_window_bits(server_max_window_bits);
+        self.server_max_window_bits = server_max_window_bits;
     }
 
     /// Sets the WebSocket to request `no_context_takeover` if `true`.
@@ -118,42 +218,91 @@ impl DeflateConfig {
     pub fn set_accept_no_context_takeover(&mut self, accept_no_context_takeover: bool) {
         self.accept_no_context_takeover = accept_no_context_takeover;
     }
+
+    /// Sets the size below which messages are sent uncompressed.
+    pub fn set_min_size_to_compress(&mut self, min_size_to_compress: usize) {
+        self.min_size_to_compress = min_size_to_compress;
+    }
+
+    /// Sets the maximum factor by which a received message may expand when decompressed.
+    pub fn set_max_expansion_ratio(&mut self, max_expansion_ratio: Option<usize>) {
+        self.max_expansion_ratio = max_expansion_ratio;
+    }
+
+    /// Sets the filter deciding whether the server accepts permessage-deflate for a request.
+    pub fn set_offer_filter(&mut self, offer_filter: Option<OfferFilter>) {
+        self.offer_filter = offer_filter.map(FilterFn);
+    }
+
+    /// Sets the number of messages after which the compression context is reset.
+    pub fn set_reset_context_after_messages(&mut self, messages: Option<usize>) {
+        self.reset_context_after_messages = messages;
+    }
+
+    /// Sets the number of bytes after which the compression context is reset.
+    pub fn set_reset_context_after_bytes(&mut self, bytes: Option<usize>) {
+        self.reset_context_after_bytes = bytes;
+    }
+
+    /// Sets the kinds of messages compressed.
+    pub fn set_compressed_messages(&mut self, compressed_messages: CompressedMessages) {
+        self.compressed_messages = compressed_messages;
+    }
 }
 
 impl Default for DeflateConfig {
     fn default() -> Self {
         DeflateConfig {
             max_message_size: MAX_MESSAGE_SIZE,
-            max_window_bits: LZ77_MAX_WINDOW_SIZE,
+            client_max_window_bits: LZ77_MAX_WINDOW_SIZE,
+            server_max_window_bits: LZ77_MAX_WINDOW_SIZE,
             request_no_context_takeover: false,
             accept_no_context_takeover: true,
             compress_reset: false,
             decompress_reset: false,
             compression_level: Compression::best(),
+            min_size_to_compress: 0,
+            max_expansion_ratio: None,
+            offer_filter: None,
+            reset_context_after_messages: None,
+            reset_context_after_bytes: None,
+            compressed_messages: CompressedMessages::All,
         }
     }
 }
 
 /// A `DeflateConfig` builder.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct DeflateConfigBuilder {
     max_message_size: Option<usize>,
-    max_window_bits: u8,
+    client_max_window_bits: u8,
+    server_max_window_bits: u8,
     request_no_context_takeover: bool,
     accept_no_context_takeover: bool,
-    fragments_grow: bool,
     compression_level: Compression,
+    min_size_to_compress: usize,
+    max_expansion_ratio: Option<usize>,
+    offer_filter: Option<FilterFn>,
+    reset_context_after_messages: Option<usize>,
+    reset_context_after_bytes: Option<usize>,
+    compressed_messages: CompressedMessages,
 }
 
 impl Default for DeflateConfigBuilder {
     fn default() -> Self {
         DeflateConfigBuilder {
             max_message_size: Some(MAX_MESSAGE_SIZE),
-            max_window_bits: LZ77_MAX_WINDOW_SIZE,
+            client_max_window_bits: LZ77_MAX_WINDOW_SIZE,
+            server_max_window_bits: LZ77_MAX_WINDOW_SIZE,
             request_no_context_takeover: false,
             accept_no_context_takeover: true,
-            fragments_grow: true,
             compression_level: Compression::fast(),
+            min_size_to_compress: 0,
+            max_expansion_ratio: None,
+            offer_filter: None,
+            reset_context_after_messages: None,
+            reset_context_after_bytes: None,
+            compressed_messages: CompressedMessages::All,
         }
     }
 }
@@ -165,13 +314,28 @@ impl DeflateConfigBuilder {
         self
     }
 
-    /// Sets the LZ77 sliding window size. Panics if the provided size is not in `8..=15`.
-    pub fn max_window_bits(mut self, max_window_bits: u8) -> DeflateConfigBuilder {
-        assert!(
-            (LZ77_MIN_WINDOW_SIZE..=LZ77_MAX_WINDOW_SIZE).contains(&max_window_bits),
-            "max window bits must be in range 8..=15"
-        );
-        self.max_window_bits = max_window_bits;
+    /// Sets the LZ77 sliding window size of both directions. Panics if the provided size is not
+    /// in `8..=15`, or is below 15 with the miniz backend.
+    pub fn max_window_bits(self, max_window_bits: u8) -> DeflateConfigBuilder {
+        self.client_max_window_bits(max_window_bits)
+            .server_max_window_bits(max_window_bits)
+    }
+
+    /// Sets the LZ77 sliding window size the client compresses with, which the server needs to
+    /// inflate its messages. Panics if the provided size is not in `8..=15`, or is below 15
+    /// with the miniz backend.
+    pub fn client_max_window_bits(mut self, client_max_window_bits: u8) -> DeflateConfigBuilder {
+        assert_window_bits(client_max_window_bits);
+        self.client_max_window_bits = client_max_window_bits;
+        self
+    }
+
+    /// Sets the LZ77 sliding window size the server compresses with, which the client needs to
+    /// inflate its messages. Panics if the provided size is not in `8..=15`, or is below 15
+    /// with the miniz backend.
+    pub fn server_max_window_bits(mut self, server_max_window_bits: u8) -> DeflateConfigBuilder {
+        assert_window_bits(server_max_window_bits);
+        self.server_max_window_bits = server_max_window_bits;
         self
     }
 
@@ -193,19 +357,268 @@ impl DeflateConfigBuilder {
         self
     }
 
+    /// Sets the size below which messages are sent uncompressed. The default value is 0, every
+    /// message is compressed.
+    pub fn min_size_to_compress(mut self, min_size_to_compress: usize) -> DeflateConfigBuilder {
+        self.min_size_to_compress = min_size_to_compress;
+        self
+    }
+
+    /// Sets the maximum factor by which a received message may expand when decompressed, e.g.
+    /// 100 to fail on a 1 KiB message inflating to more than 100 KiB. This guards against
+    /// "deflate bombs" well below `max_message_size`. The default value is `None`, no limit
+    /// other than `max_message_size`.
+    pub fn max_expansion_ratio(
+        mut self,
+        max_expansion_ratio: Option<usize>,
+    ) -> DeflateConfigBuilder {
+        self.max_expansion_ratio = max_expansion_ratio;
+        self
+    }
+
+    /// Sets a filter deciding from the headers of a request whether the server accepts
+    /// permessage-deflate for it at all, e.g. to turn compression off for clients with a known
+    /// broken implementation, or while the server is under load. Offers are declined when it
+    /// returns `false`. The default value is `None`, offers are accepted from every client.
+    ///
+    /// The filter may hold state of its own, e.g. a load signal shared with the rest of the
+    /// server. A decision that needs more than the request headers can replace the extension
+    /// in `ServerHandshake::with_config_override` instead.
+    pub fn offer_filter(mut self, offer_filter: Option<OfferFilter>) -> DeflateConfigBuilder {
+        self.offer_filter = offer_filter.map(FilterFn);
+        self
+    }
+
+    /// Resets the compression context after this many messages compressed with it, releasing
+    /// its memory until the next message is sent. With context takeover, every connection
+    /// holds on to its compression context, about 256 KiB with the largest window; resetting
+    /// it now and then bounds that for connections which rarely send, at the cost of the
+    /// compression ratio. The decompression context is up to the peer, which can be asked to
+    /// reset it with `request_no_context_takeover`. The default value is `None`, no limit.
+    pub fn reset_context_after_messages(mut self, messages: Option<usize>) -> DeflateConfigBuilder {
+        self.reset_context_after_messages = messages;
+        self
+    }
+
+    /// Resets the compression context after this many bytes compressed with it, like
+    /// `reset_context_after_messages`. The default value is `None`, no limit.
+    pub fn reset_context_after_bytes(mut self, bytes: Option<usize>) -> DeflateConfigBuilder {
+        self.reset_context_after_bytes = bytes;
+        self
+    }
+
+    /// Sets the kinds of messages to compress, e.g. `CompressedMessages::Text` to send binary
+    /// messages holding compressed media as they are, without spending time on compressing
+    /// them again. The others are sent uncompressed, as with `WriteOptions::compress` turned
+    /// off. The default value is `CompressedMessages::All`.
+    pub fn compressed_messages(
+        mut self,
+        compressed_messages: CompressedMessages,
+    ) -> DeflateConfigBuilder {
+        self.compressed_messages = compressed_messages;
+        self
+    }
+
     /// Consumes the builder and produces a `DeflateConfig.`
     pub fn build(self) -> DeflateConfig {
         DeflateConfig {
             max_message_size: self.max_message_size.unwrap_or_else(usize::max_value),
-            max_window_bits: self.max_window_bits,
+            client_max_window_bits: self.client_max_window_bits,
+            server_max_window_bits: self.server_max_window_bits,
             request_no_context_takeover: self.request_no_context_takeover,
             accept_no_context_takeover: self.accept_no_context_takeover,
             compression_level: self.compression_level,
+            min_size_to_compress: self.min_size_to_compress,
+            max_expansion_ratio: self.max_expansion_ratio,
+            offer_filter: self.offer_filter,
+            reset_context_after_messages: self.reset_context_after_messages,
+            reset_context_after_bytes: self.reset_context_after_bytes,
+            compressed_messages: self.compressed_messages,
             ..Default::default()
         }
     }
 }
 
+/// Build a permessage-deflate offer with the parameters of `config`.
+fn make_offer(config: &DeflateConfig) -> String {
+    let mut offer = String::from(EXT_IDENT);
+
+    // Without backend support, the server must not limit the window of the client.
+    if config.client_max_window_bits < LZ77_MAX_WINDOW_SIZE {
+        offer.push_str(&format!(
+            "; client_max_window_bits={}",
+            config.client_max_window_bits
+        ))
+    } else if CUSTOM_WINDOW_BITS {
+        offer.push_str("; client_max_window_bits")
+    }
+
+    if config.server_max_window_bits < LZ77_MAX_WINDOW_SIZE {
+        offer.push_str(&format!(
+            "; server_max_window_bits={}",
+            config.server_max_window_bits
+        ))
+    }
+
+    if config.request_no_context_takeover {
+        offer.push_str("; server_no_context_takeover")
+    }
+
+    offer
+}
+
+/// The parameters of a permessage-deflate response from the server.
+#[derive(Debug, Default)]
+struct ResponseParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: Option<u8>,
+    client_max_window_bits: Option<u8>,
+}
+
+impl ResponseParams {
+    fn parse(extension: &Extension) -> Result<ResponseParams, DeflateExtensionError> {
+        let mut params = ResponseParams::default();
+        let mut seen = Vec::new();
+
+        for (param, value) in &extension.params {
+            let param = param.to_ascii_lowercase();
+            if seen.contains(&param) {
+                return Err(DeflateExtensionError::NegotiationError(format!(
+                    "Duplicate extension parameter: {}",
+                    param
+                )));
+            }
+
+            match (param.as_str(), value.as_deref()) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                ("server_max_window_bits", value) | ("client_max_window_bits", value) => {
+                    // A response has to give the window size.
+                    let bits = match parse_window_bits(value) {
+                        Ok(Some(bits)) => bits,
+                        Ok(None) => {
+                            return Err(DeflateExtensionError::NegotiationError(format!(
+                                "{} parameter error: missing value",
+                                param
+                            )))
+                        }
+                        Err(e) => {
+                            return Err(DeflateExtensionError::NegotiationError(format!(
+                                "{} parameter error: {}",
+                                param, e
+                            )))
+                        }
+                    };
+                    if param == "server_max_window_bits" {
+                        params.server_max_window_bits = Some(bits);
+                    } else {
+                        params.client_max_window_bits = Some(bits);
+                    }
+                }
+                _ => {
+                    return Err(DeflateExtensionError::NegotiationError(format!(
+                        "Unknown permessage-deflate parameter: {}",
+                        param
+                    )));
+                }
+            }
+            seen.push(param);
+        }
+
+        Ok(params)
+    }
+
+    /// Check whether the response is valid for `offer` - RFC 7692 7.1.
+    fn accepts(&self, offer: &DeflateConfig) -> bool {
+        let server_bits = offer.server_max_window_bits;
+        let client_bits_offered =
+            CUSTOM_WINDOW_BITS || offer.client_max_window_bits < LZ77_MAX_WINDOW_SIZE;
+
+        // The server confirms the parameters it has to, does not use a larger window than
+        // offered, and limits the window of the client only if it was offered to.
+        (!offer.request_no_context_takeover || self.server_no_context_takeover)
+            && match self.server_max_window_bits {
+                Some(bits) => bits <= server_bits,
+                None => server_bits == LZ77_MAX_WINDOW_SIZE,
+            }
+            && (self.client_max_window_bits.is_none() || client_bits_offered)
+    }
+
+    /// Apply the response to the configuration of the offer it accepts.
+    fn apply(&self, config: &mut DeflateConfig) -> Result<(), DeflateExtensionError> {
+        if self.server_no_context_takeover {
+            config.decompress_reset = true;
+        }
+        if self.client_no_context_takeover {
+            if !config.accept_no_context_takeover() {
+                return Err(DeflateExtensionError::NegotiationError(
+                    "The client requires context takeover.".into(),
+                ));
+            }
+            config.compress_reset = true;
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            config.server_max_window_bits = bits;
+        }
+        // Compressing with a smaller window than the server allows is fine.
+        if let Some(bits) = self.client_max_window_bits {
+            config.client_max_window_bits = config.client_max_window_bits.min(bits);
+        }
+        Ok(())
+    }
+}
+
+/// Create a compressor with the given level and window size.
+fn new_deflator(level: Compression, window_bits: u8) -> Deflator {
+    if window_bits < LZ77_MAX_WINDOW_SIZE {
+        Deflator::new_with_window_bits(level, window_bits)
+    } else {
+        Deflator::new(level)
+    }
+}
+
+/// Check that a configured window size is valid and supported by the compression backend.
+fn assert_window_bits(bits: u8) {
+    assert!(
+        (LZ77_MIN_WINDOW_SIZE..=LZ77_MAX_WINDOW_SIZE).contains(&bits),
+        "max window bits must be in range 8..=15"
+    );
+    assert!(
+        CUSTOM_WINDOW_BITS || bits == LZ77_MAX_WINDOW_SIZE,
+        "the compression backend only supports 15 max window bits"
+    );
+}
+
+/// Counters collected by `DeflateExt` over the lifetime of a connection, e.g. to tune
+/// `DeflateConfig`. Messages are counted once they are complete.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateStats {
+    /// The number of messages sent compressed.
+    pub messages_compressed: u64,
+    /// The payload bytes of the messages sent compressed, before compression.
+    pub bytes_sent_uncompressed: u64,
+    /// The payload bytes of the messages sent compressed, after compression.
+    pub bytes_sent_compressed: u64,
+    /// The number of messages sent uncompressed because they are smaller than
+    /// `DeflateConfig::min_size_to_compress`.
+    pub messages_below_min_size: u64,
+    /// The number of compressed messages received.
+    pub messages_decompressed: u64,
+    /// The payload bytes of the compressed messages received, as received.
+    pub bytes_received_compressed: u64,
+    /// The payload bytes of the compressed messages received, after decompression.
+    pub bytes_received_uncompressed: u64,
+    /// The number of times the compressor context was reset after a message: for
+    /// `no_context_takeover`, once `reset_context_after_messages` or
+    /// `reset_context_after_bytes` is reached, or after a message sent with its own
+    /// compression level in [`WriteOptions`](crate::protocol::WriteOptions).
+    pub compressor_resets: u64,
+    /// The number of times the decompressor was reset after a message, for
+    /// `no_context_takeover`.
+    pub decompressor_resets: u64,
+}
+
 /// A permessage-deflate encoding WebSocket extension.
 #[derive(Debug)]
 pub struct DeflateExt {
@@ -214,14 +627,25 @@ pub struct DeflateExt {
     enabled: bool,
     /// The configuration for the extension.
     config: DeflateConfig,
-    /// A stack of continuation frames awaiting `fin` and the total size of all of the fragments.
-    fragment_buffer: FragmentBuffer,
+    /// The configurations to offer the server after `config`, in the order of preference.
+    fallback_offers: Vec<DeflateConfig>,
+    /// The compressed message being received, if its final frame has not arrived yet.
+    incoming: Option<IncomingMessage>,
     /// The deflate decompressor.
     inflator: Inflator,
-    /// The deflate compressor.
-    deflator: Deflator,
+    /// The deflate compressor, created for the first message after the context was released.
+    deflator: Option<Deflator>,
+    /// The LZ77 window size of the compressor.
+    deflator_window_bits: u8,
+    /// The LZ77 window size of the decompressor.
+    inflator_window_bits: u8,
+    /// The messages and payload bytes compressed since the compression context was reset.
+    context_messages: usize,
+    context_bytes: usize,
     /// If this deflate extension is not used, messages will be forwarded to this extension.
     uncompressed_extension: UncompressedExt,
+    /// The counters collected so far.
+    stats: DeflateStats,
 }
 
 impl DeflateExt {
@@ -229,37 +653,203 @@ impl DeflateExt {
     pub fn new(config: DeflateConfig) -> DeflateExt {
         DeflateExt {
             enabled: false,
+            uncompressed_extension: UncompressedExt::new(Some(config.max_message_size())),
             config,
-            fragment_buffer: FragmentBuffer::new(config.max_message_size),
+            fallback_offers: Vec::new(),
+            incoming: None,
             inflator: Inflator::new(),
-            deflator: Deflator::new(Compression::fast()),
-            uncompressed_extension: UncompressedExt::new(Some(config.max_message_size())),
+            deflator: None,
+            deflator_window_bits: LZ77_MAX_WINDOW_SIZE,
+            inflator_window_bits: LZ77_MAX_WINDOW_SIZE,
+            context_messages: 0,
+            context_bytes: 0,
+            stats: DeflateStats::default(),
         }
     }
 
-    fn parse_window_parameter<'a>(
+    /// Offer `config` to the server too, should it decline the offers before, e.g. a plain
+    /// offer after one limiting the window of the server, which not every server supports.
+    ///
+    /// The configuration of the offer the server accepts is used for the connection, with the
+    /// settings negotiated. Servers ignore this, they accept offers with the configuration
+    /// the extension was created with.
+    pub fn with_fallback_offer(mut self, config: DeflateConfig) -> DeflateExt {
+        self.fallback_offers.push(config);
+        self
+    }
+
+    /// The configurations offered by the client, in the order of preference.
+    fn offers(&self) -> impl Iterator<Item = &DeflateConfig> {
+        iter::once(&self.config).chain(&self.fallback_offers)
+    }
+
+    /// The configuration, with the settings negotiated once the handshake is done, e.g. the
+    /// window sizes and whether the contexts are reset after every message.
+    pub fn config(&self) -> &DeflateConfig {
+        &self.config
+    }
+
+    /// Read the compression statistics of the connection, e.g. with
+    /// `websocket.get_config().encoder.stats()`.
+    pub fn stats(&self) -> &DeflateStats {
+        &self.stats
+    }
+
+    /// Estimate the memory held by the extension for the connection in bytes: the compression
+    /// and decompression contexts, as allocated by zlib, and the message being decompressed.
+    ///
+    /// Servers with many connections can sum this up to keep track of the total, and tune the
+    /// window sizes and `DeflateConfigBuilder::reset_context_after_messages` to bound it.
+    pub fn memory_usage(&self) -> usize {
+        // zlib allocates two window sized buffers of two bytes per position for deflate, plus
+        // 128 KiB for the hash table and the pending output, and one window for inflate, plus
+        // about 7 KiB of state.
+        let deflator = match self.deflator {
+            Some(_) => (4 << self.deflator_window_bits) + (128 << 10),
+            None => 0,
+        };
+        let inflator = (1 << self.inflator_window_bits) + (7 << 10);
+        let incoming = self
+            .incoming
+            .as_ref()
+            .map_or(0, |incoming| incoming.decompressed.capacity());
+        deflator + inflator + incoming
+    }
+
+    /// Set up the compressor and the decompressor for the negotiated window sizes, keeping the
+    /// default ones for the largest window.
+    fn set_window_bits(&mut self, deflator_bits: u8, inflator_bits: u8) {
+        self.deflator_window_bits = deflator_bits;
+        self.deflator = None;
+        if inflator_bits < LZ77_MAX_WINDOW_SIZE {
+            self.inflator_window_bits = inflator_bits;
+            self.inflator = Inflator::new_with_window_bits(inflator_bits);
+        }
+    }
+
+    /// Compress a message with `level` instead of the configured level.
+    ///
+    /// A compressor cannot change its level half way through its context: flate2 fails to,
+    /// and miniz does not support it at all. So the message is compressed with a compressor of
+    /// its own, and the context is released, as the peer's window now holds the message too.
+    fn compress_with_level(
         &mut self,
-        mut param_iter: impl Iterator<Item = &'a str>,
-    ) -> Result<Option<u8>, String> {
-        if let Some(window_bits_str) = param_iter.next() {
-            match window_bits_str.trim().parse() {
-                Ok(window_bits) => {
-                    if window_bits >= LZ77_MIN_WINDOW_SIZE && window_bits <= LZ77_MAX_WINDOW_SIZE {
-                        if window_bits != self.config.max_window_bits() {
-                            self.config.max_window_bits = window_bits;
-                            Ok(Some(window_bits))
-                        } else {
-                            Ok(None)
-                        }
-                    } else {
-                        Err(format!("Invalid window parameter: {}", window_bits))
+        input: &[u8],
+        output: &mut Vec<u8>,
+        level: Compression,
+    ) -> Result<(), CompressError> {
+        if level == self.config.compression_level {
+            return self.deflator().compress(input, output);
+        }
+        new_deflator(level, self.deflator_window_bits).compress(input, output)?;
+        self.deflator = None;
+        self.context_messages = 0;
+        self.context_bytes = 0;
+        self.stats.compressor_resets += 1;
+        Ok(())
+    }
+
+    /// Returns the compressor, creating it if the context was released.
+    fn deflator(&mut self) -> &mut Deflator {
+        let level = self.config.compression_level;
+        let bits = self.deflator_window_bits;
+        self.deflator
+            .get_or_insert_with(|| new_deflator(level, bits))
+    }
+
+    /// Check the parameters of a permessage-deflate offer from a client and work out the
+    /// response to it, or `None` if the offer has to be declined.
+    fn accept_offer(&self, offer: &Extension) -> Option<AcceptedOffer> {
+        let mut accepted = AcceptedOffer {
+            response: String::from(EXT_IDENT),
+            client_max_window_bits: self.config.client_max_window_bits,
+            server_max_window_bits: self.config.server_max_window_bits,
+            compress_reset: self.config.compress_reset,
+            decompress_reset: self.config.decompress_reset,
+        };
+        let mut server_takeover = false;
+        let mut client_takeover = false;
+        let mut server_max_bits = false;
+        let mut client_max_bits = false;
+
+        for (param, value) in &offer.params {
+            let value = value.as_deref();
+            match (param.to_ascii_lowercase().as_str(), value) {
+                ("server_no_context_takeover", None) => {
+                    if server_takeover {
+                        return None;
+                    }
+                    server_takeover = true;
+                    // The client insists on the parameter, so decline rather than ignore it.
+                    if !self.config.accept_no_context_takeover() {
+                        return None;
+                    }
+                    accepted.compress_reset = true;
+                    accepted.response.push_str("; server_no_context_takeover");
+                }
+                ("client_no_context_takeover", None) => {
+                    if client_takeover {
+                        return None;
                     }
+                    client_takeover = true;
+                    accepted.decompress_reset = true;
+                    accepted.response.push_str("; client_no_context_takeover");
                 }
-                Err(e) => Err(e.to_string()),
+                ("server_max_window_bits", value) => {
+                    if server_max_bits {
+                        return None;
+                    }
+                    server_max_bits = true;
+
+                    // The client limits the window of the server, which may use an even smaller
+                    // one, if the backend supports it.
+                    let bits = parse_window_bits(value).ok()??;
+                    if !CUSTOM_WINDOW_BITS && bits < LZ77_MAX_WINDOW_SIZE {
+                        return None;
+                    }
+                    accepted.server_max_window_bits = accepted.server_max_window_bits.min(bits);
+                    accepted.response.push_str(&format!(
+                        "; server_max_window_bits={}",
+                        accepted.server_max_window_bits
+                    ));
+                }
+                ("client_max_window_bits", value) => {
+                    if client_max_bits {
+                        return None;
+                    }
+                    client_max_bits = true;
+
+                    // The client may suggest a window, the server limits it to its own.
+                    if let Some(bits) = parse_window_bits(value).ok()? {
+                        accepted.client_max_window_bits = accepted.client_max_window_bits.min(bits);
+                    }
+                    accepted.response.push_str(&format!(
+                        "; client_max_window_bits={}",
+                        accepted.client_max_window_bits
+                    ));
+                }
+                _ => return None,
             }
-        } else {
-            Ok(None)
         }
+
+        if !client_takeover && self.config.request_no_context_takeover() {
+            accepted.decompress_reset = true;
+            accepted.response.push_str("; client_no_context_takeover");
+        }
+
+        if !server_max_bits {
+            accepted.response.push_str(&format!(
+                "; server_max_window_bits={}",
+                accepted.server_max_window_bits
+            ));
+        }
+
+        // Without the parameter, the client may not limit its window.
+        if !client_max_bits && accepted.client_max_window_bits < LZ77_MAX_WINDOW_SIZE {
+            return None;
+        }
+
+        Some(accepted)
     }
 
     fn decline<T>(&mut self, res: &mut Response<T>) {
@@ -268,6 +858,34 @@ impl DeflateExt {
     }
 }
 
+/// The outcome of a permessage-deflate offer accepted by the server.
+struct AcceptedOffer {
+    /// The value of the `Sec-WebSocket-Extensions` response header.
+    response: String,
+    /// The LZ77 sliding window size agreed on for the client.
+    client_max_window_bits: u8,
+    /// The LZ77 sliding window size agreed on for the server.
+    server_max_window_bits: u8,
+    /// Whether the compressor should be reset after usage.
+    compress_reset: bool,
+    /// Whether the decompressor should be reset after usage.
+    decompress_reset: bool,
+}
+
+/// Parse the value of a window size parameter, which may be absent.
+fn parse_window_bits(value: Option<&str>) -> Result<Option<u8>, String> {
+    match value {
+        Some(value) => match value.parse() {
+            Ok(bits) if (LZ77_MIN_WINDOW_SIZE..=LZ77_MAX_WINDOW_SIZE).contains(&bits) => {
+                Ok(Some(bits))
+            }
+            Ok(bits) => Err(format!("Invalid window parameter: {}", bits)),
+            Err(e) => Err(e.to_string()),
+        },
+        None => Ok(None),
+    }
+}
+
 /// A permessage-deflate extension error.
 #[derive(Debug, Clone)]
 pub enum DeflateExtensionError {
@@ -279,21 +897,43 @@ pub enum DeflateExtensionError {
     NegotiationError(String),
     /// Produced when fragment buffer grew beyond the maximum configured size.
     Capacity(Cow<'static, str>),
+    /// An invalid message, to fail the connection with the given close code.
+    InvalidMessage(CloseCode, String),
+}
+
+impl DeflateExtensionError {
+    /// Returns the close code to fail the connection with because of this error.
+    ///
+    /// Negotiation errors only occur during the handshake, before there is a connection to
+    /// close, so a client never sends 1010 (Extension) for a failed negotiation; the handshake
+    /// fails with `Error::ExtensionError` instead.
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            DeflateExtensionError::DeflateError(_) => CloseCode::Error,
+            DeflateExtensionError::InflateError(_) => CloseCode::Invalid,
+            DeflateExtensionError::NegotiationError(_) => CloseCode::Protocol,
+            DeflateExtensionError::Capacity(_) => CloseCode::Size,
+            DeflateExtensionError::InvalidMessage(code, _) => *code,
+        }
+    }
 }
 
 impl Display for DeflateExtensionError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             DeflateExtensionError::DeflateError(m) => {
-                write!(f, "An error was produced during decompression: {}", m)
+                write!(f, "An error was produced during compression: {}", m)
             }
             DeflateExtensionError::InflateError(m) => {
-                write!(f, "An error was produced during compression: {}", m)
+                write!(f, "An error was produced during decompression: {}", m)
             }
             DeflateExtensionError::NegotiationError(m) => {
                 write!(f, "An upgrade error was encountered: {}", m)
             }
             DeflateExtensionError::Capacity(ref msg) => write!(f, "Space limit exceeded: {}", msg),
+            DeflateExtensionError::InvalidMessage(_, ref msg) => {
+                write!(f, "Invalid message: {}", msg)
+            }
         }
     }
 }
@@ -302,7 +942,16 @@ impl std::error::Error for DeflateExtensionError {}
 
 impl From<DeflateExtensionError> for crate::Error {
     fn from(e: DeflateExtensionError) -> Self {
-        crate::Error::ExtensionError(Cow::from(e.to_string()))
+        match e {
+            DeflateExtensionError::Capacity(msg) => crate::Error::Capacity(msg),
+            e @ DeflateExtensionError::NegotiationError(_) => {
+                crate::Error::ExtensionError(e.to_string().into())
+            }
+            e => crate::Error::ExtensionFailure {
+                code: e.close_code(),
+                reason: e.to_string().into(),
+            },
+        }
     }
 }
 
@@ -332,30 +981,20 @@ impl WebSocketExtension for DeflateExt {
         self.enabled
     }
 
-    fn on_make_request<T>(&mut self, mut request: Request<T>) -> Request<T> {
-        let mut header_value = String::from(EXT_IDENT);
-        let DeflateConfig {
-            max_window_bits,
-            request_no_context_takeover,
-            ..
-        } = self.config;
-
-        if max_window_bits < LZ77_MAX_WINDOW_SIZE {
-            header_value.push_str(&format!(
-                "; client_max_window_bits={}; server_max_window_bits={}",
-                max_window_bits, max_window_bits
-            ))
+    fn reserved_bits(&self) -> ReservedBits {
+        if self.enabled {
+            ReservedBits::RSV1
         } else {
-            header_value.push_str("; client_max_window_bits")
-        }
-
-        if request_no_context_takeover {
-            header_value.push_str("; server_no_context_takeover")
+            ReservedBits::NONE
         }
+    }
 
+    fn on_make_request<T>(&mut self, mut request: Request<T>) -> Request<T> {
+        // The offers go into one header line, in the order of preference.
+        let offers: Vec<_> = self.offers().map(make_offer).collect();
         request.headers_mut().append(
             SEC_WEBSOCKET_EXTENSIONS,
-            HeaderValue::from_str(&header_value).unwrap(),
+            HeaderValue::from_str(&offers.join(", ")).unwrap(),
         );
 
         request
@@ -366,131 +1005,45 @@ impl WebSocketExtension for DeflateExt {
         request: &Request<T>,
         response: &mut Response<T>,
     ) -> Result<(), Self::Error> {
-        for header in request.headers().get_all(SEC_WEBSOCKET_EXTENSIONS) {
-            return match header.to_str() {
-                Ok(header) => {
-                    let mut response_str = String::with_capacity(header.len());
-                    let mut server_takeover = false;
-                    let mut client_takeover = false;
-                    let mut server_max_bits = false;
-                    let mut client_max_bits = false;
-
-                    for param in header.split(';') {
-                        match param.trim().to_lowercase().as_str() {
-                            "permessage-deflate" => response_str.push_str("permessage-deflate"),
-                            "server_no_context_takeover" => {
-                                if server_takeover {
-                                    self.decline(response);
-                                } else {
-                                    server_takeover = true;
-                                    if self.config.accept_no_context_takeover() {
-                                        self.config.compress_reset = true;
-                                        response_str.push_str("; server_no_context_takeover");
-                                    }
-                                }
-                            }
-                            "client_no_context_takeover" => {
-                                if client_takeover {
-                                    self.decline(response);
-                                } else {
-                                    client_takeover = true;
-                                    self.config.decompress_reset = true;
-                                    response_str.push_str("; client_no_context_takeover");
-                                }
-                            }
-                            param if param.starts_with("server_max_window_bits") => {
-                                if server_max_bits {
-                                    self.decline(response);
-                                } else {
-                                    server_max_bits = true;
-
-                                    match self.parse_window_parameter(param.split('=').skip(1)) {
-                                        Ok(Some(bits)) => {
-                                            self.deflator = Deflator::new_with_window_bits(
-                                                self.config.compression_level,
-                                                bits,
-                                            );
-                                            response_str.push_str("; ");
-                                            response_str.push_str(param)
-                                        }
-                                        Ok(None) => {}
-                                        Err(_) => {
-                                            self.decline(response);
-                                        }
-                                    }
-                                }
-                            }
-                            param if param.starts_with("client_max_window_bits") => {
-                                if client_max_bits {
-                                    self.decline(response);
-                                } else {
-                                    client_max_bits = true;
-
-                                    match self.parse_window_parameter(param.split('=').skip(1)) {
-                                        Ok(Some(bits)) => {
-                                            self.inflator = Inflator::new_with_window_bits(bits);
-
-                                            response_str.push_str("; ");
-                                            response_str.push_str(param);
-                                            continue;
-                                        }
-                                        Ok(None) => {}
-                                        Err(_) => {
-                                            self.decline(response);
-                                        }
-                                    }
-
-                                    response_str.push_str("; ");
-                                    response_str.push_str(&format!(
-                                        "client_max_window_bits={}",
-                                        self.config.max_window_bits()
-                                    ))
-                                }
-                            }
-                            _ => {
-                                self.decline(response);
-                            }
-                        }
-                    }
-
-                    if !response_str.contains("client_no_context_takeover")
-                        && self.config.request_no_context_takeover()
-                    {
-                        self.config.decompress_reset = true;
-                        response_str.push_str("; client_no_context_takeover");
-                    }
-
-                    if !response_str.contains("server_max_window_bits") {
-                        response_str.push_str("; ");
-                        response_str.push_str(&format!(
-                            "server_max_window_bits={}",
-                            self.config.max_window_bits()
-                        ))
-                    }
-
-                    if !response_str.contains("client_max_window_bits")
-                        && self.config.max_window_bits() < LZ77_MAX_WINDOW_SIZE
-                    {
-                        continue;
-                    }
-
-                    response.headers_mut().insert(
-                        SEC_WEBSOCKET_EXTENSIONS,
-                        HeaderValue::from_str(&response_str)?,
-                    );
+        if let Some(FilterFn(ref filter)) = self.config.offer_filter {
+            if !filter(request.headers()) {
+                self.decline(response);
+                return Ok(());
+            }
+        }
 
-                    self.enabled = true;
+        // The offers may be spread over several header lines, each holding a comma-separated
+        // list of offers, in the order of the client's preference (RFC 7692, section 5).
+        let offers = match header::parse_headers(request.headers()) {
+            Ok(offers) => offers,
+            Err(e) => {
+                self.enabled = false;
+                return Err(DeflateExtensionError::NegotiationError(format!(
+                    "Failed to parse request header: {}",
+                    e,
+                )));
+            }
+        };
 
-                    Ok(())
-                }
-                Err(e) => {
-                    self.enabled = false;
-                    Err(DeflateExtensionError::NegotiationError(format!(
-                        "Failed to parse request header: {}",
-                        e,
-                    )))
-                }
-            };
+        for offer in offers.iter().filter(|offer| offer.is(EXT_IDENT)) {
+            if let Some(accepted) = self.accept_offer(offer) {
+                response.headers_mut().append(
+                    SEC_WEBSOCKET_EXTENSIONS,
+                    HeaderValue::from_str(&accepted.response)?,
+                );
+
+                self.config.client_max_window_bits = accepted.client_max_window_bits;
+                self.config.server_max_window_bits = accepted.server_max_window_bits;
+                self.config.compress_reset = accepted.compress_reset;
+                self.config.decompress_reset = accepted.decompress_reset;
+                self.set_window_bits(
+                    accepted.server_max_window_bits,
+                    accepted.client_max_window_bits,
+                );
+                self.enabled = true;
+
+                return Ok(());
+            }
         }
 
         self.decline(response);
@@ -498,141 +1051,115 @@ impl WebSocketExtension for DeflateExt {
     }
 
     fn on_response<T>(&mut self, response: &Response<T>) -> Result<(), Self::Error> {
-        let mut extension_name = false;
-        let mut server_takeover = false;
-        let mut client_takeover = false;
-        let mut server_max_window_bits = false;
-        let mut client_max_window_bits = false;
-
-        for header in response.headers().get_all(SEC_WEBSOCKET_EXTENSIONS).iter() {
-            match header.to_str() {
-                Ok(header) => {
-                    for param in header.split(';') {
-                        match param.trim().to_lowercase().as_str() {
-                            "permessage-deflate" => {
-                                if extension_name {
-                                    return Err(DeflateExtensionError::NegotiationError(format!(
-                                        "Duplicate extension parameter: permessage-deflate"
-                                    )));
-                                } else {
-                                    self.enabled = true;
-                                    extension_name = true;
-                                }
-                            }
-                            "server_no_context_takeover" => {
-                                if server_takeover {
-                                    return Err(DeflateExtensionError::NegotiationError(format!(
-                                        "Duplicate extension parameter: server_no_context_takeover"
-                                    )));
-                                } else {
-                                    server_takeover = true;
-                                    self.config.decompress_reset = true;
-                                }
-                            }
-                            "client_no_context_takeover" => {
-                                if client_takeover {
-                                    return Err(DeflateExtensionError::NegotiationError(format!(
-                                        "Duplicate extension parameter: client_no_context_takeover"
-                                    )));
-                                } else {
-                                    client_takeover = true;
-
-                                    if self.config.accept_no_context_takeover() {
-                                        self.config.compress_reset = true;
-                                    } else {
-                                        return Err(DeflateExtensionError::NegotiationError(
-                                            format!("The client requires context takeover."),
-                                        ));
-                                    }
-                                }
-                            }
-                            param if param.starts_with("server_max_window_bits") => {
-                                if server_max_window_bits {
-                                    return Err(DeflateExtensionError::NegotiationError(format!(
-                                        "Duplicate extension parameter: server_max_window_bits"
-                                    )));
-                                } else {
-                                    server_max_window_bits = true;
-
-                                    match self.parse_window_parameter(param.split("=").skip(1)) {
-                                        Ok(Some(bits)) => {
-                                            self.inflator = Inflator::new_with_window_bits(bits);
-                                        }
-                                        Ok(None) => {}
-                                        Err(e) => {
-                                            return Err(DeflateExtensionError::NegotiationError(
-                                                format!(
-                                                    "server_max_window_bits parameter error: {}",
-                                                    e
-                                                ),
-                                            ))
-                                        }
-                                    }
-                                }
-                            }
-                            param if param.starts_with("client_max_window_bits") => {
-                                if client_max_window_bits {
-                                    return Err(DeflateExtensionError::NegotiationError(format!(
-                                        "Duplicate extension parameter: client_max_window_bits"
-                                    )));
-                                } else {
-                                    client_max_window_bits = true;
-
-                                    match self.parse_window_parameter(param.split("=").skip(1)) {
-                                        Ok(Some(bits)) => {
-                                            self.deflator = Deflator::new_with_window_bits(
-                                                self.config.compression_level,
-                                                bits,
-                                            );
-                                        }
-                                        Ok(None) => {}
-                                        Err(e) => {
-                                            return Err(DeflateExtensionError::NegotiationError(
-                                                format!(
-                                                    "client_max_window_bits parameter error: {}",
-                                                    e
-                                                ),
-                                            ))
-                                        }
-                                    }
-                                }
-                            }
-                            p => {
-                                return Err(DeflateExtensionError::NegotiationError(format!(
-                                    "Unknown permessage-deflate parameter: {}",
-                                    p
-                                )));
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    self.enabled = false;
-                    return Err(DeflateExtensionError::NegotiationError(format!(
-                        "Failed to parse extension parameter: {}",
-                        e
-                    )));
-                }
+        let extensions = match header::parse_headers(response.headers()) {
+            Ok(extensions) => extensions,
+            Err(e) => {
+                self.enabled = false;
+                return Err(DeflateExtensionError::NegotiationError(format!(
+                    "Failed to parse extension parameter: {}",
+                    e
+                )));
             }
+        };
+
+        // Other extensions are left to the extensions stacked with this one.
+        let mut accepted = None;
+        for extension in extensions
+            .iter()
+            .filter(|extension| extension.is(EXT_IDENT))
+        {
+            if accepted.is_some() {
+                return Err(DeflateExtensionError::NegotiationError(
+                    "Duplicate extension parameter: permessage-deflate".into(),
+                ));
+            }
+            accepted = Some(ResponseParams::parse(extension)?);
         }
+        let params = match accepted {
+            Some(params) => params,
+            None => return Ok(()),
+        };
+
+        // The response does not tell which offer the server accepted, so adopt the first one
+        // it is valid for.
+        let offer = self
+            .offers()
+            .find(|offer| params.accepts(offer))
+            .cloned()
+            .ok_or_else(|| {
+                DeflateExtensionError::NegotiationError(
+                    "The response does not match any permessage-deflate offer".into(),
+                )
+            })?;
+        self.config = offer;
+        params.apply(&mut self.config)?;
+        self.uncompressed_extension = UncompressedExt::new(Some(self.config.max_message_size()));
+        self.set_window_bits(
+            self.config.client_max_window_bits,
+            self.config.server_max_window_bits,
+        );
+        self.enabled = true;
 
         Ok(())
     }
 
     fn on_send_frame(&mut self, mut frame: Frame) -> Result<Frame, Self::Error> {
-        if self.enabled {
-            if let OpCode::Data(_) = frame.header().opcode {
+        // Small messages, messages of kinds not compressed and those opted out of compression
+        // are sent as they are, without rsv1, which the peer reads as is.
+        if self.enabled && frame.compress() {
+            if let OpCode::Data(data) = frame.header().opcode {
+                let compressed = match self.config.compressed_messages {
+                    CompressedMessages::All => true,
+                    CompressedMessages::Text => data == Data::Text,
+                    CompressedMessages::Binary => data == Data::Binary,
+                };
+                if !compressed {
+                    return Ok(frame);
+                }
+                if frame.payload().len() < self.config.min_size_to_compress {
+                    self.stats.messages_below_min_size += 1;
+                    return Ok(frame);
+                }
+
                 let mut compressed = Vec::with_capacity(frame.payload().len());
-                self.deflator.compress(frame.payload(), &mut compressed)?;
+                match frame.compression_level() {
+                    None => self.deflator().compress(frame.payload(), &mut compressed)?,
+                    Some(level) => self.compress_with_level(
+                        frame.payload(),
+                        &mut compressed,
+                        Compression::new(level.min(9)),
+                    )?,
+                }
 
                 let len = compressed.len();
                 compressed.truncate(len - 4);
 
+                self.stats.messages_compressed += 1;
+                self.stats.bytes_sent_uncompressed += frame.payload().len() as u64;
+                self.stats.bytes_sent_compressed += compressed.len() as u64;
+
                 *frame.payload_mut() = compressed;
                 frame.header_mut().rsv1 = true;
 
+                self.context_messages += 1;
+                self.context_bytes = self.context_bytes.saturating_add(frame.payload().len());
+                let DeflateConfig {
+                    reset_context_after_messages: messages,
+                    reset_context_after_bytes: bytes,
+                    ..
+                } = self.config;
+
                 if self.config.compress_reset() {
-                    self.deflator.reset();
+                    self.deflator().reset();
+                    self.stats.compressor_resets += 1;
+                } else if messages.is_some_and(|messages| self.context_messages >= messages)
+                    || bytes.is_some_and(|bytes| self.context_bytes >= bytes)
+                {
+                    // Releasing the compressor resets the context and frees its memory.
+                    self.deflator = None;
+                    self.context_messages = 0;
+                    self.context_bytes = 0;
+                    self.stats.compressor_resets += 1;
                 }
             }
         }
@@ -641,63 +1168,81 @@ impl WebSocketExtension for DeflateExt {
     }
 
     fn on_receive_frame(&mut self, frame: Frame) -> Result<Option<Message>, Self::Error> {
-        let r = if self.enabled && (!self.fragment_buffer.is_empty() || frame.header().rsv1) {
-            if !frame.header().is_final {
-                self.fragment_buffer
-                    .try_push_frame(frame)
-                    .map_err(|s| DeflateExtensionError::Capacity(s.into()))?;
-                Ok(None)
-            } else {
-                let mut compressed = if self.fragment_buffer.is_empty() {
-                    Vec::with_capacity(frame.payload().len())
-                } else {
-                    Vec::with_capacity(self.fragment_buffer.len() + frame.payload().len())
-                };
-
-                let mut decompressed = Vec::with_capacity(frame.payload().len() * 2);
-
-                let opcode = match frame.header().opcode {
-                    OpCode::Data(Data::Continue) => {
-                        self.fragment_buffer
-                            .try_push_frame(frame)
-                            .map_err(|s| DeflateExtensionError::Capacity(s.into()))?;
-
-                        let opcode = self.fragment_buffer.first().unwrap().header().opcode;
+        let r = if self.enabled && (self.incoming.is_some() || frame.header().rsv1) {
+            let header = frame.header();
+            let mut incoming = match (header.opcode, self.incoming.take()) {
+                (OpCode::Data(Data::Continue), Some(_)) if header.rsv1 => {
+                    return Err(DeflateExtensionError::InvalidMessage(
+                        CloseCode::Protocol,
+                        "RSV1 set on a continuation frame".into(),
+                    ));
+                }
+                (OpCode::Data(Data::Continue), Some(incoming)) => incoming,
+                (OpCode::Data(Data::Continue), None) => {
+                    return Err(DeflateExtensionError::InvalidMessage(
+                        CloseCode::Protocol,
+                        "Continue frame but nothing to continue".into(),
+                    ));
+                }
+                (opcode, None) => IncomingMessage {
+                    opcode,
+                    compressed_len: 0,
+                    decompressed: Vec::with_capacity(frame.payload().len() * 2),
+                },
+                (opcode, Some(_)) => {
+                    return Err(DeflateExtensionError::InvalidMessage(
+                        CloseCode::Protocol,
+                        format!("Received {} while waiting for more fragments", opcode),
+                    ));
+                }
+            };
 
-                        self.fragment_buffer.reset().into_iter().for_each(|f| {
-                            compressed.extend(f.into_data());
-                        });
+            let is_final = header.is_final;
+            let mut compressed = frame.into_data();
+            incoming.compressed_len = incoming.compressed_len.saturating_add(compressed.len());
 
-                        opcode
-                    }
-                    _ => {
-                        compressed.put_slice(frame.payload());
-                        frame.header().opcode
-                    }
-                };
+            let mut max_size = self.config.max_message_size();
+            if let Some(ratio) = self.config.max_expansion_ratio() {
+                max_size = max_size.min(incoming.compressed_len.saturating_mul(ratio));
+            }
 
+            if is_final {
                 compressed.extend(&[0, 0, 255, 255]);
+            }
 
-                self.inflator.decompress(&compressed, &mut decompressed)?;
+            self.inflator
+                .decompress(&compressed, &mut incoming.decompressed, max_size)?;
 
-                if self.config.decompress_reset() {
-                    self.inflator.reset(false);
-                }
+            if !is_final {
+                self.incoming = Some(incoming);
+                return Ok(None);
+            }
 
-                self.uncompressed_extension.on_receive_frame(Frame::message(
-                    decompressed,
-                    opcode,
-                    true,
-                ))
+            self.stats.messages_decompressed += 1;
+            self.stats.bytes_received_compressed += incoming.compressed_len as u64;
+            self.stats.bytes_received_uncompressed += incoming.decompressed.len() as u64;
+
+            if self.config.decompress_reset() {
+                self.inflator.reset(false);
+                self.stats.decompressor_resets += 1;
             }
+
+            self.uncompressed_extension.on_receive_frame(Frame::message(
+                incoming.decompressed,
+                incoming.opcode,
+                true,
+            ))
         } else {
             self.uncompressed_extension.on_receive_frame(frame)
         };
 
-        match r {
-            Ok(msg) => Ok(msg),
-            Err(e) => Err(DeflateExtensionError::DeflateError(e.to_string())),
-        }
+        r.map_err(|e| match e {
+            crate::Error::Capacity(msg) => DeflateExtensionError::Capacity(msg),
+            crate::Error::Utf8 => {
+                DeflateExtensionError::InvalidMessage(CloseCode::Invalid, e.to_string())
+            }
+            e => DeflateExtensionError::InvalidMessage(CloseCode::Protocol, e.to_string()),
+        })
     }
 }
 
@@ -731,9 +1276,16 @@ impl Deflator {
             window_size = 9;
         }
 
-        Deflator {
-            compress: Compress::new_with_window_bits(compression, false, window_size),
-        }
+        // Negotiation never agrees on a smaller window than the backend supports.
+        #[cfg(feature = "__deflate-zlib")]
+        let compress = Compress::new_with_window_bits(compression, false, window_size);
+        #[cfg(not(feature = "__deflate-zlib"))]
+        let compress = {
+            debug_assert_eq!(window_size, LZ77_MAX_WINDOW_SIZE);
+            Compress::new(compression, false)
+        };
+
+        Deflator { compress }
     }
 
     fn reset(&mut self) {
@@ -747,8 +1299,9 @@ impl Deflator {
         loop {
             output_size = output.len();
 
+            // Even an empty message needs space for the sync marker.
             if output_size == output.capacity() {
-                output.reserve(input.len());
+                output.reserve(input.len().max(16));
             }
 
             let before_out = self.compress.total_out();
@@ -756,7 +1309,7 @@ impl Deflator {
 
             let out_slice = unsafe {
                 slice::from_raw_parts_mut(
-                    output.as_mut_ptr().offset(output_size as isize),
+                    output.as_mut_ptr().add(output_size),
                     output.capacity() - output_size,
                 )
             };
@@ -773,8 +1326,13 @@ impl Deflator {
             }
 
             match status {
+                // The flush is complete once the input is consumed and there is output space
+                // left; miniz would emit another sync marker if called again.
                 Status::Ok | Status::BufError => {
-                    if before_out == self.compress.total_out() && read_buff.is_empty() {
+                    if read_buff.is_empty()
+                        && (before_out == self.compress.total_out()
+                            || output.len() < output.capacity())
+                    {
                         return Ok(());
                     }
                 }
@@ -802,22 +1360,35 @@ impl Inflator {
             window_size = 9;
         }
 
-        Inflator {
-            decompress: Decompress::new_with_window_bits(false, window_size),
-        }
+        // Inflating with the largest window works for a smaller one too.
+        #[cfg(feature = "__deflate-zlib")]
+        let decompress = Decompress::new_with_window_bits(false, window_size);
+        #[cfg(not(feature = "__deflate-zlib"))]
+        let decompress = {
+            let _ = window_size;
+            Decompress::new(false)
+        };
+
+        Inflator { decompress }
     }
 
     fn reset(&mut self, zlib_header: bool) {
         self.decompress.reset(zlib_header)
     }
 
-    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), DecompressError> {
+    /// Decompress `input` into `output`, failing once the output grows beyond `max_size`
+    /// instead of allocating without bound.
+    fn decompress(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        max_size: usize,
+    ) -> Result<(), DeflateExtensionError> {
         let mut read_buff = Vec::from(input);
         let mut output_size;
 
         loop {
             output_size = output.len();
-
             if output_size == output.capacity() {
                 output.reserve(input.len());
             }
@@ -827,7 +1398,7 @@ impl Inflator {
 
             let out_slice = unsafe {
                 slice::from_raw_parts_mut(
-                    output.as_mut_ptr().offset(output_size as isize),
+                    output.as_mut_ptr().add(output_size),
                     output.capacity() - output_size,
                 )
             };
@@ -843,80 +1414,635 @@ impl Inflator {
                 output.set_len((self.decompress.total_out() - before_out) as usize + output_size);
             }
 
+            if output.len() > max_size {
+                return Err(DeflateExtensionError::Capacity(
+                    format!("Decompressed message too big: more than {}", max_size).into(),
+                ));
+            }
+
             match status {
                 Status::Ok | Status::BufError => {
-                    if before_out == self.decompress.total_out() && read_buff.is_empty() {
+                    if read_buff.is_empty()
+                        && (before_out == self.decompress.total_out()
+                            || output.len() < output.capacity())
+                    {
                         return Ok(());
                     }
                 }
-                s => panic!("Decompression error: {:?}", s),
+                // A peer ending the deflate stream is no reason to panic.
+                s => {
+                    return Err(DeflateExtensionError::InflateError(format!(
+                        "Unexpected status: {:?}",
+                        s
+                    )))
+                }
             }
         }
     }
 }
 
-/// A buffer for holding continuation frames. Ensures that the total length of all of the frame's
-/// payloads does not exceed `max_len`.
-///
-/// Defaults to an initial capacity of ten frames.
+/// A compressed message being received, inflated frame by frame as the fragments arrive.
 #[derive(Debug)]
-struct FragmentBuffer {
-    fragments: Vec<Frame>,
-    fragments_len: usize,
-    max_len: usize,
+struct IncomingMessage {
+    /// The opcode of the first frame.
+    opcode: OpCode,
+    /// The total length of the compressed payloads received so far.
+    compressed_len: usize,
+    /// The payload decompressed so far.
+    decompressed: Vec<u8>,
 }
 
-impl FragmentBuffer {
-    /// Creates a new fragment buffer that will permit a maximum length of `max_len`.
-    fn new(max_len: usize) -> FragmentBuffer {
-        FragmentBuffer {
-            fragments: Vec::with_capacity(10),
-            fragments_len: 0,
-            max_len,
+#[cfg(test)]
+mod tests {
+    use super::{
+        CompressedMessages, DeflateConfig, DeflateConfigBuilder, DeflateExt, CUSTOM_WINDOW_BITS,
+    };
+    use crate::extensions::WebSocketExtension;
+    use crate::protocol::frame::coding::{Data, OpCode};
+    use crate::protocol::frame::{Frame, FrameSocket};
+    use crate::protocol::{Role, WebSocket, WebSocketConfig, WriteOptions};
+    use crate::Message;
+    use flate2::Compression;
+    use http::header::{HeaderMap, SEC_WEBSOCKET_EXTENSIONS, USER_AGENT};
+    use http::{Request, Response};
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn negotiate(offers: &[&str]) -> (DeflateExt, Option<String>) {
+        negotiate_with(offers, DeflateConfig::default())
+    }
+
+    fn negotiate_with(offers: &[&str], config: DeflateConfig) -> (DeflateExt, Option<String>) {
+        let mut request = Request::builder();
+        for offer in offers {
+            request = request.header(SEC_WEBSOCKET_EXTENSIONS, *offer);
         }
+        let request = request.body(()).unwrap();
+        let mut response = Response::new(());
+        let mut ext = DeflateExt::new(config);
+        ext.on_receive_request(&request, &mut response).unwrap();
+        let accepted = response
+            .headers()
+            .get(SEC_WEBSOCKET_EXTENSIONS)
+            .map(|h| h.to_str().unwrap().to_owned());
+        (ext, accepted)
     }
 
-    /// Attempts to push a frame into the buffer. This will fail if the new length of the buffer's
-    /// frames exceeds the maximum capacity of `max_len`.
-    fn try_push_frame(&mut self, frame: Frame) -> Result<(), String> {
-        let FragmentBuffer {
-            fragments,
-            fragments_len,
-            max_len,
-        } = self;
+    #[test]
+    fn multiple_offers() {
+        // The first acceptable offer wins, whether the offers share a header line or not.
+        let (ext, accepted) = negotiate(&[
+            "x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=42",
+            "permessage-deflate; client_no_context_takeover, permessage-deflate",
+        ]);
+        assert!(ext.enabled());
+        assert_eq!(
+            accepted.as_deref(),
+            Some("permessage-deflate; client_no_context_takeover; server_max_window_bits=15")
+        );
+
+        // Limiting the window of the client needs an offer allowing it.
+        if !CUSTOM_WINDOW_BITS {
+            return;
+        }
+        let config = DeflateConfigBuilder::default()
+            .client_max_window_bits(9)
+            .build();
+        let (ext, accepted) = negotiate_with(
+            &[
+                "permessage-deflate; server_max_window_bits=10",
+                "permessage-deflate; server_max_window_bits=10; client_max_window_bits",
+            ],
+            config,
+        );
+        assert!(ext.enabled());
+        assert_eq!(
+            accepted.as_deref(),
+            Some("permessage-deflate; server_max_window_bits=10; client_max_window_bits=9")
+        );
 
-        *fragments_len += frame.payload().len();
+        let (ext, accepted) = negotiate(&["x-webkit-deflate-frame", "permessage-deflate; foo"]);
+        assert!(!ext.enabled());
+        assert_eq!(accepted, None);
+    }
+
+    #[test]
+    fn offer_filter() {
+        let broken = String::from("broken/");
+        // `Option::is_none_or` needs a newer compiler than the crate otherwise does.
+        #[allow(clippy::unnecessary_map_or)]
+        let filter = move |headers: &HeaderMap| {
+            headers.get(USER_AGENT).map_or(true, |agent| {
+                !agent.as_bytes().starts_with(broken.as_bytes())
+            })
+        };
+        let config = DeflateConfigBuilder::default()
+            .offer_filter(Some(Arc::new(filter)))
+            .build();
+
+        let negotiate = |agent: &str| {
+            let request = Request::builder()
+                .header(SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate")
+                .header(USER_AGENT, agent)
+                .body(())
+                .unwrap();
+            let mut response = Response::new(());
+            let mut ext = DeflateExt::new(config.clone());
+            ext.on_receive_request(&request, &mut response).unwrap();
+            assert_eq!(
+                ext.enabled(),
+                response.headers().contains_key(SEC_WEBSOCKET_EXTENSIONS)
+            );
+            ext.enabled()
+        };
+        assert!(negotiate("fine/1.0"));
+        assert!(!negotiate("broken/2.3"));
+    }
 
-        if *fragments_len > *max_len || frame.len() > *max_len - *fragments_len {
-            return Err(format!(
-                "Message too big: {} + {} > {}",
-                fragments_len, fragments_len, max_len
+    #[test]
+    fn split_response() {
+        let mut ext = DeflateExt::new(DeflateConfig::default());
+        let response = Response::builder()
+            .header(
+                SEC_WEBSOCKET_EXTENSIONS,
+                "permessage-deflate, permessage-deflate",
             )
-            .into());
-        } else {
-            fragments.push(frame);
-            Ok(())
+            .body(())
+            .unwrap();
+        assert!(ext.on_response(&response).is_err());
+    }
+
+    #[test]
+    fn min_size_to_compress() {
+        let config = DeflateConfigBuilder::default()
+            .min_size_to_compress(64)
+            .build();
+        let (mut sender, _) = negotiate_with(&["permessage-deflate"], config);
+        let (mut receiver, _) = negotiate(&["permessage-deflate"]);
+
+        let small = "a".repeat(63);
+        let frame = Frame::message(small.clone().into(), OpCode::Data(Data::Text), true);
+        let frame = sender.on_send_frame(frame).unwrap();
+        assert!(!frame.header().rsv1);
+        assert_eq!(frame.payload(), small.as_bytes());
+        assert_eq!(
+            receiver.on_receive_frame(frame).unwrap(),
+            Some(Message::Text(small))
+        );
+
+        let large = "a".repeat(64);
+        let frame = Frame::message(large.clone().into(), OpCode::Data(Data::Text), true);
+        let frame = sender.on_send_frame(frame).unwrap();
+        assert!(frame.header().rsv1);
+        assert!(frame.payload().len() < large.len());
+        assert_eq!(
+            receiver.on_receive_frame(frame).unwrap(),
+            Some(Message::Text(large))
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let offer = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+        let config = DeflateConfigBuilder::default()
+            .min_size_to_compress(64)
+            .build();
+        let (mut sender, _) = negotiate_with(&[offer], config);
+        let (mut receiver, _) = negotiate(&[offer]);
+
+        let large = "a".repeat(1000);
+        for payload in &["small", large.as_str(), large.as_str()] {
+            let frame = Frame::message(payload.as_bytes().into(), OpCode::Data(Data::Text), true);
+            let frame = sender.on_send_frame(frame).unwrap();
+            receiver.on_receive_frame(frame).unwrap().unwrap();
+        }
+
+        let sent = *sender.stats();
+        assert_eq!(sent.messages_compressed, 2);
+        assert_eq!(sent.messages_below_min_size, 1);
+        assert_eq!(sent.bytes_sent_uncompressed, 2000);
+        assert!(sent.bytes_sent_compressed > 0 && sent.bytes_sent_compressed < 200);
+        assert_eq!(sent.compressor_resets, 2);
+
+        let received = *receiver.stats();
+        assert_eq!(received.messages_decompressed, 2);
+        assert_eq!(
+            received.bytes_received_compressed,
+            sent.bytes_sent_compressed
+        );
+        assert_eq!(received.bytes_received_uncompressed, 2000);
+        assert_eq!(received.decompressor_resets, 2);
+        assert_eq!(received.messages_compressed, 0);
+    }
+
+    #[test]
+    fn reset_context() {
+        let config = DeflateConfigBuilder::default()
+            .reset_context_after_messages(Some(2))
+            .reset_context_after_bytes(Some(2500))
+            .build();
+        let (mut sender, _) = negotiate_with(&["permessage-deflate"], config);
+        let (mut receiver, _) = negotiate(&["permessage-deflate"]);
+        let idle = sender.memory_usage();
+
+        // The peer keeps its context, which a reset compressor does not refer to.
+        let mut send = |payload: &str| {
+            let frame = Frame::message(payload.as_bytes().into(), OpCode::Data(Data::Text), true);
+            let frame = sender.on_send_frame(frame).unwrap();
+            assert_eq!(
+                receiver.on_receive_frame(frame).unwrap(),
+                Some(Message::Text(payload.into()))
+            );
+            (sender.stats().compressor_resets, sender.memory_usage())
+        };
+        let text = "a".repeat(1000);
+        let (resets, busy) = send(&text);
+        assert_eq!(resets, 0);
+        assert!(busy >= idle + (256 << 10));
+        assert_eq!(send(&text), (1, idle));
+        assert_eq!(send(&text), (1, busy));
+        assert_eq!(send(&"b".repeat(2000)), (2, idle));
+    }
+
+    #[test]
+    fn compression_level() {
+        let config = DeflateConfig::with_compression_level(Compression::fast());
+        let (mut sender, _) = negotiate_with(&["permessage-deflate"], config);
+        let (mut receiver, _) = negotiate(&["permessage-deflate"]);
+        let text: String = (0..2000).map(|i| format!("{} ", i % 100)).collect();
+
+        // Messages compressed with another level start over with a new context, which the
+        // receiver copes with.
+        let mut sizes = Vec::new();
+        for level in &[Some(0), None, Some(9), Some(42), Some(0)] {
+            let mut frame = Frame::message(text.clone().into(), OpCode::Data(Data::Text), true);
+            frame.set_compression_level(*level);
+            let frame = sender.on_send_frame(frame).unwrap();
+            assert!(frame.header().rsv1);
+            sizes.push(frame.payload().len());
+            assert_eq!(
+                receiver.on_receive_frame(frame).unwrap(),
+                Some(Message::Text(text.clone()))
+            );
+        }
+        // Level 0 stores the message as it is.
+        assert!(sizes[0] > text.len());
+        assert!(sizes[1] < text.len() / 10);
+        assert!(sizes[2] < text.len() / 10);
+        assert_eq!(sizes[4], sizes[0]);
+        assert_eq!(sender.stats().compressor_resets, 4);
+    }
+
+    #[test]
+    fn configured_compression_level() {
+        // The configured level applies with the largest window too, and asking for it
+        // explicitly keeps the context.
+        let config = DeflateConfig::with_compression_level(Compression::none());
+        let (mut sender, _) = negotiate_with(&["permessage-deflate"], config);
+        let text: String = (0..2000).map(|i| format!("{} ", i % 100)).collect();
+        for level in &[None, Some(0)] {
+            let mut frame = Frame::message(text.clone().into(), OpCode::Data(Data::Text), true);
+            frame.set_compression_level(*level);
+            let frame = sender.on_send_frame(frame).unwrap();
+            assert!(frame.payload().len() > text.len());
+        }
+        assert_eq!(sender.config().server_max_window_bits(), 15);
+        assert_eq!(sender.stats().compressor_resets, 0);
+    }
+
+    #[test]
+    fn compressed_messages() {
+        let config = DeflateConfigBuilder::default()
+            .compressed_messages(CompressedMessages::Text)
+            .build();
+        let (mut sender, _) = negotiate_with(&["permessage-deflate"], config);
+        let (mut receiver, _) = negotiate(&["permessage-deflate"]);
+
+        let payload = vec![b'a'; 256];
+        for (data, compressed) in &[(Data::Binary, false), (Data::Text, true)] {
+            let frame = Frame::message(payload.clone(), OpCode::Data(*data), true);
+            let frame = sender.on_send_frame(frame).unwrap();
+            assert_eq!(frame.header().rsv1, *compressed);
+            assert_eq!(frame.payload().len() < payload.len(), *compressed);
+            receiver.on_receive_frame(frame).unwrap().unwrap();
         }
+        assert_eq!(sender.stats().messages_compressed, 1);
     }
 
-    /// Returns the total length of all of the frames that have been pushed into the buffer.
-    fn len(&self) -> usize {
-        self.fragments_len
+    #[test]
+    fn write_without_compression() {
+        let (ext, _) = negotiate(&["permessage-deflate"]);
+        let config = WebSocketConfig::default_with_encoder(ext);
+        let mut socket =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, Some(config));
+        let payload = vec![0; 256];
+        let uncompressed = WriteOptions {
+            compress: false,
+            ..WriteOptions::default()
+        };
+        socket
+            .write_message_with_options(Message::binary(payload.clone()), uncompressed)
+            .unwrap();
+        socket
+            .write_message(Message::binary(payload.clone()))
+            .unwrap();
+
+        let mut sent = FrameSocket::new(Cursor::new(socket.get_ref().get_ref().clone()));
+        let frame = sent.read_frame(None).unwrap().unwrap();
+        assert!(!frame.header().rsv1);
+        assert_eq!(frame.into_data(), payload);
+        let frame = sent.read_frame(None).unwrap().unwrap();
+        assert!(frame.header().rsv1);
+        assert!(frame.payload().len() < payload.len());
     }
 
-    /// Returns whether the buffer is empty.
-    fn is_empty(&self) -> bool {
-        self.fragments.is_empty()
+    #[test]
+    fn decompression_limits() {
+        // zlib-rs compresses zeros about half as well as zlib on the fast level, so the best
+        // level keeps the bomb small with every backend.
+        let config = DeflateConfig::with_compression_level(Compression::best());
+        let (mut sender, _) = negotiate_with(&["permessage-deflate"], config);
+        let payload = vec![0; 1 << 20];
+        let frame = Frame::message(payload.clone(), OpCode::Data(Data::Binary), true);
+        let bomb = sender.on_send_frame(frame).unwrap();
+        assert!(bomb.payload().len() < 8 << 10);
+
+        let receive = |config: DeflateConfig| {
+            let (mut receiver, _) = negotiate_with(&["permessage-deflate"], config);
+            receiver
+                .on_receive_frame(bomb.clone())
+                .map_err(crate::Error::from)
+        };
+        assert_eq!(
+            receive(DeflateConfig::default()).unwrap(),
+            Some(Message::Binary(payload))
+        );
+
+        let small = DeflateConfigBuilder::default()
+            .max_message_size(Some(64 << 10))
+            .build();
+        assert!(matches!(receive(small), Err(crate::Error::Capacity(_))));
+
+        let ratio = DeflateConfigBuilder::default()
+            .max_expansion_ratio(Some(100))
+            .build();
+        assert!(matches!(receive(ratio), Err(crate::Error::Capacity(_))));
     }
 
-    /// Returns the first element of the fragments slice, or `None` if it is empty.
-    fn first(&self) -> Option<&Frame> {
-        self.fragments.first()
+    /// Split a compressed message into frames of `size` bytes, as a peer fragmenting it would.
+    fn fragment(message: Frame, size: usize) -> Vec<Frame> {
+        let opcode = message.header().opcode;
+        let chunks: Vec<_> = message.payload().chunks(size).map(Vec::from).collect();
+        let last = chunks.len() - 1;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let opcode = if i == 0 {
+                    opcode
+                } else {
+                    OpCode::Data(Data::Continue)
+                };
+                let mut frame = Frame::message(chunk, opcode, i == last);
+                frame.header_mut().rsv1 = i == 0;
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let (mut sender, _) = negotiate(&["permessage-deflate"]);
+        let text: String = (0..2000).map(|i| format!("{} ", i)).collect();
+        let frame = Frame::message(text.clone().into(), OpCode::Data(Data::Text), true);
+        let frames = fragment(sender.on_send_frame(frame).unwrap(), 16);
+        assert!(frames.len() > 2);
+
+        // Every fragment is inflated as it arrives, the message is complete with the last one.
+        let (mut receiver, _) = negotiate(&["permessage-deflate"]);
+        let last = frames.len() - 1;
+        for (i, frame) in frames.iter().cloned().enumerate() {
+            let message = receiver.on_receive_frame(frame).unwrap();
+            if i == last {
+                assert_eq!(message, Some(Message::Text(text.clone())));
+            } else {
+                assert_eq!(message, None);
+            }
+            if i == last - 1 {
+                assert!(!receiver.incoming.as_ref().unwrap().decompressed.is_empty());
+            }
+        }
+
+        // The limit fails the message at the fragment exceeding it.
+        let config = DeflateConfigBuilder::default()
+            .max_message_size(Some(text.len() / 2))
+            .build();
+        let (mut receiver, _) = negotiate_with(&["permessage-deflate"], config);
+        let failed = frames
+            .iter()
+            .cloned()
+            .position(|frame| receiver.on_receive_frame(frame).is_err())
+            .unwrap();
+        assert!(failed < last);
+
+        // A continuation frame with RSV1 set is a protocol error.
+        let (mut receiver, _) = negotiate(&["permessage-deflate"]);
+        let mut frames = frames.into_iter();
+        receiver.on_receive_frame(frames.next().unwrap()).unwrap();
+        let mut second = frames.next().unwrap();
+        second.header_mut().rsv1 = true;
+        assert!(receiver.on_receive_frame(second).is_err());
     }
 
-    /// Drains the buffer and resets it to an initial capacity of 10 elements.
-    fn reset(&mut self) -> Vec<Frame> {
-        self.fragments_len = 0;
-        replace(&mut self.fragments, Vec::with_capacity(10))
+    #[cfg(feature = "__deflate-zlib")]
+    #[test]
+    fn asymmetric_window_bits() {
+        // A client inflating with a small window, while compressing with the largest one.
+        let config = DeflateConfigBuilder::default()
+            .server_max_window_bits(9)
+            .build();
+        let mut client = DeflateExt::new(config);
+        let request = client.on_make_request(Request::new(()));
+        assert_eq!(
+            request.headers()[SEC_WEBSOCKET_EXTENSIONS],
+            "permessage-deflate; client_max_window_bits; server_max_window_bits=9"
+        );
+
+        let config = DeflateConfigBuilder::default()
+            .client_max_window_bits(12)
+            .build();
+        let mut server = DeflateExt::new(config);
+        let mut response = Response::new(());
+        server.on_receive_request(&request, &mut response).unwrap();
+        assert_eq!(
+            response.headers()[SEC_WEBSOCKET_EXTENSIONS],
+            "permessage-deflate; client_max_window_bits=12; server_max_window_bits=9"
+        );
+        client.on_response(&response).unwrap();
+        for ext in &[&client, &server] {
+            assert_eq!(ext.config.client_max_window_bits(), 12);
+            assert_eq!(ext.config.server_max_window_bits(), 9);
+        }
+
+        let text: String = (0..2000).map(|i| format!("{} ", i)).collect();
+        let frame = Frame::message(text.clone().into(), OpCode::Data(Data::Text), true);
+        let frame = client.on_send_frame(frame).unwrap();
+        assert_eq!(
+            server.on_receive_frame(frame).unwrap(),
+            Some(Message::Text(text.clone()))
+        );
+        let frame = Frame::message(text.clone().into(), OpCode::Data(Data::Text), true);
+        let frame = server.on_send_frame(frame).unwrap();
+        assert_eq!(
+            client.on_receive_frame(frame).unwrap(),
+            Some(Message::Text(text))
+        );
+
+        // A server exceeding the window offered fails the negotiation.
+        let mut client = DeflateExt::new(
+            DeflateConfigBuilder::default()
+                .server_max_window_bits(9)
+                .build(),
+        );
+        let response = Response::builder()
+            .header(
+                SEC_WEBSOCKET_EXTENSIONS,
+                "permessage-deflate; server_max_window_bits=10",
+            )
+            .body(())
+            .unwrap();
+        assert!(client.on_response(&response).is_err());
+    }
+
+    #[test]
+    fn fallback_offers() {
+        // A server insisting on context takeover declines the first offer.
+        let config = DeflateConfigBuilder::default()
+            .request_no_context_takeover(true)
+            .build();
+        let mut client = DeflateExt::new(config).with_fallback_offer(DeflateConfig::default());
+        let request = client.on_make_request(Request::new(()));
+        let config = DeflateConfigBuilder::default()
+            .accept_no_context_takeover(false)
+            .build();
+        let mut server = DeflateExt::new(config);
+        let mut response = Response::new(());
+        server.on_receive_request(&request, &mut response).unwrap();
+        assert!(server.enabled());
+        client.on_response(&response).unwrap();
+        assert!(client.enabled());
+        assert!(!client.config().request_no_context_takeover());
+        assert!(!client.config().decompress_reset());
+
+        // A server accepting it makes the client adopt the first offer.
+        let config = DeflateConfigBuilder::default()
+            .request_no_context_takeover(true)
+            .build();
+        let mut client = DeflateExt::new(config).with_fallback_offer(DeflateConfig::default());
+        let request = client.on_make_request(Request::new(()));
+        let (_, accepted) = negotiate(&[request.headers()[SEC_WEBSOCKET_EXTENSIONS]
+            .to_str()
+            .unwrap()]);
+        let response = Response::builder()
+            .header(SEC_WEBSOCKET_EXTENSIONS, accepted.unwrap())
+            .body(())
+            .unwrap();
+        client.on_response(&response).unwrap();
+        assert!(client.config().decompress_reset());
+
+        if !CUSTOM_WINDOW_BITS {
+            return;
+        }
+        let config = DeflateConfigBuilder::default()
+            .client_max_window_bits(11)
+            .server_max_window_bits(10)
+            .build();
+        let mut client = DeflateExt::new(config).with_fallback_offer(DeflateConfig::default());
+        let request = client.on_make_request(Request::new(()));
+        assert_eq!(
+            request.headers()[SEC_WEBSOCKET_EXTENSIONS],
+            "permessage-deflate; client_max_window_bits=11; server_max_window_bits=10, \
+             permessage-deflate; client_max_window_bits"
+        );
+
+        // The server using the largest window only fits the fallback offer.
+        let response = Response::builder()
+            .header(SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate")
+            .body(())
+            .unwrap();
+        client.on_response(&response).unwrap();
+        assert_eq!(client.config().client_max_window_bits(), 15);
+        assert_eq!(client.config().server_max_window_bits(), 15);
+
+        // A window between the offered ones only fits the fallback offer, a larger one neither.
+        let client = || {
+            DeflateExt::new(
+                DeflateConfigBuilder::default()
+                    .server_max_window_bits(10)
+                    .build(),
+            )
+            .with_fallback_offer(
+                DeflateConfigBuilder::default()
+                    .server_max_window_bits(12)
+                    .build(),
+            )
+        };
+        let response = |bits: u8| {
+            Response::builder()
+                .header(
+                    SEC_WEBSOCKET_EXTENSIONS,
+                    format!("permessage-deflate; server_max_window_bits={}", bits),
+                )
+                .body(())
+                .unwrap()
+        };
+        let mut fallback = client();
+        fallback.on_response(&response(11)).unwrap();
+        assert_eq!(fallback.config().server_max_window_bits(), 11);
+        assert!(client().on_response(&response(13)).is_err());
+    }
+
+    #[cfg(not(feature = "__deflate-zlib"))]
+    #[test]
+    fn unsupported_window_bits() {
+        // The client does not let the server limit its window.
+        let mut client = DeflateExt::new(DeflateConfig::default());
+        let request = client.on_make_request(Request::new(()));
+        assert_eq!(
+            request.headers()[SEC_WEBSOCKET_EXTENSIONS],
+            "permessage-deflate"
+        );
+        let response = Response::builder()
+            .header(
+                SEC_WEBSOCKET_EXTENSIONS,
+                "permessage-deflate; client_max_window_bits=10",
+            )
+            .body(())
+            .unwrap();
+        assert!(client.on_response(&response).is_err());
+
+        // The server declines offers limiting its window.
+        let (ext, accepted) = negotiate(&[
+            "permessage-deflate; server_max_window_bits=10",
+            "permessage-deflate; client_max_window_bits",
+        ]);
+        assert!(ext.enabled());
+        assert_eq!(
+            accepted.as_deref(),
+            Some("permessage-deflate; client_max_window_bits=15; server_max_window_bits=15")
+        );
+    }
+
+    #[test]
+    fn empty_message() {
+        let (mut sender, _) = negotiate(&["permessage-deflate"]);
+        let (mut receiver, _) = negotiate(&["permessage-deflate"]);
+        let frame = Frame::message(Vec::new(), OpCode::Data(Data::Text), true);
+        let frame = sender.on_send_frame(frame).unwrap();
+        assert!(frame.header().rsv1);
+        assert_eq!(
+            receiver.on_receive_frame(frame).unwrap(),
+            Some(Message::Text(String::new()))
+        );
     }
 }
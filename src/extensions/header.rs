@@ -0,0 +1,262 @@
+//! Parsing the `Sec-WebSocket-Extensions` header (RFC 6455, section 9.1).
+
+use std::fmt;
+
+use http::header::{HeaderMap, SEC_WEBSOCKET_EXTENSIONS};
+
+use crate::error::{Error, Result};
+
+/// An extension with its parameters, one element of a `Sec-WebSocket-Extensions` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    /// The name of the extension, e.g. `permessage-deflate`.
+    pub name: String,
+    /// The parameters in the order given, with their values unquoted.
+    pub params: Vec<(String, Option<String>)>,
+}
+
+impl Extension {
+    /// Returns true if the extension is called `name`, ignoring the case.
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Returns the first parameter called `name`, ignoring the case: `Some(None)` for a
+    /// parameter without a value, `None` if there is no such parameter.
+    pub fn param(&self, name: &str) -> Option<Option<&str>> {
+        self.params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_deref())
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)?;
+        for (name, value) in &self.params {
+            match value {
+                Some(value) => write!(f, "; {}={}", name, value)?,
+                None => write!(f, "; {}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the value of a `Sec-WebSocket-Extensions` header into the extensions it lists.
+///
+/// Several offers of the same extension are kept apart, in the order given. Parameter values
+/// may be quoted, but have to be tokens once unquoted.
+///
+/// ```
+/// use tungstenite::extensions::header::parse;
+///
+/// let extensions = parse("permessage-deflate; client_max_window_bits=\"10\", x-foo").unwrap();
+/// assert_eq!(extensions.len(), 2);
+/// assert!(extensions[0].is("permessage-deflate"));
+/// assert_eq!(extensions[0].param("client_max_window_bits"), Some(Some("10")));
+/// assert_eq!(extensions[1].name, "x-foo");
+/// ```
+pub fn parse(value: &str) -> Result<Vec<Extension>> {
+    let mut parser = Parser {
+        input: value,
+        pos: 0,
+    };
+    let mut extensions = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => return Ok(extensions),
+            // Empty list elements are allowed (RFC 7230, section 7).
+            Some(b',') => {
+                parser.pos += 1;
+                continue;
+            }
+            Some(_) => {}
+        }
+
+        let name = parser.token()?;
+        let mut params = Vec::new();
+        while parser.eat(b';') {
+            let param = parser.token()?;
+            let value = if parser.eat(b'=') {
+                Some(parser.value()?)
+            } else {
+                None
+            };
+            params.push((param, value));
+        }
+        extensions.push(Extension { name, params });
+
+        parser.skip_whitespace();
+        if parser.peek().is_some() && !parser.eat(b',') {
+            return Err(parser.error("',' or ';'"));
+        }
+    }
+}
+
+/// Parse all `Sec-WebSocket-Extensions` headers of a request or response, which together form
+/// one list.
+pub fn parse_headers(headers: &HeaderMap) -> Result<Vec<Extension>> {
+    let mut extensions = Vec::new();
+    for header in headers.get_all(SEC_WEBSOCKET_EXTENSIONS) {
+        let value = header.to_str().map_err(|_| {
+            Error::Protocol("Sec-WebSocket-Extensions header is not valid ASCII".into())
+        })?;
+        extensions.extend(parse(value)?);
+    }
+    Ok(extensions)
+}
+
+/// A cursor over a header value.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip the whitespace and `byte` after it, returning whether `byte` was there.
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.peek() == Some(byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn token(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.peek().is_some_and(is_token_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("a token"));
+        }
+        Ok(self.input[start..self.pos].to_owned())
+    }
+
+    /// A parameter value: a token, or a quoted string holding a token.
+    fn value(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        if self.peek() != Some(b'"') {
+            return self.token();
+        }
+        self.pos += 1;
+        let mut value = String::new();
+        loop {
+            let byte = self.peek().ok_or_else(|| self.error("a closing quote"))?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| self.error("a quoted character"))?;
+                    self.pos += 1;
+                    value.push(escaped as char);
+                }
+                byte => value.push(byte as char),
+            }
+        }
+        if value.is_empty() || !value.bytes().all(is_token_char) {
+            return Err(self.error("a token in the quoted string"));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, expected: &str) -> Error {
+        Error::Protocol(
+            format!(
+                "Invalid Sec-WebSocket-Extensions header: expected {} at position {}",
+                expected, self.pos
+            )
+            .into(),
+        )
+    }
+}
+
+/// Check whether `byte` may appear in a token (RFC 7230, section 3.2.6).
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Extension};
+
+    fn extension(name: &str, params: &[(&str, Option<&str>)]) -> Extension {
+        Extension {
+            name: name.into(),
+            params: params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.map(String::from)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn offers() {
+        assert_eq!(
+            parse(
+                "permessage-deflate;client_max_window_bits ; server_max_window_bits = 10 ,\
+                 permessage-deflate, x-webkit-deflate-frame"
+            )
+            .unwrap(),
+            vec![
+                extension(
+                    "permessage-deflate",
+                    &[
+                        ("client_max_window_bits", None),
+                        ("server_max_window_bits", Some("10"))
+                    ]
+                ),
+                extension("permessage-deflate", &[]),
+                extension("x-webkit-deflate-frame", &[]),
+            ]
+        );
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(parse(" , foo,,").unwrap(), vec![extension("foo", &[])]);
+    }
+
+    #[test]
+    fn quoted_values() {
+        let extensions = parse(r#"foo; a="1"; b="x\yz""#).unwrap();
+        assert_eq!(
+            extensions,
+            vec![extension("foo", &[("a", Some("1")), ("b", Some("xyz"))])]
+        );
+        assert_eq!(extensions[0].to_string(), "foo; a=1; b=xyz");
+        // A comma in a quoted string does not separate extensions, but is not a token either.
+        assert!(parse(r#"foo; a="1,2""#).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        for value in &[
+            "foo;",
+            "foo; =1",
+            "foo; a=",
+            "foo; a=\"1",
+            "foo; a=\"\"",
+            "foo bar",
+            "foo; a b",
+            "@foo",
+            ";a",
+        ] {
+            assert!(parse(value).is_err(), "{:?} should not parse", value);
+        }
+    }
+}
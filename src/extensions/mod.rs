@@ -2,15 +2,91 @@
 
 use http::{Request, Response};
 
-use crate::protocol::frame::Frame;
+use crate::protocol::frame::{CloseFrame, Frame, FrameHeader};
 use crate::Message;
 
 /// A permessage-deflate WebSocket extension (RFC 7692).
-#[cfg(feature = "deflate")]
+#[cfg(feature = "__deflate")]
 pub mod deflate;
+/// Parsing of the `Sec-WebSocket-Extensions` header, for use by extensions.
+pub mod header;
+/// Payload transformations stacked on top of another extension.
+pub mod transform;
 /// An uncompressed message handler for a WebSocket.
 pub mod uncompressed;
 
+/// A set of the reserved bits of a frame header, RSV1 to RSV3.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedBits {
+    /// The RSV1 bit, used by permessage-deflate.
+    pub rsv1: bool,
+    /// The RSV2 bit.
+    pub rsv2: bool,
+    /// The RSV3 bit.
+    pub rsv3: bool,
+}
+
+impl ReservedBits {
+    /// No reserved bits.
+    pub const NONE: ReservedBits = ReservedBits {
+        rsv1: false,
+        rsv2: false,
+        rsv3: false,
+    };
+    /// The RSV1 bit only.
+    pub const RSV1: ReservedBits = ReservedBits {
+        rsv1: true,
+        ..ReservedBits::NONE
+    };
+    /// The RSV2 bit only.
+    pub const RSV2: ReservedBits = ReservedBits {
+        rsv2: true,
+        ..ReservedBits::NONE
+    };
+    /// The RSV3 bit only.
+    pub const RSV3: ReservedBits = ReservedBits {
+        rsv3: true,
+        ..ReservedBits::NONE
+    };
+
+    /// The reserved bits set in `header`.
+    pub fn of(header: &FrameHeader) -> ReservedBits {
+        ReservedBits {
+            rsv1: header.rsv1,
+            rsv2: header.rsv2,
+            rsv3: header.rsv3,
+        }
+    }
+
+    /// The bits set in either `self` or `other`.
+    pub fn union(self, other: ReservedBits) -> ReservedBits {
+        ReservedBits {
+            rsv1: self.rsv1 || other.rsv1,
+            rsv2: self.rsv2 || other.rsv2,
+            rsv3: self.rsv3 || other.rsv3,
+        }
+    }
+
+    /// The bits set in both `self` and `other`.
+    pub fn intersection(self, other: ReservedBits) -> ReservedBits {
+        ReservedBits {
+            rsv1: self.rsv1 && other.rsv1,
+            rsv2: self.rsv2 && other.rsv2,
+            rsv3: self.rsv3 && other.rsv3,
+        }
+    }
+
+    /// Returns true if every bit set in `other` is set in `self` as well.
+    pub fn contains(self, other: ReservedBits) -> bool {
+        self.union(other) == self
+    }
+
+    /// Returns true if no bit is set.
+    pub fn is_empty(self) -> bool {
+        self == ReservedBits::NONE
+    }
+}
+
 /// A trait for defining WebSocket extensions for both WebSocket clients and servers. Extensions
 /// may be stacked by nesting them inside one another.
 pub trait WebSocketExtension {
@@ -25,6 +101,16 @@ pub trait WebSocketExtension {
         false
     }
 
+    /// The reserved bits the extension uses, once negotiated.
+    ///
+    /// Incoming frames with any other reserved bit set fail the connection, and so do control
+    /// frames with any reserved bit set: extensions only see data frames. When a message is
+    /// split into several frames because of `max_frame_size`, the reserved bits set by
+    /// `on_send_frame` go on the first frame.
+    fn reserved_bits(&self) -> ReservedBits {
+        ReservedBits::NONE
+    }
+
     /// For WebSocket clients, this will be called when a `Request` is being constructed.
     fn on_make_request<T>(&mut self, request: Request<T>) -> Request<T> {
         request
@@ -40,7 +126,8 @@ pub trait WebSocketExtension {
     }
 
     /// For WebSocket clients, this will be called when a response from the server has been
-    /// received. If an error is produced, then subsequent calls to `rsv1()` should return `false`.
+    /// received. If an error is produced, then subsequent calls to `reserved_bits()` should
+    /// return no bits.
     fn on_response<T>(&mut self, _response: &Response<T>) -> Result<(), Self::Error> {
         Ok(())
     }
@@ -53,4 +140,13 @@ pub trait WebSocketExtension {
     /// Called when a frame has been received and unmasked. The frame provided frame will be of the
     /// type `OpCode::Data`.
     fn on_receive_frame(&mut self, frame: Frame) -> Result<Option<Message>, Self::Error>;
+
+    /// Called once when the closing handshake starts, with the close frame sent or received
+    /// first. Data frames may still arrive until the handshake completes.
+    fn on_close(&mut self, _frame: Option<&CloseFrame>) {}
+
+    /// Called once when the connection is over: when it terminates, or when the WebSocket is
+    /// dropped before that. Stateful extensions can release their resources or report final
+    /// statistics here.
+    fn on_teardown(&mut self) {}
 }
@@ -0,0 +1,331 @@
+//! Payload transformations stacked on top of another extension.
+
+use http::{Request, Response};
+
+use crate::extensions::uncompressed::UncompressedExt;
+use crate::extensions::{ReservedBits, WebSocketExtension};
+use crate::protocol::frame::coding::{Data, OpCode};
+use crate::protocol::frame::{CloseFrame, Frame};
+use crate::Message;
+
+/// A payload transformation negotiated as a WebSocket extension, marking the messages it
+/// applies to with reserved bits, e.g. RSV2 for encrypted messages.
+///
+/// A transform neither assembles messages nor sees control frames: stacked on top of another
+/// extension with `Transformed`, it only encodes and decodes payloads, while the extension
+/// below takes care of the rest.
+pub trait FrameTransform {
+    /// An error type that the transform produces.
+    type Error: Into<crate::Error>;
+
+    /// The reserved bits marking the messages the transform applies to. They are set on every
+    /// message sent once the transform is enabled.
+    fn reserved_bits(&self) -> ReservedBits;
+
+    /// Returns whether the transform has been negotiated.
+    fn enabled(&self) -> bool;
+
+    /// For WebSocket clients, this will be called when a `Request` is being constructed.
+    fn on_make_request<T>(&mut self, request: Request<T>) -> Request<T> {
+        request
+    }
+
+    /// For WebSocket server, this will be called when a `Request` has been received.
+    fn on_receive_request<T>(
+        &mut self,
+        _request: &Request<T>,
+        _response: &mut Response<T>,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// For WebSocket clients, this will be called when a response from the server has been
+    /// received.
+    fn on_response<T>(&mut self, _response: &Response<T>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Encode the payload of an outgoing message, given as a single data frame after the
+    /// extension below encoded it.
+    fn encode(&mut self, frame: &mut Frame) -> Result<(), Self::Error>;
+
+    /// Decode the payload of an incoming data frame, before the extension below decodes it.
+    /// Called for every frame of a message whose first frame has the reserved bits of the
+    /// transform set.
+    fn decode(&mut self, frame: &mut Frame) -> Result<(), Self::Error>;
+}
+
+/// A `FrameTransform` stacked on top of another extension, e.g. on `DeflateExt` to transform
+/// compressed messages.
+///
+/// Outgoing messages are encoded by the extension below first, then by the transform; incoming
+/// ones are decoded the other way round. The transform negotiates first, so its offer comes
+/// first in the `Sec-WebSocket-Extensions` header.
+#[derive(Debug)]
+pub struct Transformed<T, Ext = UncompressedExt> {
+    transform: T,
+    inner: Ext,
+    /// Whether the frames of the message being received have to be decoded.
+    decoding: bool,
+}
+
+impl<T, Ext> Transformed<T, Ext> {
+    /// Stack `transform` on top of `inner`.
+    pub fn new(transform: T, inner: Ext) -> Self {
+        Transformed {
+            transform,
+            inner,
+            decoding: false,
+        }
+    }
+
+    /// Returns a shared reference to the transform.
+    pub fn transform(&self) -> &T {
+        &self.transform
+    }
+
+    /// Returns a shared reference to the extension below the transform.
+    pub fn inner(&self) -> &Ext {
+        &self.inner
+    }
+}
+
+impl<T, Ext> WebSocketExtension for Transformed<T, Ext>
+where
+    T: FrameTransform + Default,
+    Ext: WebSocketExtension,
+{
+    type Error = crate::Error;
+
+    fn new(max_message_size: Option<usize>) -> Self {
+        Transformed::new(T::default(), Ext::new(max_message_size))
+    }
+
+    fn enabled(&self) -> bool {
+        self.transform.enabled() || self.inner.enabled()
+    }
+
+    fn reserved_bits(&self) -> ReservedBits {
+        let bits = self.inner.reserved_bits();
+        if self.transform.enabled() {
+            bits.union(self.transform.reserved_bits())
+        } else {
+            bits
+        }
+    }
+
+    fn on_make_request<B>(&mut self, request: Request<B>) -> Request<B> {
+        let request = self.transform.on_make_request(request);
+        self.inner.on_make_request(request)
+    }
+
+    fn on_receive_request<B>(
+        &mut self,
+        request: &Request<B>,
+        response: &mut Response<B>,
+    ) -> Result<(), Self::Error> {
+        self.transform
+            .on_receive_request(request, response)
+            .map_err(Into::into)?;
+        self.inner
+            .on_receive_request(request, response)
+            .map_err(Into::into)
+    }
+
+    fn on_response<B>(&mut self, response: &Response<B>) -> Result<(), Self::Error> {
+        self.transform.on_response(response).map_err(Into::into)?;
+        self.inner.on_response(response).map_err(Into::into)
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Frame, Self::Error> {
+        let mut frame = self.inner.on_send_frame(frame).map_err(Into::into)?;
+        if self.transform.enabled() && matches!(frame.header().opcode, OpCode::Data(_)) {
+            self.transform.encode(&mut frame).map_err(Into::into)?;
+            let bits = ReservedBits::of(frame.header()).union(self.transform.reserved_bits());
+            set_reserved_bits(&mut frame, bits);
+        }
+        Ok(frame)
+    }
+
+    fn on_receive_frame(&mut self, mut frame: Frame) -> Result<Option<Message>, Self::Error> {
+        if self.transform.enabled() {
+            let bits = self.transform.reserved_bits();
+            let received = ReservedBits::of(frame.header());
+            if frame.header().opcode != OpCode::Data(Data::Continue) {
+                self.decoding = !received.intersection(bits).is_empty();
+            }
+            if self.decoding {
+                self.transform.decode(&mut frame).map_err(Into::into)?;
+                // Leave the extension below only the bits it knows about.
+                let remaining = ReservedBits {
+                    rsv1: received.rsv1 && !bits.rsv1,
+                    rsv2: received.rsv2 && !bits.rsv2,
+                    rsv3: received.rsv3 && !bits.rsv3,
+                };
+                set_reserved_bits(&mut frame, remaining);
+            }
+            if frame.header().is_final {
+                self.decoding = false;
+            }
+        }
+        self.inner.on_receive_frame(frame).map_err(Into::into)
+    }
+
+    fn on_close(&mut self, frame: Option<&CloseFrame>) {
+        self.inner.on_close(frame)
+    }
+
+    fn on_teardown(&mut self) {
+        self.inner.on_teardown()
+    }
+}
+
+/// Set the reserved bits of `frame` to `bits`.
+fn set_reserved_bits(frame: &mut Frame, bits: ReservedBits) {
+    let header = frame.header_mut();
+    header.rsv1 = bits.rsv1;
+    header.rsv2 = bits.rsv2;
+    header.rsv3 = bits.rsv3;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameTransform, Transformed};
+    use crate::extensions::header;
+    use crate::extensions::uncompressed::UncompressedExt;
+    use crate::extensions::{ReservedBits, WebSocketExtension};
+    use crate::protocol::frame::{Frame, FrameSocket};
+    use crate::protocol::{Role, WebSocket, WebSocketConfig};
+    use crate::{Error, Message};
+    use http::header::SEC_WEBSOCKET_EXTENSIONS;
+    use http::{HeaderValue, Request, Response};
+    use std::io::Cursor;
+
+    /// Flips the bits of the payloads of messages marked with RSV2.
+    #[derive(Debug, Default)]
+    struct Invert {
+        enabled: bool,
+    }
+
+    impl FrameTransform for Invert {
+        type Error = Error;
+
+        fn reserved_bits(&self) -> ReservedBits {
+            ReservedBits::RSV2
+        }
+
+        fn enabled(&self) -> bool {
+            self.enabled
+        }
+
+        fn on_make_request<T>(&mut self, mut request: Request<T>) -> Request<T> {
+            let value = HeaderValue::from_static("x-invert");
+            request
+                .headers_mut()
+                .append(SEC_WEBSOCKET_EXTENSIONS, value);
+            request
+        }
+
+        fn on_receive_request<T>(
+            &mut self,
+            request: &Request<T>,
+            response: &mut Response<T>,
+        ) -> Result<(), Error> {
+            let offers = header::parse_headers(request.headers())?;
+            self.enabled = offers.iter().any(|offer| offer.is("x-invert"));
+            if self.enabled {
+                let value = HeaderValue::from_static("x-invert");
+                response
+                    .headers_mut()
+                    .append(SEC_WEBSOCKET_EXTENSIONS, value);
+            }
+            Ok(())
+        }
+
+        fn on_response<T>(&mut self, response: &Response<T>) -> Result<(), Error> {
+            let extensions = header::parse_headers(response.headers())?;
+            self.enabled = extensions.iter().any(|extension| extension.is("x-invert"));
+            Ok(())
+        }
+
+        fn encode(&mut self, frame: &mut Frame) -> Result<(), Error> {
+            frame
+                .payload_mut()
+                .iter_mut()
+                .for_each(|byte| *byte = !*byte);
+            Ok(())
+        }
+
+        fn decode(&mut self, frame: &mut Frame) -> Result<(), Error> {
+            self.encode(frame)
+        }
+    }
+
+    type InvertExt = Transformed<Invert, UncompressedExt>;
+
+    /// Negotiate the extension, returning the client and the server side.
+    fn negotiate() -> (InvertExt, InvertExt) {
+        let mut client = Transformed::new(Invert::default(), UncompressedExt::new(None));
+        let mut server = Transformed::new(Invert::default(), UncompressedExt::new(None));
+        let request = client.on_make_request(Request::new(()));
+        let mut response = Response::new(());
+        server.on_receive_request(&request, &mut response).unwrap();
+        client.on_response(&response).unwrap();
+        assert!(client.transform().enabled() && server.transform().enabled());
+        (client, server)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (client, server) = negotiate();
+        let config = WebSocketConfig {
+            max_frame_size: Some(4),
+            ..WebSocketConfig::default_with_encoder(client)
+        };
+        let mut client =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Client, Some(config));
+        client
+            .write_message(Message::text("Hello, World!"))
+            .unwrap();
+        let sent = client.get_ref().get_ref().clone();
+
+        // The message is split into frames, the first one marked with RSV2.
+        let mut frames = FrameSocket::new(Cursor::new(sent.clone()));
+        let mut first = frames.read_frame(None).unwrap().unwrap();
+        assert!(first.header().rsv2 && !first.header().rsv1);
+        first.apply_mask();
+        assert_eq!(
+            first.payload(),
+            &b"Hell".iter().map(|b| !b).collect::<Vec<_>>()
+        );
+        let second = frames.read_frame(None).unwrap().unwrap();
+        assert!(!second.header().rsv2);
+
+        let config = WebSocketConfig::default_with_encoder(server);
+        let mut server = WebSocket::from_raw_socket(Cursor::new(sent), Role::Server, Some(config));
+        assert_eq!(
+            server.read_message().unwrap(),
+            Message::text("Hello, World!")
+        );
+    }
+
+    #[test]
+    fn not_negotiated() {
+        // Without the transform, RSV2 fails the connection.
+        let (client, _) = negotiate();
+        let mut client = WebSocket::from_raw_socket(
+            Cursor::new(Vec::new()),
+            Role::Client,
+            Some(WebSocketConfig::default_with_encoder(client)),
+        );
+        client.write_message(Message::text("Hello")).unwrap();
+        let sent = client.get_ref().get_ref().clone();
+
+        let mut server: WebSocket<_, UncompressedExt> =
+            WebSocket::from_raw_socket(Cursor::new(sent), Role::Server, None);
+        match server.read_message() {
+            Err(Error::Protocol(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
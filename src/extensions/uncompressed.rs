@@ -2,8 +2,8 @@ use crate::extensions::WebSocketExtension;
 use crate::protocol::frame::coding::{Data, OpCode};
 use crate::protocol::frame::Frame;
 use crate::protocol::message::{IncompleteMessage, IncompleteMessageType};
-use crate::{Error, Message};
 use crate::protocol::MAX_MESSAGE_SIZE;
+use crate::{Error, Message};
 
 /// An uncompressed message handler for a WebSocket.
 #[derive(Debug)]
@@ -16,7 +16,7 @@ impl Default for UncompressedExt {
     fn default() -> Self {
         UncompressedExt {
             incomplete: None,
-            max_message_size: Some(MAX_MESSAGE_SIZE)
+            max_message_size: Some(MAX_MESSAGE_SIZE),
         }
     }
 }
@@ -49,14 +49,6 @@ impl WebSocketExtension for UncompressedExt {
     fn on_receive_frame(&mut self, frame: Frame) -> Result<Option<Message>, Self::Error> {
         let fin = frame.header().is_final;
 
-        let hdr = frame.header();
-
-        if hdr.rsv1 || hdr.rsv2 || hdr.rsv3 {
-            return Err(Error::Protocol(
-                "Reserved bits are non-zero and no WebSocket extensions are enabled".into(),
-            ));
-        }
-
         match frame.header().opcode {
             OpCode::Data(data) => match data {
                 Data::Continue => {
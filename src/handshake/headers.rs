@@ -8,9 +8,16 @@ use httparse::Status;
 use super::machine::TryParse;
 use crate::error::Result;
 
-/// Limit for the number of header lines.
+/// Default limit for the number of header lines.
 pub const MAX_HEADERS: usize = 124;
 
+/// Default limit for the size of a request head received by a server, in bytes.
+pub const MAX_HEAD_SIZE: usize = 64 << 10;
+
+/// Default limit for the length of the request line and of each header line of a request
+/// received by a server, in bytes.
+pub const MAX_LINE_SIZE: usize = 8 << 10;
+
 /// Trait to convert raw objects into HTTP parseables.
 pub(crate) trait FromHttparse<T>: Sized {
     /// Convert raw object into parsed HTTP headers.
@@ -30,6 +37,17 @@ impl<'b: 'h, 'h> FromHttparse<&'b [httparse::Header<'h>]> for HeaderMap {
         Ok(headers)
     }
 }
+/// Check whether any of the `name` headers lists `token`, as a comma-separated list of tokens
+/// compared case-insensitively, e.g. `Connection: keep-alive, Upgrade`.
+pub(crate) fn contains_token(headers: &HeaderMap, name: &str, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
 impl TryParse for HeaderMap {
     fn try_parse(buf: &[u8]) -> Result<Option<(usize, Self)>> {
         let mut hbuffer = [httparse::EMPTY_HEADER; MAX_HEADERS];
@@ -44,7 +62,7 @@ impl TryParse for HeaderMap {
 mod tests {
 
     use super::super::machine::TryParse;
-    use super::HeaderMap;
+    use super::{contains_token, HeaderMap};
 
     #[test]
     fn headers() {
@@ -73,6 +91,20 @@ mod tests {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn token_lists() {
+        const DATA: &[u8] = b"Connection: keep-alive, Upgrade\r\n\
+              Upgrade: h2c\r\n\
+              Upgrade: WebSocket\r\n\
+              \r\n";
+        let (_, hdr) = HeaderMap::try_parse(DATA).unwrap().unwrap();
+        assert!(contains_token(&hdr, "Connection", "upgrade"));
+        assert!(contains_token(&hdr, "Connection", "Keep-Alive"));
+        assert!(contains_token(&hdr, "Upgrade", "websocket"));
+        assert!(!contains_token(&hdr, "Upgrade", "web"));
+        assert!(!contains_token(&hdr, "Host", "foo.com"));
+    }
+
     #[test]
     fn headers_incomplete() {
         const DATA: &[u8] = b"Host: foo.com\r\n\
@@ -1,7 +1,10 @@
 use bytes::Buf;
 use log::*;
-use std::io::{Cursor, Read, Write};
+use std::io::{self, Cursor, Read, Write};
+use std::time::Instant;
 
+use super::headers::MAX_HEADERS;
+use super::Interest;
 use crate::error::{Error, Result};
 use crate::util::NonBlockingResult;
 use input_buffer::{InputBuffer, MIN_READ};
@@ -11,6 +14,86 @@ use input_buffer::{InputBuffer, MIN_READ};
 pub struct HandshakeMachine<Stream> {
     stream: Stream,
     state: HandshakeState,
+    /// The number of bytes still to be discarded before the data to parse.
+    skip: usize,
+    /// Limits on the data to parse.
+    limits: HeadLimits,
+    /// When the handshake times out, if ever.
+    deadline: Option<Instant>,
+}
+
+/// Limits on the HTTP head read during the handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct HeadLimits {
+    /// The maximum number of header lines.
+    pub max_headers: usize,
+    /// The maximum size of the head in bytes, `None` means no limit.
+    pub max_size: Option<usize>,
+    /// The maximum length of the start line (request or status line) in bytes, `None` means
+    /// no limit.
+    pub max_start_line: Option<usize>,
+    /// The maximum length of a header line in bytes, `None` means no limit.
+    pub max_header_line: Option<usize>,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        HeadLimits {
+            max_headers: MAX_HEADERS,
+            max_size: None,
+            max_start_line: None,
+            max_header_line: None,
+        }
+    }
+}
+
+impl HeadLimits {
+    /// Check the size of a head, complete or not.
+    fn check_size(&self, size: usize) -> Option<(Limit, Error)> {
+        match self.max_size {
+            Some(max_size) if size > max_size => {
+                let message = format!("Header longer than {} bytes", max_size);
+                Some((Limit::Head, Error::Capacity(message.into())))
+            }
+            _ => None,
+        }
+    }
+
+    /// Check the line lengths of a possibly incomplete head.
+    fn check_lines(&self, data: &[u8]) -> Option<(Limit, Error)> {
+        if self.max_start_line.is_none() && self.max_header_line.is_none() {
+            return None;
+        }
+        for (i, line) in data.split(|b| *b == b'\n').enumerate() {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            // The empty line ends the head.
+            if line.is_empty() && i > 0 {
+                break;
+            }
+            let (limit, max, name) = if i == 0 {
+                (Limit::StartLine, self.max_start_line, "Start line")
+            } else {
+                (Limit::Head, self.max_header_line, "Header line")
+            };
+            match max {
+                Some(max) if line.len() > max => {
+                    let message = format!("{} longer than {} bytes", name, max);
+                    return Some((limit, Error::Capacity(message.into())));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// The limit the HTTP head went over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The start line, i.e. the request line or the status line, is too long.
+    StartLine,
+    /// The headers are too long or too many.
+    Head,
 }
 
 impl<Stream> HandshakeMachine<Stream> {
@@ -19,6 +102,28 @@ impl<Stream> HandshakeMachine<Stream> {
         HandshakeMachine {
             stream,
             state: HandshakeState::Reading(InputBuffer::with_capacity(MIN_READ)),
+            skip: 0,
+            limits: HeadLimits::default(),
+            deadline: None,
+        }
+    }
+    /// Start reading data from the peer, failing the stage with `StageResult::LimitExceeded`
+    /// when the data goes over `limits`.
+    pub fn start_read_limited(stream: Stream, limits: HeadLimits) -> Self {
+        HandshakeMachine {
+            limits,
+            ..HandshakeMachine::start_read(stream)
+        }
+    }
+    /// Start reading data from the peer, discarding the first `skip` bytes, which may include
+    /// the already read `part`.
+    pub fn start_read_skipping(stream: Stream, part: Vec<u8>, skip: usize) -> Self {
+        HandshakeMachine {
+            stream,
+            state: HandshakeState::Reading(InputBuffer::from_partially_read(part)),
+            skip,
+            limits: HeadLimits::default(),
+            deadline: None,
         }
     }
     /// Start writing data to the peer.
@@ -26,8 +131,24 @@ impl<Stream> HandshakeMachine<Stream> {
         HandshakeMachine {
             stream,
             state: HandshakeState::Writing(Cursor::new(data.into())),
+            skip: 0,
+            limits: HeadLimits::default(),
+            deadline: None,
         }
     }
+    /// Start with nothing to read or write, e.g. when the data was read elsewhere: the first
+    /// round finishes the stage without any I/O.
+    pub fn start_idle(stream: Stream) -> Self {
+        HandshakeMachine::start_write(stream, Vec::new())
+    }
+    /// Fail the rounds with an `io::ErrorKind::TimedOut` error from `deadline` on.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+    /// Returns when the handshake times out, if ever.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
     /// Returns a shared reference to the inner stream.
     pub fn get_ref(&self) -> &Stream {
         &self.stream
@@ -36,37 +157,88 @@ impl<Stream> HandshakeMachine<Stream> {
     pub fn get_mut(&mut self) -> &mut Stream {
         &mut self.stream
     }
+    /// Tell whether the machine waits to read or to write.
+    pub fn interest(&self) -> Interest {
+        match self.state {
+            HandshakeState::Reading(_) => Interest::Readable,
+            HandshakeState::Writing(_) => Interest::Writable,
+        }
+    }
 }
 
 impl<Stream: Read + Write> HandshakeMachine<Stream> {
     /// Perform a single handshake round.
     pub fn single_round<Obj: TryParse>(mut self) -> Result<RoundResult<Obj, Stream>> {
         trace!("Doing handshake round.");
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Handshake timed out",
+            )));
+        }
         match self.state {
             HandshakeState::Reading(mut buf) => {
                 let read = buf
                     .prepare_reserve(MIN_READ)
-                    .with_limit(usize::max_value()) // TODO limit size
+                    // An incomplete head is rejected as soon as it is too long, so this only
+                    // guards against reading far beyond it.
+                    .with_limit(
+                        self.limits
+                            .max_size
+                            .map_or(usize::MAX, |max_size| max_size.saturating_add(MIN_READ)),
+                    )
                     .map_err(|_| Error::Capacity("Header too long".into()))?
                     .read_from(&mut self.stream)
                     .no_block()?;
                 match read {
                     Some(0) => Err(Error::Protocol("Handshake not finished".into())),
-                    Some(_) => Ok(
-                        if let Some((size, obj)) = Obj::try_parse(Buf::bytes(&buf))? {
-                            buf.advance(size);
-                            RoundResult::StageFinished(StageResult::DoneReading {
-                                result: obj,
-                                stream: self.stream,
-                                tail: buf.into_vec(),
-                            })
-                        } else {
-                            RoundResult::Incomplete(HandshakeMachine {
+                    Some(_) => Ok({
+                        let skipped = self.skip.min(buf.remaining());
+                        buf.advance(skipped);
+                        self.skip -= skipped;
+                        let data = Buf::bytes(&buf);
+                        let parsed = match self.limits.check_lines(data) {
+                            Some(exceeded) => Err(exceeded),
+                            None => match Obj::try_parse_limited(data, self.limits.max_headers) {
+                                // Data following a complete head is not part of it.
+                                Ok(Some((size, obj))) => match self.limits.check_size(size) {
+                                    Some(exceeded) => Err(exceeded),
+                                    None => Ok(Some((size, obj))),
+                                },
+                                Ok(None) => match self.limits.check_size(data.len()) {
+                                    Some(exceeded) => Err(exceeded),
+                                    None => Ok(None),
+                                },
+                                // Too many header lines.
+                                Err(error @ Error::Capacity(_)) => Err((Limit::Head, error)),
+                                Err(error) => return Err(error),
+                            },
+                        };
+                        match parsed {
+                            Ok(Some((size, obj))) => {
+                                buf.advance(size);
+                                RoundResult::StageFinished(StageResult::DoneReading {
+                                    result: obj,
+                                    stream: self.stream,
+                                    tail: buf.into_vec(),
+                                })
+                            }
+                            Ok(None) => RoundResult::Incomplete(HandshakeMachine {
                                 state: HandshakeState::Reading(buf),
                                 ..self
-                            })
-                        },
-                    ),
+                            }),
+                            Err((limit, error)) => {
+                                RoundResult::StageFinished(StageResult::LimitExceeded {
+                                    stream: self.stream,
+                                    limit,
+                                    error,
+                                })
+                            }
+                        }
+                    }),
                     None => Ok(RoundResult::WouldBlock(HandshakeMachine {
                         state: HandshakeState::Reading(buf),
                         ..self
@@ -74,7 +246,11 @@ impl<Stream: Read + Write> HandshakeMachine<Stream> {
                 }
             }
             HandshakeState::Writing(mut buf) => {
-                assert!(buf.has_remaining());
+                if !buf.has_remaining() {
+                    return Ok(RoundResult::StageFinished(StageResult::DoneWriting(
+                        self.stream,
+                    )));
+                }
                 if let Some(size) = self.stream.write(Buf::bytes(&buf)).no_block()? {
                     assert!(size > 0);
                     buf.advance(size);
@@ -119,12 +295,24 @@ pub enum StageResult<Obj, Stream> {
     },
     /// Writing round finished.
     DoneWriting(Stream),
+    /// The data read went over the limits before it could be parsed.
+    LimitExceeded {
+        stream: Stream,
+        limit: Limit,
+        error: Error,
+    },
 }
 
 /// The parseable object.
 pub trait TryParse: Sized {
     /// Return Ok(None) if incomplete, Err on syntax error.
     fn try_parse(data: &[u8]) -> Result<Option<(usize, Self)>>;
+
+    /// Like `try_parse`, but allowing at most `max_headers` header lines where supported.
+    fn try_parse_limited(data: &[u8], max_headers: usize) -> Result<Option<(usize, Self)>> {
+        let _ = max_headers;
+        Self::try_parse(data)
+    }
 }
 
 /// The handshake state.
@@ -11,7 +11,6 @@ use std::fmt;
 use std::io::{Read, Write};
 
 use base64;
-use sha1::{Digest, Sha1};
 
 use self::machine::{HandshakeMachine, RoundResult, StageResult, TryParse};
 use crate::error::Error;
@@ -34,11 +33,27 @@ impl<Role: HandshakeRole> MidHandshake<Role> {
         &mut self.machine
     }
 
+    /// Tell what the handshake is waiting for to make progress.
+    ///
+    /// After `handshake` returned `HandshakeError::Interrupted`, register this interest with
+    /// your event loop and call `handshake` again once the stream is ready.
+    pub fn interest(&self) -> Interest {
+        self.machine.interest()
+    }
+
     /// Restarts the handshake process.
     pub fn handshake(mut self) -> Result<Role::FinalResult, HandshakeError<Role>> {
         let mut mach = self.machine;
         loop {
-            mach = match mach.single_round()? {
+            let deadline = mach.deadline();
+            let round = match mach.single_round() {
+                Ok(round) => round,
+                Err(e) => {
+                    self.role.on_failure(&e);
+                    return Err(e.into());
+                }
+            };
+            mach = match round {
                 RoundResult::WouldBlock(m) => {
                     return Err(HandshakeError::Interrupted(MidHandshake {
                         machine: m,
@@ -46,15 +61,31 @@ impl<Role: HandshakeRole> MidHandshake<Role> {
                     }))
                 }
                 RoundResult::Incomplete(m) => m,
-                RoundResult::StageFinished(s) => match self.role.stage_finished(s)? {
-                    ProcessingResult::Continue(m) => m,
-                    ProcessingResult::Done(result) => return Ok(result),
+                RoundResult::StageFinished(s) => match self.role.stage_finished(s) {
+                    Ok(ProcessingResult::Continue(mut m)) => {
+                        m.set_deadline(deadline);
+                        m
+                    }
+                    Ok(ProcessingResult::Done(result)) => return Ok(result),
+                    Err(e) => {
+                        self.role.on_failure(&e);
+                        return Err(e.into());
+                    }
                 },
             }
         }
     }
 }
 
+/// The readiness a handshake in progress is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interest {
+    /// The handshake is waiting for data from the peer.
+    Readable,
+    /// The handshake is waiting to send data to the peer.
+    Writable,
+}
+
 /// A handshake result.
 pub enum HandshakeError<Role: HandshakeRole> {
     /// Handshake was interrupted (would block).
@@ -102,6 +133,8 @@ pub trait HandshakeRole {
         &mut self,
         finish: StageResult<Self::IncomingData, Self::InternalStream>,
     ) -> Result<ProcessingResult<Self::InternalStream, Self::FinalResult>, Error>;
+    #[doc(hidden)]
+    fn on_failure(&mut self, _error: &Error) {}
 }
 
 /// Stage processing result.
@@ -112,26 +145,104 @@ pub enum ProcessingResult<Stream, FinalResult> {
     Done(FinalResult),
 }
 
-/// Turns a Sec-WebSocket-Key into a Sec-WebSocket-Accept.
-fn convert_key(input: &[u8]) -> Result<String, Error> {
+/// Derive the `Sec-WebSocket-Accept` response header value from a `Sec-WebSocket-Key` request
+/// header value.
+///
+/// This is useful for frameworks that do the HTTP part of the handshake themselves and only
+/// need this piece of it.
+///
+/// ```
+/// use tungstenite::handshake::derive_accept_key;
+///
+/// // Example from RFC 6455.
+/// let accept = derive_accept_key(b"dGhlIHNhbXBsZSBub25jZQ==");
+/// assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+/// ```
+pub fn derive_accept_key(request_key: &[u8]) -> String {
     // ... field is constructed by concatenating /key/ ...
     // ... with the string "258EAFA5-E914-47DA-95CA-C5AB0DC85B11" (RFC 6455)
     const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    base64::encode(sha1_digest(&[request_key, WS_GUID]))
+}
+
+/// Compute the SHA-1 digest of the concatenated `parts`.
+#[cfg(not(feature = "openssl-sha1"))]
+fn sha1_digest(parts: &[&[u8]]) -> [u8; 20] {
+    use sha1::{Digest, Sha1};
+
     let mut sha1 = Sha1::default();
-    sha1.update(input);
-    sha1.update(WS_GUID);
-    Ok(base64::encode(&sha1.finalize()))
+    for part in parts {
+        sha1.update(part);
+    }
+    sha1.finalize().into()
+}
+
+/// Compute the SHA-1 digest of the concatenated `parts`.
+#[cfg(feature = "openssl-sha1")]
+fn sha1_digest(parts: &[&[u8]]) -> [u8; 20] {
+    let mut sha1 = openssl::sha::Sha1::new();
+    for part in parts {
+        sha1.update(part);
+    }
+    sha1.finish()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::convert_key;
+    use super::client::ClientHandshake;
+    use super::{derive_accept_key, HandshakeError, Interest};
+    use crate::client::IntoClientRequest;
+    use crate::extensions::uncompressed::UncompressedExt;
+
+    use std::io;
+
+    struct WouldBlockStream {
+        writable: bool,
+    }
+
+    impl io::Read for WouldBlockStream {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl io::Write for WouldBlockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writable {
+                Ok(buf.len())
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interest() {
+        let request = "ws://localhost/".into_client_request().unwrap();
+        let stream = WouldBlockStream { writable: false };
+        let mid = ClientHandshake::<_, UncompressedExt>::start(stream, request, None).unwrap();
+        let mut mid = match mid.handshake() {
+            Err(HandshakeError::Interrupted(mid)) => mid,
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        };
+        assert_eq!(mid.interest(), Interest::Writable);
+
+        mid.get_mut().get_mut().writable = true;
+        let mid = match mid.handshake() {
+            Err(HandshakeError::Interrupted(mid)) => mid,
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        };
+        assert_eq!(mid.interest(), Interest::Readable);
+    }
 
     #[test]
     fn key_conversion() {
         // example from RFC 6455
         assert_eq!(
-            convert_key(b"dGhlIHNhbXBsZSBub25jZQ==").unwrap(),
+            derive_accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
             "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
         );
     }
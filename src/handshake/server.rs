@@ -1,92 +1,131 @@
 //! Server handshake machine.
 
+use std::fmt;
 use std::io::{self, Read, Write};
 use std::marker::PhantomData;
+use std::mem;
+use std::net::SocketAddr;
 use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use http::{HeaderMap, Request as HttpRequest, Response as HttpResponse, StatusCode};
 use httparse::Status;
 use log::*;
 
-use super::headers::{FromHttparse, MAX_HEADERS};
-use super::machine::{HandshakeMachine, StageResult, TryParse};
-use super::{convert_key, HandshakeRole, MidHandshake, ProcessingResult};
+use super::headers::{contains_token, FromHttparse, MAX_HEADERS, MAX_HEAD_SIZE, MAX_LINE_SIZE};
+use super::machine::{HandshakeMachine, HeadLimits, Limit, StageResult, TryParse};
+use super::{derive_accept_key, HandshakeError, HandshakeRole, MidHandshake, ProcessingResult};
 use crate::error::{Error, Result};
-use crate::extensions::WebSocketExtension;
+use crate::extensions::uncompressed::UncompressedExt;
+use crate::extensions::{header, WebSocketExtension};
 use crate::protocol::{Role, WebSocket, WebSocketConfig};
+use crate::stream::SetTimeout;
 
 /// Server request type.
+///
+/// This is a plain `http::Request`, so the typed header APIs and URI parsing of the `http`
+/// crate, and any middleware built on them, work in callbacks.
 pub type Request = HttpRequest<()>;
 
-/// Server response type.
+/// Server response type, a plain `http::Response`.
 pub type Response = HttpResponse<()>;
 
-/// Server error response type.
+/// Server error response type, a plain `http::Response` with an optional body.
 pub type ErrorResponse = HttpResponse<Option<String>>;
 
 /// Create a response for the request.
-pub fn create_response(request: &Request) -> Result<Response> {
+///
+/// The request may have any body type, so servers which do the HTTP part of the handshake
+/// themselves, e.g. with `hyper`, can pass their request as is, send the response, and hand the
+/// upgraded connection to `WebSocket::from_upgraded`.
+pub fn create_response<B>(request: &HttpRequest<B>) -> Result<Response> {
+    let key = check_request(request).map_err(|(error, _)| error)?;
+
+    let builder = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .version(request.version())
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()));
+
+    Ok(builder.body(())?)
+}
+
+/// Build the error for an invalid request along with the response to reject it with.
+fn invalid_request(status: StatusCode, message: &'static str) -> (Error, ErrorResponse) {
+    let mut response = ErrorResponse::new(Some(format!("{}\n", message)));
+    *response.status_mut() = status;
+    (Error::Protocol(message.into()), response)
+}
+
+/// Check that the request is a valid WebSocket upgrade request and return its
+/// `Sec-WebSocket-Key`. Otherwise, return the error along with the response to send to the
+/// client.
+fn check_request<B>(
+    request: &HttpRequest<B>,
+) -> StdResult<&http::HeaderValue, (Error, ErrorResponse)> {
     if request.method() != http::Method::GET {
-        return Err(Error::Protocol("Method is not GET".into()));
+        let (error, mut response) =
+            invalid_request(StatusCode::METHOD_NOT_ALLOWED, "Method is not GET");
+        response
+            .headers_mut()
+            .insert(http::header::ALLOW, http::HeaderValue::from_static("GET"));
+        return Err((error, response));
     }
 
     if request.version() < http::Version::HTTP_11 {
-        return Err(Error::Protocol(
-            "HTTP version should be 1.1 or higher".into(),
+        return Err(invalid_request(
+            StatusCode::BAD_REQUEST,
+            "HTTP version should be 1.1 or higher",
         ));
     }
 
-    if !request
-        .headers()
-        .get("Connection")
-        .and_then(|h| h.to_str().ok())
-        .map(|h| {
-            h.split(|c| c == ' ' || c == ',')
-                .any(|p| p.eq_ignore_ascii_case("Upgrade"))
-        })
-        .unwrap_or(false)
-    {
-        return Err(Error::Protocol(
-            "No \"Connection: upgrade\" in client request".into(),
+    if !contains_token(request.headers(), "Connection", "Upgrade") {
+        return Err(invalid_request(
+            StatusCode::BAD_REQUEST,
+            "No \"Connection: upgrade\" in client request",
         ));
     }
 
-    if !request
-        .headers()
-        .get("Upgrade")
-        .and_then(|h| h.to_str().ok())
-        .map(|h| h.eq_ignore_ascii_case("websocket"))
-        .unwrap_or(false)
-    {
-        return Err(Error::Protocol(
-            "No \"Upgrade: websocket\" in client request".into(),
+    if !is_upgrade_request(request) {
+        return Err(invalid_request(
+            StatusCode::BAD_REQUEST,
+            "No \"Upgrade: websocket\" in client request",
         ));
     }
 
-    if !request
-        .headers()
-        .get("Sec-WebSocket-Version")
-        .map(|h| h == "13")
-        .unwrap_or(false)
-    {
-        return Err(Error::Protocol(
-            "No \"Sec-WebSocket-Version: 13\" in client request".into(),
-        ));
+    match request.headers().get("Sec-WebSocket-Version") {
+        None => {
+            return Err(invalid_request(
+                StatusCode::BAD_REQUEST,
+                "No \"Sec-WebSocket-Version: 13\" in client request",
+            ))
+        }
+        Some(version) if version == "13" => {}
+        Some(_) => {
+            // Tell the client which version we support (RFC 6455, section 4.4).
+            let (_, mut response) = invalid_request(
+                StatusCode::UPGRADE_REQUIRED,
+                "Unsupported Sec-WebSocket-Version, only 13 is supported",
+            );
+            let headers = response.headers_mut();
+            headers.insert(
+                "Sec-WebSocket-Version",
+                http::HeaderValue::from_static("13"),
+            );
+            headers.insert(
+                http::header::UPGRADE,
+                http::HeaderValue::from_static("websocket"),
+            );
+            return Err((Error::UnsupportedVersion(vec![13]), response));
+        }
     }
 
-    let key = request
+    request
         .headers()
         .get("Sec-WebSocket-Key")
-        .ok_or_else(|| Error::Protocol("Missing Sec-WebSocket-Key".into()))?;
-
-    let builder = Response::builder()
-        .status(StatusCode::SWITCHING_PROTOCOLS)
-        .version(request.version())
-        .header("Connection", "Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Sec-WebSocket-Accept", convert_key(key.as_bytes())?);
-
-    Ok(builder.body(())?)
+        .ok_or_else(|| invalid_request(StatusCode::BAD_REQUEST, "Missing Sec-WebSocket-Key"))
 }
 
 // Assumes that this is a valid response
@@ -109,7 +148,11 @@ fn write_response<T>(w: &mut dyn io::Write, response: &HttpResponse<T>) -> Resul
 
 impl TryParse for Request {
     fn try_parse(buf: &[u8]) -> Result<Option<(usize, Self)>> {
-        let mut hbuffer = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        Self::try_parse_limited(buf, MAX_HEADERS)
+    }
+
+    fn try_parse_limited(buf: &[u8], max_headers: usize) -> Result<Option<(usize, Self)>> {
+        let mut hbuffer = vec![httparse::EMPTY_HEADER; max_headers];
         let mut req = httparse::Request::new(&mut hbuffer);
         Ok(match req.parse(buf)? {
             Status::Partial => None,
@@ -120,25 +163,23 @@ impl TryParse for Request {
 
 impl<'h, 'b: 'h> FromHttparse<httparse::Request<'h, 'b>> for Request {
     fn from_httparse(raw: httparse::Request<'h, 'b>) -> Result<Self> {
-        if raw.method.expect("Bug: no method in header") != "GET" {
-            return Err(Error::Protocol("Method is not GET".into()));
-        }
-
-        if raw.version.expect("Bug: no HTTP version") < /*1.*/1 {
-            return Err(Error::Protocol(
-                "HTTP version should be 1.1 or higher".into(),
-            ));
-        }
+        // The method and the version are checked once the request is complete, so that the
+        // client can be answered with a proper error response.
+        let method = raw.method.expect("Bug: no method in header");
+        let method = http::Method::from_bytes(method.as_bytes())
+            .map_err(|_| Error::Protocol("Invalid HTTP method".into()))?;
 
         let headers = HeaderMap::from_httparse(raw.headers)?;
 
         let mut request = Request::new(());
-        *request.method_mut() = http::Method::GET;
+        *request.method_mut() = method;
         *request.headers_mut() = headers;
         *request.uri_mut() = raw.path.expect("Bug: no path in header").parse()?;
-        // TODO: httparse only supports HTTP 0.9/1.0/1.1 but not HTTP 2.0
-        // so the only valid value we could get in the response would be 1.1.
-        *request.version_mut() = http::Version::HTTP_11;
+        // httparse only supports HTTP/1.0 and HTTP/1.1.
+        *request.version_mut() = match raw.version.expect("Bug: no HTTP version") {
+            0 => http::Version::HTTP_10,
+            _ => http::Version::HTTP_11,
+        };
 
         Ok(request)
     }
@@ -154,6 +195,10 @@ pub trait Callback: Sized {
     /// Called whenever the server read the request from the client and is ready to reply to it.
     /// May return additional reply headers.
     /// Returning an error resulting in rejecting the incoming connection.
+    ///
+    /// The error response, e.g. 403 with a JSON body or 429 with `Retry-After`, is sent to the
+    /// client as it is, with `Content-Length` and `Connection: close` added unless already set,
+    /// and the handshake then fails with `Error::Http`.
     fn on_request(
         self,
         request: &Request,
@@ -188,6 +233,164 @@ impl Callback for NoCallback {
     }
 }
 
+/// Extra headers to add to the response, e.g. `Server` or `Set-Cookie`.
+///
+/// Headers already in the response are kept; the extra ones are appended after them.
+impl Callback for HeaderMap {
+    fn on_request(
+        self,
+        _request: &Request,
+        mut response: Response,
+    ) -> StdResult<Response, ErrorResponse> {
+        let headers = response.headers_mut();
+        let mut name = None;
+        for (key, value) in self {
+            // Consecutive values of the same header only carry the name once.
+            if key.is_some() {
+                name = key;
+            }
+            if let Some(ref name) = name {
+                headers.append(name, value);
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// A rule of an `OriginPolicy`.
+#[derive(Clone)]
+enum OriginRule {
+    /// An exact origin, in lower case.
+    Exact(String),
+    /// Any subdomain of a host, with the given scheme and suffix, in lower case.
+    Subdomains { scheme: String, suffix: String },
+    /// A custom check.
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+/// The origins a server accepts WebSocket connections from, protecting against cross-site
+/// WebSocket hijacking.
+///
+/// Browsers send the origin of the page opening a WebSocket in the `Origin` header, so a
+/// server relying on cookies for authentication must check it. The policy is a `Callback`
+/// rejecting requests from other origins with `403 Forbidden`; pass it to `accept_hdr`, or
+/// call `check` from your own callback.
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use tungstenite::accept_hdr;
+/// use tungstenite::handshake::server::OriginPolicy;
+///
+/// let policy = OriginPolicy::new()
+///     .allow("https://example.com")
+///     .allow("https://*.example.com");
+/// let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+/// for stream in server.incoming() {
+///     let websocket = accept_hdr(stream.unwrap(), policy.clone());
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct OriginPolicy {
+    rules: Vec<OriginRule>,
+    allow_missing: bool,
+}
+
+impl OriginPolicy {
+    /// Create a policy allowing no origin at all.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow an origin, e.g. `https://example.com` or `http://localhost:8080`. A host starting
+    /// with `*.`, as in `https://*.example.com`, allows all subdomains of the rest, but not
+    /// the rest itself. Origins are compared case-insensitively.
+    pub fn allow<O: AsRef<str>>(mut self, origin: O) -> Self {
+        let origin = origin.as_ref().to_ascii_lowercase();
+        let rule = match origin.split_once("://*.") {
+            Some((scheme, suffix)) => OriginRule::Subdomains {
+                scheme: scheme.to_owned(),
+                suffix: suffix.to_owned(),
+            },
+            None => OriginRule::Exact(origin),
+        };
+        self.rules.push(rule);
+        self
+    }
+
+    /// Allow the origins for which `predicate` returns `true`. The origin is passed in lower case.
+    pub fn allow_fn<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.rules.push(OriginRule::Predicate(Arc::new(predicate)));
+        self
+    }
+
+    /// Allow requests without an `Origin` header, which non-browser clients usually do not
+    /// send. The default value is `false`.
+    pub fn allow_missing(mut self, allow: bool) -> Self {
+        self.allow_missing = allow;
+        self
+    }
+
+    /// Tell whether the given origin, if any, is allowed.
+    pub fn is_allowed(&self, origin: Option<&str>) -> bool {
+        let origin = match origin {
+            Some(origin) => origin.to_ascii_lowercase(),
+            None => return self.allow_missing,
+        };
+        self.rules.iter().any(|rule| match rule {
+            OriginRule::Exact(allowed) => *allowed == origin,
+            OriginRule::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .map(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.'))
+                .unwrap_or(false),
+            OriginRule::Predicate(predicate) => predicate(&origin),
+        })
+    }
+
+    /// Check the `Origin` header of a request, returning a `403 Forbidden` response to reject
+    /// it with if the origin is not allowed.
+    pub fn check(&self, request: &Request) -> StdResult<(), ErrorResponse> {
+        let origin = request.headers().get("Origin").map(|h| h.to_str());
+        let allowed = match origin {
+            Some(Ok(origin)) => self.is_allowed(Some(origin)),
+            Some(Err(_)) => false,
+            None => self.is_allowed(None),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            debug!("Rejecting a request from origin {:?}", origin);
+            let mut response = ErrorResponse::new(Some("Origin not allowed".into()));
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            Err(response)
+        }
+    }
+}
+
+impl fmt::Debug for OriginPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OriginPolicy")
+            .field("rules", &self.rules.len())
+            .field("allow_missing", &self.allow_missing)
+            .finish()
+    }
+}
+
+impl Callback for OriginPolicy {
+    fn on_request(
+        self,
+        request: &Request,
+        response: Response,
+    ) -> StdResult<Response, ErrorResponse> {
+        self.check(request)?;
+        Ok(response)
+    }
+}
+
 /// Server handshake role.
 #[allow(missing_copy_implementations)]
 #[derive(Debug)]
@@ -201,12 +404,102 @@ where
     callback: Option<C>,
     /// WebSocket configuration.
     config: Option<Option<WebSocketConfig<Ext>>>,
-    /// Error code/flag. If set, an error will be returned after sending response to the client.
-    error_code: Option<u16>,
+    /// If set, this error will be returned after sending the response to the client.
+    error: Option<Error>,
+    /// Subprotocols supported by the server.
+    protocols: Vec<String>,
+    /// The subprotocol selected for the connection.
+    protocol: Option<String>,
+    /// The request received from the client.
+    request: Option<Request>,
+    /// A request read before the handshake started, processed in its first round.
+    parsed: Option<Request>,
+    /// Handler for requests which are not WebSocket upgrade requests.
+    fallback: Option<Fallback>,
+    /// Data already read from the stream past the request.
+    leftover: Vec<u8>,
+    /// Observer to report the outcome of the handshake to.
+    observer: Option<Observer>,
+    /// When the handshake started.
+    started: Instant,
+    /// The status of the response to the client, once there is one.
+    status: Option<StatusCode>,
+    /// The extensions negotiated, as in the `Sec-WebSocket-Extensions` response header.
+    extensions: Option<String>,
+    /// The address of the client, if known.
+    peer_addr: Option<SocketAddr>,
+    /// Adjustment of the configuration for the request.
+    config_override: Option<ConfigOverride<Ext>>,
     /// Internal stream type.
     _marker: PhantomData<S>,
 }
 
+/// Information about the connection a request arrived on.
+///
+/// The handshake stores this in the extensions of the request before the callback runs, when
+/// any of it is known, e.g. after `MidHandshake::with_peer_addr`:
+///
+/// ```
+/// use tungstenite::handshake::server::{ConnectionInfo, ErrorResponse, Request, Response};
+///
+/// fn callback(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+///     if let Some(info) = request.extensions().get::<ConnectionInfo>() {
+///         println!("Request from {:?}", info.peer_addr);
+///     }
+///     Ok(response)
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The address of the client.
+    pub peer_addr: Option<SocketAddr>,
+}
+
+/// The outcome of a server handshake, as reported to an observer.
+#[derive(Debug)]
+pub struct HandshakeReport<'a> {
+    /// The time from the start of the handshake until it was done or failed.
+    pub duration: Duration,
+    /// The status of the response sent to the client, `None` if the handshake failed before
+    /// a response was made, e.g. because the connection was lost.
+    pub status: Option<StatusCode>,
+    /// The subprotocol selected, if any.
+    pub protocol: Option<&'a str>,
+    /// The extensions negotiated, as in the `Sec-WebSocket-Extensions` response header.
+    pub extensions: Option<&'a str>,
+    /// Why the handshake failed, `None` if it succeeded.
+    pub error: Option<&'a Error>,
+}
+
+/// A function receiving the outcome of a handshake.
+struct Observer(Box<dyn FnOnce(&HandshakeReport) + Send>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer(..)")
+    }
+}
+
+/// A function adjusting the configuration for a request.
+struct ConfigOverride<Ext: WebSocketExtension>(
+    Box<dyn FnOnce(&Request, &mut WebSocketConfig<Ext>) + Send>,
+);
+
+impl<Ext: WebSocketExtension> fmt::Debug for ConfigOverride<Ext> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ConfigOverride(..)")
+    }
+}
+
+/// A function answering plain HTTP requests.
+struct Fallback(Box<dyn FnOnce(&Request) -> ErrorResponse + Send>);
+
+impl fmt::Debug for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Fallback(..)")
+    }
+}
+
 impl<S, C, Ext> ServerHandshake<S, C, Ext>
 where
     S: Read + Write,
@@ -223,18 +516,427 @@ where
         config: Option<WebSocketConfig<Ext>>,
     ) -> MidHandshake<Self> {
         trace!("Server handshake initiated.");
+        let limits = head_limits(&config);
         MidHandshake {
-            machine: HandshakeMachine::start_read(stream),
+            machine: HandshakeMachine::start_read_limited(stream, limits),
             role: ServerHandshake {
                 callback: Some(callback),
                 config: Some(config),
-                error_code: None,
+                error: None,
+                protocols: Vec::new(),
+                protocol: None,
+                request: None,
+                parsed: None,
+                fallback: None,
+                leftover: Vec::new(),
+                observer: None,
+                started: Instant::now(),
+                status: None,
+                extensions: None,
+                peer_addr: None,
+                config_override: None,
+                _marker: PhantomData,
+            },
+        }
+    }
+
+    /// Start server handshake for a request which has already been read and parsed, e.g. by
+    /// an HTTP server framework.
+    ///
+    /// `leftover` holds any data the framework read from the stream past the request; it is
+    /// treated as the start of the WebSocket stream. The request is processed, `callback`
+    /// included, on the first call to `handshake`, so the options set on the returned handshake
+    /// apply to it.
+    pub fn start_from_parts(
+        stream: S,
+        parts: http::request::Parts,
+        leftover: Vec<u8>,
+        callback: C,
+        config: Option<WebSocketConfig<Ext>>,
+    ) -> Result<MidHandshake<Self>> {
+        trace!("Server handshake initiated with a parsed request.");
+        let request = Request::from_parts(parts, ());
+        Self::start_with_request(stream, request, leftover, callback, config)
+    }
+
+    /// Start server handshake for a request which has already been read.
+    fn start_with_request(
+        stream: S,
+        request: Request,
+        leftover: Vec<u8>,
+        callback: C,
+        config: Option<WebSocketConfig<Ext>>,
+    ) -> Result<MidHandshake<Self>> {
+        let role = ServerHandshake {
+            callback: Some(callback),
+            config: Some(config),
+            error: None,
+            protocols: Vec::new(),
+            protocol: None,
+            request: None,
+            parsed: Some(request),
+            fallback: None,
+            leftover,
+            observer: None,
+            started: Instant::now(),
+            status: None,
+            extensions: None,
+            peer_addr: None,
+            config_override: None,
+            _marker: PhantomData,
+        };
+        Ok(MidHandshake {
+            machine: HandshakeMachine::start_idle(stream),
+            role,
+        })
+    }
+}
+
+impl<S, C, Ext> MidHandshake<ServerHandshake<S, C, Ext>>
+where
+    S: Read + Write + SetTimeout,
+    C: Callback,
+    Ext: WebSocketExtension,
+{
+    /// Complete the handshake, giving the client at most `timeout` to do its part.
+    ///
+    /// This fails with an `io::ErrorKind::TimedOut` error if the handshake is not done in
+    /// time, which is reported to the observer like any other failure. The deadline is
+    /// enforced with the read and write timeouts of the stream, which are reset afterwards.
+    pub fn handshake_with_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> StdResult<WebSocket<S, Ext>, HandshakeError<ServerHandshake<S, C, Ext>>> {
+        let deadline = Instant::now() + timeout;
+        self.get_mut().set_deadline(Some(deadline));
+        loop {
+            // Once the deadline has passed, the next round fails the handshake.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining > Duration::from_secs(0) {
+                self.get_mut()
+                    .get_mut()
+                    .set_timeout(Some(remaining))
+                    .map_err(|e| HandshakeError::Failure(e.into()))?;
+            }
+            match self.handshake() {
+                Ok(mut socket) => {
+                    socket
+                        .get_mut()
+                        .set_timeout(None)
+                        .map_err(|e| HandshakeError::Failure(e.into()))?;
+                    return Ok(socket);
+                }
+                // Timeouts show up as WouldBlock on some platforms.
+                Err(HandshakeError::Interrupted(mid)) => self = mid,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<S, C, Ext> MidHandshake<ServerHandshake<S, C, Ext>>
+where
+    S: Read + Write,
+    C: Callback,
+    Ext: WebSocketExtension,
+{
+    /// Declare the subprotocols the server supports.
+    ///
+    /// The first subprotocol offered by the client in its `Sec-WebSocket-Protocol` header that
+    /// is also in `protocols` is selected and echoed in the response, before the callback
+    /// runs. The subprotocol selected in the final response, whether by this or by the
+    /// callback, is available from `WebSocket::protocol` afterwards.
+    pub fn with_protocols<I, P>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.role.protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Serve requests which are not WebSocket upgrade requests with `fallback`.
+    ///
+    /// A request without an `Upgrade: websocket` header, such as a load balancer health check
+    /// or a browser visiting the address, is passed to `fallback` instead of being rejected,
+    /// and the response it returns is sent to the client. The connection is closed afterwards
+    /// and the handshake fails with `Error::HttpFallback`.
+    ///
+    /// ```no_run
+    /// use std::net::TcpListener;
+    /// use tungstenite::extensions::uncompressed::UncompressedExt;
+    /// use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+    ///
+    /// let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+    /// for stream in server.incoming() {
+    ///     let handshake = ServerHandshake::<_, _, UncompressedExt>::start(
+    ///         stream.unwrap(),
+    ///         NoCallback,
+    ///         None,
+    ///     )
+    ///     .with_fallback(|_request| http::Response::new(Some("OK\n".to_owned())));
+    ///     if let Ok(websocket) = handshake.handshake() {
+    ///         // ...
+    ///     }
+    /// }
+    /// ```
+    pub fn with_fallback<F>(mut self, fallback: F) -> Self
+    where
+        F: FnOnce(&Request) -> ErrorResponse + Send + 'static,
+    {
+        self.role.fallback = Some(Fallback(Box::new(fallback)));
+        self
+    }
+
+    /// Report the outcome of the handshake to `observer`, e.g. to export handshake success
+    /// and failure rates.
+    ///
+    /// The observer is called once, when the handshake is done or has failed, with how long it
+    /// took, the response status, the subprotocol and extensions selected, and the error the
+    /// handshake failed with, if any. It is not called while the handshake is interrupted.
+    pub fn with_observer<F>(mut self, observer: F) -> Self
+    where
+        F: FnOnce(&HandshakeReport) + Send + 'static,
+    {
+        self.role.observer = Some(Observer(Box::new(observer)));
+        self
+    }
+
+    /// Adjust the configuration of the connection to the request, e.g. to allow larger
+    /// messages for some clients or to turn compression off for others.
+    ///
+    /// `config_override` is called with a valid upgrade request and the configuration the
+    /// handshake was started with, or the default one if there was none, before the
+    /// extensions are negotiated and before the callback runs. The configuration it leaves is
+    /// used for the response and the WebSocket. The limits on the size of the request have
+    /// been applied already by then, so changing them has no effect.
+    pub fn with_config_override<F>(mut self, config_override: F) -> Self
+    where
+        F: FnOnce(&Request, &mut WebSocketConfig<Ext>) + Send + 'static,
+    {
+        self.role.config_override = Some(ConfigOverride(Box::new(config_override)));
+        self
+    }
+
+    /// Tell the handshake the address of the client, for the callback to find in the
+    /// `ConnectionInfo` of the request.
+    ///
+    /// For streams implementing `PeerAddr`, such as `TcpStream`,
+    /// `server::accept_hdr_with_peer_addr` does this.
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.role.peer_addr = Some(addr);
+        self
+    }
+}
+
+/// The first phase of a server handshake done in two phases: reading the request.
+///
+/// This reads and parses the request, and finishes with a `PendingHandshake` which holds the
+/// stream and the request until the server decides, e.g. after looking up the credentials
+/// of the client in its own time, to accept or reject it.
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use tungstenite::extensions::uncompressed::UncompressedExt;
+/// use tungstenite::handshake::server::{NoCallback, RequestReader};
+///
+/// let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+/// for stream in server.incoming() {
+///     let pending = RequestReader::start(stream.unwrap()).handshake().unwrap();
+///     let authorized = pending.request().headers().contains_key("Authorization");
+///     if authorized {
+///         let websocket = pending
+///             .accept::<_, UncompressedExt>(NoCallback, None)
+///             .unwrap()
+///             .handshake()
+///             .unwrap();
+///         // ...
+///     } else {
+///         let _ = pending
+///             .reject(http::Response::builder().status(401).body(None).unwrap())
+///             .unwrap()
+///             .handshake();
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RequestReader<S> {
+    error: Option<Error>,
+    _marker: PhantomData<S>,
+}
+
+impl<S: Read + Write> RequestReader<S> {
+    /// Start reading the request with the default limits on its size.
+    pub fn start(stream: S) -> MidHandshake<Self> {
+        Self::start_with_config::<UncompressedExt>(stream, &None)
+    }
+
+    /// Start reading the request with the limits on its size from `config`.
+    pub fn start_with_config<Ext: WebSocketExtension>(
+        stream: S,
+        config: &Option<WebSocketConfig<Ext>>,
+    ) -> MidHandshake<Self> {
+        trace!("Server handshake initiated, reading the request.");
+        MidHandshake {
+            machine: HandshakeMachine::start_read_limited(stream, head_limits(config)),
+            role: RequestReader {
+                error: None,
                 _marker: PhantomData,
             },
         }
     }
 }
 
+/// A server handshake of which the request has been read, waiting to be accepted or rejected.
+#[derive(Debug)]
+pub struct PendingHandshake<S> {
+    stream: S,
+    request: Request,
+}
+
+impl<S: Read + Write> PendingHandshake<S> {
+    /// The request of the client.
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    /// Returns a shared reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Respond to the request, running `callback` on it like `ServerHandshake` does.
+    ///
+    /// A request which is not a valid WebSocket upgrade request is still rejected with the
+    /// right response.
+    pub fn accept<C, Ext>(
+        self,
+        callback: C,
+        config: Option<WebSocketConfig<Ext>>,
+    ) -> Result<MidHandshake<ServerHandshake<S, C, Ext>>>
+    where
+        C: Callback,
+        Ext: WebSocketExtension,
+    {
+        ServerHandshake::start_with_request(self.stream, self.request, Vec::new(), callback, config)
+    }
+
+    /// Reject the request with `response`, which must not be successful.
+    ///
+    /// The handshake fails with `Error::Http` once the response has been sent.
+    pub fn reject(
+        self,
+        response: ErrorResponse,
+    ) -> Result<MidHandshake<ServerHandshake<S, impl Callback, UncompressedExt>>> {
+        self.accept(move |_: &Request, _| Err(response), None)
+    }
+}
+
+/// Check whether the request asks for a WebSocket upgrade at all.
+fn is_upgrade_request<B>(request: &HttpRequest<B>) -> bool {
+    contains_token(request.headers(), "Upgrade", "websocket")
+}
+
+/// Serialize a final response to the client.
+fn serialize_rejection(mut response: ErrorResponse) -> Result<Vec<u8>> {
+    // The connection is closed after the response, tell the client so and where the body ends
+    // unless the callback did.
+    let body_size = response.body().as_ref().map(String::len).unwrap_or(0);
+    let headers = response.headers_mut();
+    headers
+        .entry(http::header::CONTENT_LENGTH)
+        .or_insert_with(|| body_size.into());
+    headers
+        .entry(http::header::CONNECTION)
+        .or_insert_with(|| http::HeaderValue::from_static("close"));
+
+    let mut output = vec![];
+    write_response(&mut output, &response)?;
+    if let Some(body) = response.body() {
+        output.extend_from_slice(body.as_bytes());
+    }
+    Ok(output)
+}
+
+/// Build the response to a request going over `limit`.
+fn limit_response(limit: Limit, error: &Error) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(format!("{}\n", error)));
+    *response.status_mut() = match limit {
+        Limit::StartLine => StatusCode::URI_TOO_LONG,
+        Limit::Head => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+    };
+    response
+}
+
+/// The limits on the request head for the configuration.
+fn head_limits<Ext: WebSocketExtension>(config: &Option<WebSocketConfig<Ext>>) -> HeadLimits {
+    match config {
+        Some(ref config) => HeadLimits {
+            max_headers: config.max_headers,
+            max_size: config.max_head_size,
+            max_start_line: config.max_request_line,
+            max_header_line: config.max_header_line,
+        },
+        None => HeadLimits {
+            max_headers: MAX_HEADERS,
+            max_size: Some(MAX_HEAD_SIZE),
+            max_start_line: Some(MAX_LINE_SIZE),
+            max_header_line: Some(MAX_LINE_SIZE),
+        },
+    }
+}
+
+/// Pick the first subprotocol offered in the request that is also in `supported`.
+fn select_protocol<'a>(request: &Request, supported: &'a [String]) -> Option<&'a String> {
+    request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .find_map(|offered| supported.iter().find(|s| *s == offered.trim()))
+}
+
+impl<S, C, Ext> ServerHandshake<S, C, Ext>
+where
+    S: Read + Write,
+    C: Callback,
+    Ext: WebSocketExtension,
+{
+    /// Send a final response to the client, failing the handshake with `error` afterwards.
+    fn reject(
+        &mut self,
+        stream: S,
+        response: ErrorResponse,
+        error: Error,
+    ) -> Result<ProcessingResult<S, WebSocket<S, Ext>>> {
+        self.error = Some(error);
+        self.status = Some(response.status());
+        let output = serialize_rejection(response)?;
+        Ok(ProcessingResult::Continue(HandshakeMachine::start_write(
+            stream, output,
+        )))
+    }
+
+    /// Report the outcome of the handshake to the observer, if any.
+    fn report(&mut self, error: Option<&Error>) {
+        if let Some(Observer(observer)) = self.observer.take() {
+            observer(&HandshakeReport {
+                duration: self.started.elapsed(),
+                status: self.status,
+                protocol: self.protocol.as_deref(),
+                extensions: self.extensions.as_deref(),
+                error,
+            });
+        }
+    }
+}
+
 impl<S, C, Ext> HandshakeRole for ServerHandshake<S, C, Ext>
 where
     S: Read + Write,
@@ -245,6 +947,10 @@ where
     type InternalStream = S;
     type FinalResult = WebSocket<S, Ext>;
 
+    fn on_failure(&mut self, error: &Error) {
+        self.report(Some(error));
+    }
+
     fn stage_finished(
         &mut self,
         finish: StageResult<Self::IncomingData, Self::InternalStream>,
@@ -252,14 +958,36 @@ where
         Ok(match finish {
             StageResult::DoneReading {
                 stream,
-                result: request,
+                result: mut request,
                 tail,
             } => {
+                if let Some(addr) = self.peer_addr {
+                    request.extensions_mut().insert(ConnectionInfo {
+                        peer_addr: Some(addr),
+                    });
+                }
+
+                if !is_upgrade_request(&request) {
+                    if let Some(Fallback(fallback)) = self.fallback.take() {
+                        let response = fallback(&request);
+                        let error = Error::HttpFallback(response.status());
+                        return self.reject(stream, response, error);
+                    }
+                }
+
                 if !tail.is_empty() {
                     return Err(Error::Protocol("Junk after client request".into()));
                 }
 
-                let mut response = create_response(&request)?;
+                let mut response = match check_request(&request) {
+                    Ok(_) => create_response(&request)?,
+                    Err((error, response)) => return self.reject(stream, response, error),
+                };
+
+                if let Some(ConfigOverride(config_override)) = self.config_override.take() {
+                    let config = self.config.as_mut().unwrap();
+                    config_override(&request, config.get_or_insert_with(Default::default));
+                }
 
                 if let Some(ref mut config) = self.config.as_mut().unwrap() {
                     if let Err(e) = config.encoder.on_receive_request(&request, &mut response) {
@@ -267,6 +995,12 @@ where
                     }
                 }
 
+                if let Some(protocol) = select_protocol(&request, &self.protocols) {
+                    response
+                        .headers_mut()
+                        .insert("Sec-WebSocket-Protocol", protocol.parse()?);
+                }
+
                 let callback_result = if let Some(callback) = self.callback.take() {
                     callback.on_request(&request, response)
                 } else {
@@ -275,6 +1009,21 @@ where
 
                 match callback_result {
                     Ok(response) => {
+                        self.protocol = response
+                            .headers()
+                            .get("Sec-WebSocket-Protocol")
+                            .and_then(|h| h.to_str().ok())
+                            .map(ToOwned::to_owned);
+                        self.request = Some(request);
+                        self.status = Some(response.status());
+                        // The extensions may have added a header line each.
+                        let extensions: Vec<_> = response
+                            .headers()
+                            .get_all("Sec-WebSocket-Extensions")
+                            .iter()
+                            .filter_map(|h| h.to_str().ok())
+                            .collect();
+                        self.extensions = Some(extensions.join(", ")).filter(|s| !s.is_empty());
                         let mut output = vec![];
                         write_response(&mut output, &response)?;
                         ProcessingResult::Continue(HandshakeMachine::start_write(stream, output))
@@ -286,30 +1035,51 @@ where
                                 "Custom response must not be successful".into(),
                             ));
                         }
-
-                        self.error_code = Some(resp.status().as_u16());
-
-                        let mut output = vec![];
-                        write_response(&mut output, &resp)?;
-                        if let Some(body) = resp.body() {
-                            output.extend_from_slice(body.as_bytes());
-                        }
-                        ProcessingResult::Continue(HandshakeMachine::start_write(stream, output))
+                        let error = Error::Http(resp.status());
+                        return self.reject(stream, resp, error);
                     }
                 }
             }
 
+            StageResult::LimitExceeded {
+                stream,
+                limit,
+                error,
+            } => {
+                let response = limit_response(limit, &error);
+                return self.reject(stream, response, error);
+            }
+
             StageResult::DoneWriting(stream) => {
-                if let Some(err) = self.error_code.take() {
+                if let Some(request) = self.parsed.take() {
+                    let finish = StageResult::DoneReading {
+                        stream,
+                        result: request,
+                        tail: Vec::new(),
+                    };
+                    return self.stage_finished(finish);
+                }
+                if let Some(err) = self.error.take() {
                     debug!("Server handshake failed.");
-                    return Err(Error::Http(StatusCode::from_u16(err)?));
+                    return Err(err);
                 } else {
                     debug!("Server handshake done.");
-                    let websocket = WebSocket::from_raw_socket(
+                    let mut websocket = WebSocket::from_partially_read(
                         stream,
+                        mem::take(&mut self.leftover),
                         Role::Server,
                         self.config.take().unwrap(),
                     );
+                    self.report(None);
+                    websocket.set_protocol(self.protocol.take());
+                    if let Some(ref extensions) = self.extensions {
+                        websocket.set_negotiated_extensions(
+                            header::parse(extensions).unwrap_or_default(),
+                        );
+                    }
+                    if let Some(request) = self.request.take() {
+                        websocket.set_request(request);
+                    }
                     ProcessingResult::Done(websocket)
                 }
             }
@@ -317,11 +1087,592 @@ where
     }
 }
 
+impl<S: Read + Write> HandshakeRole for RequestReader<S> {
+    type IncomingData = Request;
+    type InternalStream = S;
+    type FinalResult = PendingHandshake<S>;
+
+    fn stage_finished(
+        &mut self,
+        finish: StageResult<Self::IncomingData, Self::InternalStream>,
+    ) -> Result<ProcessingResult<Self::InternalStream, Self::FinalResult>> {
+        Ok(match finish {
+            StageResult::DoneReading {
+                stream,
+                result: request,
+                tail,
+            } => {
+                if !tail.is_empty() {
+                    return Err(Error::Protocol("Junk after client request".into()));
+                }
+                debug!("Server handshake request read.");
+                ProcessingResult::Done(PendingHandshake { stream, request })
+            }
+
+            StageResult::LimitExceeded {
+                stream,
+                limit,
+                error,
+            } => {
+                let output = serialize_rejection(limit_response(limit, &error))?;
+                self.error = Some(error);
+                ProcessingResult::Continue(HandshakeMachine::start_write(stream, output))
+            }
+
+            StageResult::DoneWriting(_) => {
+                debug!("Server handshake failed.");
+                return Err(self
+                    .error
+                    .take()
+                    .expect("Bug: response written without an error"));
+            }
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::machine::TryParse;
     use super::create_response;
-    use super::Request;
+    use super::{
+        ConnectionInfo, NoCallback, OriginPolicy, Request, RequestReader, Response, ServerHandshake,
+    };
+    use crate::error::Error;
+    use crate::extensions::uncompressed::UncompressedExt;
+    use crate::handshake::HandshakeError;
+    use crate::protocol::{Message, WebSocketConfig};
+    use std::io::{self, Cursor, Read, Write};
+    use std::sync::mpsc;
+
+    /// A stream reading from one buffer and writing to another.
+    struct ReadWriteMoc {
+        incoming: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl ReadWriteMoc {
+        fn new(incoming: &[u8]) -> Self {
+            ReadWriteMoc {
+                incoming: Cursor::new(incoming.to_vec()),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for ReadWriteMoc {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for ReadWriteMoc {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A stream with an upgrade request for `/script.ws` to read, with extra header lines.
+    fn request(headers: &[&str]) -> ReadWriteMoc {
+        let mut data = String::from(
+            "GET /script.ws HTTP/1.1\r\n\
+             Host: foo.com\r\n\
+             Connection: upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        );
+        for header in headers {
+            data.push_str(header);
+            data.push_str("\r\n");
+        }
+        data.push_str("\r\n");
+        ReadWriteMoc::new(data.as_bytes())
+    }
+
+    #[test]
+    fn protocol_selection() {
+        let protocols = [
+            "Sec-WebSocket-Protocol: chat, mqtt",
+            "Sec-WebSocket-Protocol: graphql-ws",
+        ];
+        let stream = request(&protocols);
+        let socket = ServerHandshake::<_, _, UncompressedExt>::start(stream, NoCallback, None)
+            .with_protocols(vec!["graphql-ws", "mqtt"])
+            .handshake()
+            .unwrap();
+        assert_eq!(socket.protocol(), Some("mqtt"));
+        let parsed = socket.request().unwrap();
+        assert_eq!(parsed.uri().path(), "/script.ws");
+        assert_eq!(parsed.headers()["Host"], "foo.com");
+        let response = String::from_utf8(socket.get_ref().written.clone()).unwrap();
+        assert!(response.contains("\r\nsec-websocket-protocol: mqtt\r\n"));
+
+        let stream = request(&protocols);
+        let socket = ServerHandshake::<_, _, UncompressedExt>::start(stream, NoCallback, None)
+            .with_protocols(vec!["wamp"])
+            .handshake()
+            .unwrap();
+        assert_eq!(socket.protocol(), None);
+    }
+
+    #[test]
+    fn origin_policy() {
+        let policy = OriginPolicy::new()
+            .allow("https://Example.com")
+            .allow("https://*.example.com")
+            .allow_fn(|origin| origin.ends_with(".test:8080"));
+        assert!(policy.is_allowed(Some("https://example.com")));
+        assert!(policy.is_allowed(Some("https://api.EXAMPLE.com")));
+        assert!(policy.is_allowed(Some("https://a.b.example.com")));
+        assert!(policy.is_allowed(Some("http://app.test:8080")));
+        assert!(!policy.is_allowed(Some("http://example.com")));
+        assert!(!policy.is_allowed(Some("https://evilexample.com")));
+        assert!(!policy.is_allowed(Some("https://.example.com")));
+        assert!(!policy.is_allowed(Some("https://example.com.evil.org")));
+        assert!(!policy.is_allowed(None));
+        assert!(policy.clone().allow_missing(true).is_allowed(None));
+
+        let request = Request::builder()
+            .header("Origin", "https://evil.org")
+            .body(())
+            .unwrap();
+        assert_eq!(policy.check(&request).unwrap_err().status(), 403);
+    }
+
+    #[test]
+    fn custom_rejection() {
+        let mut stream = request(&[]);
+        #[allow(clippy::result_large_err)]
+        let callback = |_: &Request, _: Response| {
+            Err(http::Response::builder()
+                .status(429)
+                .header("Retry-After", "30")
+                .header("Content-Type", "application/json")
+                .body(Some(r#"{"error":"slow down"}"#.to_owned()))
+                .unwrap())
+        };
+        let result = ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, callback, None)
+            .handshake();
+        match result {
+            Err(HandshakeError::Failure(Error::Http(status))) => assert_eq!(status, 429),
+            _ => panic!("Expected the handshake to fail"),
+        }
+        assert_eq!(
+            String::from_utf8(stream.written).unwrap(),
+            "HTTP/1.1 429 Too Many Requests\r\n\
+             retry-after: 30\r\n\
+             content-type: application/json\r\n\
+             content-length: 21\r\n\
+             connection: close\r\n\
+             \r\n\
+             {\"error\":\"slow down\"}"
+        );
+    }
+
+    #[test]
+    fn peer_addr() {
+        let addr = "192.168.0.1:56324".parse().unwrap();
+        let mut stream = request(&[]);
+        let callback = |request: &Request, response: Response| {
+            assert_eq!(
+                request.extensions().get::<ConnectionInfo>(),
+                Some(&ConnectionInfo {
+                    peer_addr: Some(addr)
+                })
+            );
+            Ok(response)
+        };
+        let socket = ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, callback, None)
+            .with_peer_addr(addr)
+            .handshake()
+            .unwrap();
+        let info = socket
+            .request()
+            .unwrap()
+            .extensions()
+            .get::<ConnectionInfo>();
+        assert_eq!(info.unwrap().peer_addr, Some(addr));
+
+        let mut stream = request(&[]);
+        let socket = ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, NoCallback, None)
+            .handshake()
+            .unwrap();
+        assert!(socket
+            .request()
+            .unwrap()
+            .extensions()
+            .get::<ConnectionInfo>()
+            .is_none());
+    }
+
+    #[test]
+    fn config_override() {
+        let mut stream = request(&["Authorization: Bearer admin"]);
+        let config = WebSocketConfig::<UncompressedExt> {
+            max_frame_size: Some(1024),
+            ..Default::default()
+        };
+        let socket = ServerHandshake::start(&mut stream, NoCallback, Some(config))
+            .with_config_override(|request, config| {
+                if request.headers().contains_key("Authorization") {
+                    config.max_frame_size = None;
+                }
+            })
+            .handshake()
+            .unwrap();
+        assert_eq!(socket.get_config().max_frame_size, None);
+
+        // Without a configuration, the default one is adjusted.
+        let mut stream = request(&[]);
+        let socket = ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, NoCallback, None)
+            .with_config_override(|_, config| config.max_fragments = Some(8))
+            .handshake()
+            .unwrap();
+        assert_eq!(socket.get_config().max_fragments, Some(8));
+        assert_eq!(socket.get_config().max_frame_size, Some(16 << 20));
+    }
+
+    #[test]
+    fn two_phase() {
+        let mut stream = request(&[]);
+        let pending = RequestReader::start(&mut stream).handshake().unwrap();
+        assert_eq!(pending.request().uri().path(), "/script.ws");
+        assert!(pending.get_ref().written.is_empty());
+        let socket = pending
+            .accept::<_, UncompressedExt>(NoCallback, None)
+            .unwrap()
+            .handshake()
+            .unwrap();
+        assert_eq!(socket.request().unwrap().uri().path(), "/script.ws");
+        drop(socket);
+        assert_eq!(
+            String::from_utf8(stream.written).unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             connection: Upgrade\r\n\
+             upgrade: websocket\r\n\
+             sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+             \r\n"
+        );
+
+        let mut stream = request(&[]);
+        let pending = RequestReader::start(&mut stream).handshake().unwrap();
+        let response = http::Response::builder()
+            .status(401)
+            .body(Some("Unauthorized\n".to_owned()))
+            .unwrap();
+        match pending.reject(response).unwrap().handshake() {
+            Err(HandshakeError::Failure(Error::Http(status))) => assert_eq!(status, 401),
+            _ => panic!("Expected the handshake to fail"),
+        }
+        assert_eq!(
+            String::from_utf8(stream.written).unwrap(),
+            "HTTP/1.1 401 Unauthorized\r\n\
+             content-length: 13\r\n\
+             connection: close\r\n\
+             \r\n\
+             Unauthorized\n"
+        );
+
+        let mut stream = request(&[]);
+        let config = WebSocketConfig::<UncompressedExt> {
+            max_request_line: Some(16),
+            ..Default::default()
+        };
+        match RequestReader::start_with_config(&mut stream, &Some(config)).handshake() {
+            Err(HandshakeError::Failure(Error::Capacity(_))) => {}
+            _ => panic!("Expected the request line to be too long"),
+        }
+        assert!(String::from_utf8(stream.written)
+            .unwrap()
+            .starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    }
+
+    #[test]
+    fn observer() {
+        fn observe(mut stream: ReadWriteMoc) -> (Option<u16>, Option<String>, Option<String>) {
+            let (tx, rx) = mpsc::channel();
+            let _ = ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, NoCallback, None)
+                .with_protocols(vec!["chat"])
+                .with_observer(move |report| {
+                    tx.send((
+                        report.status.map(|s| s.as_u16()),
+                        report.protocol.map(ToOwned::to_owned),
+                        report.error.map(ToString::to_string),
+                    ))
+                    .unwrap()
+                })
+                .handshake();
+            rx.try_recv().expect("Observer not called")
+        }
+
+        assert_eq!(
+            observe(request(&["Sec-WebSocket-Protocol: chat"])),
+            (Some(101), Some("chat".to_owned()), None)
+        );
+        assert_eq!(
+            observe(ReadWriteMoc::new(
+                b"POST /script.ws HTTP/1.1\r\n\
+                  Host: foo.com\r\n\
+                  \r\n"
+            )),
+            (
+                Some(405),
+                None,
+                Some("WebSocket protocol error: Method is not GET".to_owned())
+            )
+        );
+        assert_eq!(
+            observe(ReadWriteMoc::new(b"GET /script.ws HTTP/1.1\r\n")),
+            (
+                None,
+                None,
+                Some("WebSocket protocol error: Handshake not finished".to_owned())
+            )
+        );
+    }
+
+    #[test]
+    fn extra_headers() {
+        let mut stream = request(&[]);
+        let mut headers = http::HeaderMap::new();
+        headers.insert("Server", "example/1.0".parse().unwrap());
+        headers.append("Set-Cookie", "a=1".parse().unwrap());
+        headers.append("Set-Cookie", "b=2".parse().unwrap());
+        ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, headers, None)
+            .handshake()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(stream.written).unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             connection: Upgrade\r\n\
+             upgrade: websocket\r\n\
+             sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+             server: example/1.0\r\n\
+             set-cookie: a=1\r\n\
+             set-cookie: b=2\r\n\
+             \r\n"
+        );
+    }
+
+    #[test]
+    fn parsed_request() {
+        let (parts, _) = http::Request::builder()
+            .uri("/script.ws")
+            .header("Host", "foo.com")
+            .header("Connection", "upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap()
+            .into_parts();
+        // A masked "Hello" text frame from RFC 6455, section 5.7.
+        let leftover = vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let mut stream = ReadWriteMoc::new(b"");
+        let mut socket = ServerHandshake::<_, _, UncompressedExt>::start_from_parts(
+            &mut stream,
+            parts,
+            leftover,
+            NoCallback,
+            None,
+        )
+        .unwrap()
+        .handshake()
+        .unwrap();
+        assert_eq!(socket.request().unwrap().uri().path(), "/script.ws");
+        assert_eq!(socket.read_message().unwrap(), Message::text("Hello"));
+        drop(socket);
+        assert_eq!(
+            String::from_utf8(stream.written).unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             connection: Upgrade\r\n\
+             upgrade: websocket\r\n\
+             sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+             \r\n"
+        );
+    }
+
+    #[test]
+    fn parsed_request_with_protocols() {
+        // Options set after starting apply, as the request is processed by `handshake`.
+        let (parts, _) = http::Request::builder()
+            .header("Host", "foo.com")
+            .header("Connection", "upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Protocol", "chat, superchat")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let mut stream = ReadWriteMoc::new(b"");
+        let socket = ServerHandshake::<_, _, UncompressedExt>::start_from_parts(
+            &mut stream,
+            parts,
+            Vec::new(),
+            NoCallback,
+            None,
+        )
+        .unwrap()
+        .with_protocols(vec!["superchat"])
+        .handshake()
+        .unwrap();
+        assert_eq!(socket.protocol(), Some("superchat"));
+    }
+
+    #[test]
+    fn fallback() {
+        const DATA: &[u8] = b"\
+            GET /health HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            \r\n";
+        let mut stream = ReadWriteMoc::new(DATA);
+        let result = ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, NoCallback, None)
+            .with_fallback(|request| {
+                assert_eq!(request.uri().path(), "/health");
+                http::Response::new(Some("OK\n".to_owned()))
+            })
+            .handshake();
+        match result {
+            Err(HandshakeError::Failure(Error::HttpFallback(status))) => assert_eq!(status, 200),
+            _ => panic!("Expected the request to be served by the fallback"),
+        }
+        assert_eq!(
+            String::from_utf8(stream.written).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             content-length: 3\r\n\
+             connection: close\r\n\
+             \r\n\
+             OK\n"
+        );
+    }
+
+    #[test]
+    fn header_limits() {
+        fn reject(mut stream: ReadWriteMoc, config: WebSocketConfig) -> String {
+            let result = ServerHandshake::start(&mut stream, NoCallback, Some(config)).handshake();
+            match result {
+                Err(HandshakeError::Failure(Error::Capacity(_))) => {}
+                _ => panic!("Expected the handshake to fail"),
+            }
+            String::from_utf8(stream.written).unwrap()
+        }
+
+        let few_headers = WebSocketConfig {
+            max_headers: 4,
+            ..WebSocketConfig::default()
+        };
+        assert!(reject(request(&[]), few_headers)
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        // The head is never finished, it is rejected as soon as it is too long.
+        const ENDLESS: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        let short_head = || WebSocketConfig {
+            max_head_size: Some(64),
+            ..WebSocketConfig::default()
+        };
+        assert_eq!(
+            reject(ReadWriteMoc::new(ENDLESS), short_head()),
+            "HTTP/1.1 431 Request Header Fields Too Large\r\n\
+             content-length: 50\r\n\
+             connection: close\r\n\
+             \r\n\
+             Space limit exceeded: Header longer than 64 bytes\n"
+        );
+        // A complete head is rejected too, even if it arrives at once.
+        assert!(reject(request(&[]), short_head())
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let short_lines = || WebSocketConfig {
+            max_request_line: Some(32),
+            max_header_line: Some(32),
+            ..WebSocketConfig::default()
+        };
+        const LONG_URI: &[u8] = b"GET /aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        assert!(reject(ReadWriteMoc::new(LONG_URI), short_lines())
+            .starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+        assert!(reject(ReadWriteMoc::new(ENDLESS), short_lines())
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        // Within the default limits.
+        let mut stream = request(&[]);
+        ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, NoCallback, None)
+            .handshake()
+            .unwrap();
+    }
+
+    #[test]
+    fn invalid_requests() {
+        fn reject(data: &[u8]) -> String {
+            let mut stream = ReadWriteMoc::new(data);
+            let result =
+                ServerHandshake::<_, _, UncompressedExt>::start(&mut stream, NoCallback, None)
+                    .handshake();
+            match result {
+                Err(HandshakeError::Failure(Error::Protocol(_))) => {}
+                Err(HandshakeError::Failure(Error::UnsupportedVersion(versions))) => {
+                    assert_eq!(versions, vec![13])
+                }
+                _ => panic!("Expected the handshake to fail"),
+            }
+            String::from_utf8(stream.written).unwrap()
+        }
+
+        assert_eq!(
+            reject(
+                b"POST /script.ws HTTP/1.1\r\n\
+                  Host: foo.com\r\n\
+                  \r\n"
+            ),
+            "HTTP/1.1 405 Method Not Allowed\r\n\
+             allow: GET\r\n\
+             content-length: 18\r\n\
+             connection: close\r\n\
+             \r\n\
+             Method is not GET\n"
+        );
+        assert_eq!(
+            reject(
+                b"GET /script.ws HTTP/1.1\r\n\
+                  Host: foo.com\r\n\
+                  Connection: upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 8\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  \r\n"
+            ),
+            "HTTP/1.1 426 Upgrade Required\r\n\
+             sec-websocket-version: 13\r\n\
+             upgrade: websocket\r\n\
+             content-length: 56\r\n\
+             connection: close\r\n\
+             \r\n\
+             Unsupported Sec-WebSocket-Version, only 13 is supported\n"
+        );
+        assert!(reject(
+            b"GET /script.ws HTTP/1.1\r\n\
+              Host: foo.com\r\n\
+              \r\n"
+        )
+        .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(reject(
+            b"GET /script.ws HTTP/1.1\r\n\
+              Host: foo.com\r\n\
+              Connection: upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              \r\n"
+        )
+        .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
 
     #[test]
     fn request_parsing() {
@@ -331,6 +1682,20 @@ mod tests {
         assert_eq!(req.headers().get("Host").unwrap(), &b"foo.com"[..]);
     }
 
+    #[test]
+    fn header_token_lists() {
+        const DATA: &[u8] = b"\
+            GET /script.ws HTTP/1.1\r\n\
+            Host: foo.com\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Upgrade: WebSocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n";
+        let (_, req) = Request::try_parse(DATA).unwrap().unwrap();
+        assert!(create_response(&req).is_ok());
+    }
+
     #[test]
     fn request_replying() {
         const DATA: &[u8] = b"\
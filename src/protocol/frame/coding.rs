@@ -69,12 +69,12 @@ impl fmt::Display for OpCode {
     }
 }
 
-impl Into<u8> for OpCode {
-    fn into(self) -> u8 {
+impl From<OpCode> for u8 {
+    fn from(code: OpCode) -> u8 {
         use self::Control::{Close, Ping, Pong};
         use self::Data::{Binary, Continue, Text};
         use self::OpCode::*;
-        match self {
+        match code {
             Data(Continue) => 0,
             Data(Text) => 1,
             Data(Binary) => 2,
@@ -184,14 +184,7 @@ pub enum CloseCode {
 impl CloseCode {
     /// Check if this CloseCode is allowed.
     pub fn is_allowed(self) -> bool {
-        match self {
-            Bad(_) => false,
-            Reserved(_) => false,
-            Status => false,
-            Abnormal => false,
-            Tls => false,
-            _ => true,
-        }
+        !matches!(self, Bad(_) | Reserved(_) | Status | Abnormal | Tls)
     }
 }
 
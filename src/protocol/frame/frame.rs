@@ -210,6 +210,12 @@ impl FrameHeader {
 pub struct Frame {
     header: FrameHeader,
     payload: Vec<u8>,
+    /// The masking key the payload was unmasked with.
+    received_mask: Option<[u8; 4]>,
+    /// Whether extensions may compress the payload when the frame is sent.
+    compress: bool,
+    /// The level to compress the payload with, instead of the one of the extension.
+    compression_level: Option<u32>,
 }
 
 impl Frame {
@@ -271,10 +277,49 @@ impl Frame {
     #[inline]
     pub(crate) fn apply_mask(&mut self) {
         if let Some(mask) = self.header.mask.take() {
-            apply_mask(&mut self.payload, mask)
+            apply_mask(&mut self.payload, mask);
+            self.received_mask = Some(mask);
         }
     }
 
+    /// Get the masking key the frame was received with.
+    ///
+    /// Frames received from a client are unmasked before they are handed out, which clears the
+    /// mask in the header. This returns the original 4-byte key in that case and `None` for
+    /// frames that were never masked.
+    #[inline]
+    pub fn received_mask(&self) -> Option<[u8; 4]> {
+        self.received_mask
+    }
+
+    /// Check whether extensions may compress the payload when the frame is sent.
+    ///
+    /// This is true unless the message was written with `WriteOptions::compress` turned off.
+    #[inline]
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    /// Allow or forbid extensions to compress the payload when the frame is sent.
+    #[inline]
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Get the level extensions should compress the payload with, if not their own.
+    ///
+    /// This is the level the message was written with in `WriteOptions::compression_level`.
+    #[inline]
+    pub fn compression_level(&self) -> Option<u32> {
+        self.compression_level
+    }
+
+    /// Set the level extensions should compress the payload with, if not their own.
+    #[inline]
+    pub fn set_compression_level(&mut self, compression_level: Option<u32>) {
+        self.compression_level = compression_level;
+    }
+
     /// Consume the frame into its payload as binary.
     #[inline]
     pub fn into_data(self) -> Vec<u8> {
@@ -310,10 +355,7 @@ impl Frame {
     #[inline]
     pub fn message(data: Vec<u8>, opcode: OpCode, is_final: bool) -> Frame {
         debug_assert!(
-            match opcode {
-                OpCode::Data(_) => true,
-                _ => false,
-            },
+            matches!(opcode, OpCode::Data(_)),
             "Invalid opcode for data frame."
         );
 
@@ -324,6 +366,9 @@ impl Frame {
                 ..FrameHeader::default()
             },
             payload: data,
+            received_mask: None,
+            compress: true,
+            compression_level: None,
         }
     }
 
@@ -336,6 +381,9 @@ impl Frame {
                 ..FrameHeader::default()
             },
             payload: data,
+            received_mask: None,
+            compress: true,
+            compression_level: None,
         }
     }
 
@@ -348,6 +396,9 @@ impl Frame {
                 ..FrameHeader::default()
             },
             payload: data,
+            received_mask: None,
+            compress: true,
+            compression_level: None,
         }
     }
 
@@ -355,7 +406,7 @@ impl Frame {
     #[inline]
     pub fn close(msg: Option<CloseFrame>) -> Frame {
         let payload = if let Some(CloseFrame { code, reason }) = msg {
-            let mut p = Vec::with_capacity(reason.as_bytes().len() + 2);
+            let mut p = Vec::with_capacity(reason.len() + 2);
             p.write_u16::<NetworkEndian>(code.into()).unwrap(); // can't fail
             p.extend_from_slice(reason.as_bytes());
             p
@@ -366,12 +417,21 @@ impl Frame {
         Frame {
             header: FrameHeader::default(),
             payload,
+            received_mask: None,
+            compress: true,
+            compression_level: None,
         }
     }
 
     /// Create a frame from given header and data.
     pub fn from_payload(header: FrameHeader, payload: Vec<u8>) -> Self {
-        Frame { header, payload }
+        Frame {
+            header,
+            payload,
+            received_mask: None,
+            compress: true,
+            compression_level: None,
+        }
     }
 
     /// Write a frame out to a buffer
@@ -485,6 +545,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn received_mask() {
+        let mut raw: Cursor<Vec<u8>> =
+            Cursor::new(vec![0x82, 0x82, 0x01, 0x02, 0x03, 0x04, 0x06, 0x06]);
+        let (header, length) = FrameHeader::parse(&mut raw).unwrap().unwrap();
+        assert_eq!(length, 2);
+        let mut payload = Vec::new();
+        raw.read_to_end(&mut payload).unwrap();
+        let mut frame = Frame::from_payload(header, payload);
+        assert_eq!(frame.received_mask(), None);
+        frame.apply_mask();
+        assert!(frame.header().mask.is_none());
+        assert_eq!(frame.received_mask(), Some([0x01, 0x02, 0x03, 0x04]));
+        assert_eq!(frame.into_data(), vec![0x07, 0x04]);
+    }
+
     #[test]
     fn format() {
         let frame = Frame::ping(vec![0x01, 0x02]);
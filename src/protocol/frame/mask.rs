@@ -4,7 +4,10 @@ pub fn generate_mask() -> [u8; 4] {
     rand::random()
 }
 
-/// Mask/unmask a frame.
+/// Mask/unmask a frame payload in place with the given key.
+///
+/// Masking is a plain XOR with the key, so applying the same key twice restores the original
+/// data. Payloads are processed four bytes at a time where alignment allows it.
 #[inline]
 pub fn apply_mask(buf: &mut [u8], mask: [u8; 4]) {
     apply_mask_fast32(buf, mask)
@@ -23,8 +26,8 @@ fn apply_mask_fallback(buf: &mut [u8], mask: [u8; 4]) {
 pub fn apply_mask_fast32(buf: &mut [u8], mask: [u8; 4]) {
     let mask_u32 = u32::from_ne_bytes(mask);
 
-    let (mut prefix, words, mut suffix) = unsafe { buf.align_to_mut::<u32>() };
-    apply_mask_fallback(&mut prefix, mask);
+    let (prefix, words, suffix) = unsafe { buf.align_to_mut::<u32>() };
+    apply_mask_fallback(prefix, mask);
     let head = prefix.len() & 3;
     let mask_u32 = if head > 0 {
         if cfg!(target_endian = "big") {
@@ -38,7 +41,7 @@ pub fn apply_mask_fast32(buf: &mut [u8], mask: [u8; 4]) {
     for word in words.iter_mut() {
         *word ^= mask_u32;
     }
-    apply_mask_fallback(&mut suffix, mask_u32.to_ne_bytes());
+    apply_mask_fallback(suffix, mask_u32.to_ne_bytes());
 }
 
 #[cfg(test)]
@@ -8,10 +8,12 @@ mod mask;
 
 pub use self::frame::CloseFrame;
 pub use self::frame::{Frame, FrameHeader};
+pub use self::mask::apply_mask;
 
 use crate::error::{Error, Result};
 use input_buffer::{InputBuffer, MIN_READ};
 use log::*;
+use std::convert::TryInto;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
 
 /// A reader and writer for WebSocket frames.
@@ -115,6 +117,11 @@ impl FrameCodec {
         }
     }
 
+    /// Extract the data read from the stream but not processed yet.
+    pub(super) fn into_buffer(self) -> Vec<u8> {
+        self.in_buffer.into_vec()
+    }
+
     /// Read a frame from the provided stream.
     pub(super) fn read_frame<Stream>(
         &mut self,
@@ -135,22 +142,27 @@ impl FrameCodec {
                 }
 
                 if let Some((_, ref length)) = self.header {
-                    let length = *length;
-
-                    // Enforce frame size limit early and make sure `length`
-                    // is not too big (fits into `usize`).
-                    if length > max_size as u64 {
-                        return Err(Error::Capacity(
-                            format!("Message length too big: {} > {}", length, max_size).into(),
-                        ));
+                    // Frame lengths take up to 64 bits, which may not fit into `usize`
+                    // on targets with a narrower address space.
+                    let length: usize = (*length).try_into().map_err(|_| {
+                        Error::Capacity(
+                            format!("Frame length {} is too big for this target", length).into(),
+                        )
+                    })?;
+
+                    // Enforce frame size limit early.
+                    if length > max_size {
+                        return Err(Error::MessageTooBig {
+                            size: length,
+                            max_size,
+                        });
                     }
 
                     let input_size = cursor.get_ref().len() as u64 - cursor.position();
-                    if length <= input_size {
-                        // No truncation here since `length` is checked above
-                        let mut payload = Vec::with_capacity(length as usize);
+                    if length as u64 <= input_size {
+                        let mut payload = Vec::with_capacity(length);
                         if length > 0 {
-                            cursor.take(length).read_to_end(&mut payload)?;
+                            cursor.take(length as u64).read_to_end(&mut payload)?;
                         }
                         break payload;
                     }
@@ -161,7 +173,7 @@ impl FrameCodec {
             let size = self
                 .in_buffer
                 .prepare_reserve(MIN_READ)
-                .with_limit(usize::max_value())
+                .with_limit(usize::MAX)
                 .map_err(|_| Error::Capacity("Incoming TCP buffer is full".into()))?
                 .read_from(stream)?;
             if size == 0 {
@@ -274,13 +286,26 @@ mod tests {
         let _ = sock.read_frame(None); // should not crash
     }
 
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn length_too_big_for_target() {
+        let raw = Cursor::new(vec![
+            0x82, 0x7f, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        let mut sock = FrameSocket::new(raw);
+        assert_eq!(
+            sock.read_frame(None).unwrap_err().to_string(),
+            "Space limit exceeded: Frame length 4294967296 is too big for this target"
+        );
+    }
+
     #[test]
     fn size_limit_hit() {
         let raw = Cursor::new(vec![0x82, 0x07, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07]);
         let mut sock = FrameSocket::new(raw);
         assert_eq!(
             sock.read_frame(Some(5)).unwrap_err().to_string(),
-            "Space limit exceeded: Message length too big: 7 > 5"
+            "Space limit exceeded: Message too big: 7 > 5"
         );
     }
 }
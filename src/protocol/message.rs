@@ -128,13 +128,10 @@ impl IncompleteMessage {
         let portion_size = tail.as_ref().len();
         // Be careful about integer overflows here.
         if my_size > max_size || portion_size > max_size - my_size {
-            return Err(Error::Capacity(
-                format!(
-                    "Message too big: {} + {} > {}",
-                    my_size, portion_size, max_size
-                )
-                .into(),
-            ));
+            return Err(Error::MessageTooBig {
+                size: my_size.saturating_add(portion_size),
+                max_size,
+            });
         }
 
         match self.collector {
@@ -202,42 +199,27 @@ impl Message {
 
     /// Indicates whether a message is a text message.
     pub fn is_text(&self) -> bool {
-        match *self {
-            Message::Text(_) => true,
-            _ => false,
-        }
+        matches!(*self, Message::Text(_))
     }
 
     /// Indicates whether a message is a binary message.
     pub fn is_binary(&self) -> bool {
-        match *self {
-            Message::Binary(_) => true,
-            _ => false,
-        }
+        matches!(*self, Message::Binary(_))
     }
 
     /// Indicates whether a message is a ping message.
     pub fn is_ping(&self) -> bool {
-        match *self {
-            Message::Ping(_) => true,
-            _ => false,
-        }
+        matches!(*self, Message::Ping(_))
     }
 
     /// Indicates whether a message is a pong message.
     pub fn is_pong(&self) -> bool {
-        match *self {
-            Message::Pong(_) => true,
-            _ => false,
-        }
+        matches!(*self, Message::Pong(_))
     }
 
     /// Indicates whether a message ia s close message.
     pub fn is_close(&self) -> bool {
-        match *self {
-            Message::Close(_) => true,
-            _ => false,
-        }
+        matches!(*self, Message::Close(_))
     }
 
     /// Get the length of the WebSocket message.
@@ -317,9 +299,9 @@ impl From<Vec<u8>> for Message {
     }
 }
 
-impl Into<Vec<u8>> for Message {
-    fn into(self) -> Vec<u8> {
-        self.into_data()
+impl From<Message> for Vec<u8> {
+    fn from(message: Message) -> Vec<u8> {
+        message.into_data()
     }
 }
 
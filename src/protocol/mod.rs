@@ -3,26 +3,37 @@
 pub mod frame;
 
 pub(crate) mod message;
+mod shutdown;
 
 pub use self::frame::CloseFrame;
 pub use self::message::Message;
+pub use self::shutdown::{close_all, CloseOutcome};
 
 use log::*;
 use std::collections::VecDeque;
 use std::io::{ErrorKind as IoErrorKind, Read, Write};
 use std::mem::replace;
+use std::time::{Duration, Instant};
 
 use self::frame::coding::{CloseCode, Control as OpCtl, Data as OpData, OpCode};
 use self::frame::{Frame, FrameCodec};
-use self::message::IncompleteMessage;
 use crate::error::{Error, Result};
+use crate::extensions::header::Extension;
 use crate::extensions::uncompressed::UncompressedExt;
-use crate::extensions::WebSocketExtension;
+use crate::extensions::{ReservedBits, WebSocketExtension};
+use crate::handshake::headers::{MAX_HEADERS, MAX_HEAD_SIZE, MAX_LINE_SIZE};
 use crate::protocol::frame::coding::Data;
 use crate::util::NonBlockingResult;
 
 pub(crate) const MAX_MESSAGE_SIZE: usize = 64 << 20;
 
+/// The maximum size of a close reason: control frame payloads are limited to 125 bytes, two of
+/// which are taken by the close code.
+const MAX_CLOSE_REASON_SIZE: usize = 123;
+
+/// The maximum number of unanswered pings remembered for round-trip time measurement.
+const MAX_PENDING_PINGS: usize = 16;
+
 /// Indicates a Client or Server role of the websocket
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Role {
@@ -32,6 +43,49 @@ pub enum Role {
     Client,
 }
 
+/// What to do with an outgoing message when the send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendQueueOverflow {
+    /// Refuse the new message with `Error::SendQueueFull`.
+    Reject,
+    /// Drop the oldest queued data message to make room for the new one. Control frames are
+    /// never dropped; if the queue holds nothing else, the new message is refused as with
+    /// `Reject`.
+    DropOldest,
+}
+
+/// Counters collected over the lifetime of a WebSocket connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WebSocketStats {
+    /// The number of queued messages dropped because of `SendQueueOverflow::DropOldest`.
+    pub dropped_messages: u64,
+}
+
+/// Options for sending a single message with `WebSocket::write_message_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Let the extension compress the message. Turn it off for payloads which do not shrink,
+    /// e.g. JPEG images, while compression stays on for the other messages. The default value
+    /// is `true`.
+    pub compress: bool,
+    /// The level to compress the message with, from 0 (no compression) to 9 (best
+    /// compression), e.g. 9 for a large snapshot sent now and then and 1 for frequent small
+    /// updates. Higher levels are taken as 9. `None` means the level the extension is
+    /// configured with. With permessage-deflate, a message compressed with another level than
+    /// the configured one ends the compression context, so use it for occasional messages.
+    /// The default value is `None`.
+    pub compression_level: Option<u32>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            compress: true,
+            compression_level: None,
+        }
+    }
+}
+
 /// The configuration for WebSocket connection.
 #[derive(Debug, Copy, Clone)]
 pub struct WebSocketConfig<E = UncompressedExt>
@@ -42,11 +96,58 @@ where
     /// means here that the size of the queue is unlimited. The default value is the unlimited
     /// queue.
     pub max_send_queue: Option<usize>,
+    /// What to do when the send queue set by `max_send_queue` is full. The default value is
+    /// `SendQueueOverflow::Reject`.
+    pub send_queue_overflow: SendQueueOverflow,
     /// The maximum size of a single message frame. `None` means no size limit. The limit is for
     /// frame payload NOT including the frame header. The default value is 16 MiB which should
     /// be reasonably big for all normal use-cases but small enough to prevent memory eating
     /// by a malicious user.
     pub max_frame_size: Option<usize>,
+    /// The maximum number of frames a single incoming message may consist of. `None` means no
+    /// limit. Even under a modest byte limit, a peer sending a message as millions of tiny
+    /// fragments causes a lot of per-frame overhead, so this caps that separately; going over
+    /// the limit fails the connection with a policy violation. The default value is no limit.
+    pub max_fragments: Option<usize>,
+    /// The maximum number of ping and pong frames accepted from the peer per second. `None`
+    /// means no limit. Every ping makes us send a pong, so a peer flooding us with pings can
+    /// burn a lot of CPU; going over the limit fails the connection with a policy violation.
+    /// The default value is no limit.
+    pub max_control_frames_per_second: Option<u32>,
+    /// What to do with close reasons longer than 123 bytes, which would not fit into a control
+    /// frame. If `true`, the reason is truncated on a UTF-8 character boundary. If `false`,
+    /// closing fails with an error and nothing is sent. The default value is `false`.
+    pub truncate_close_reason: bool,
+    /// Accept any close code from the peer. Normally a close frame with a reserved or otherwise
+    /// invalid code is answered with a protocol error close frame; some brokers send such codes
+    /// anyway, so with this set to `true` they are acknowledged like any other and passed on to
+    /// the application verbatim. The default value is `false`.
+    pub accept_any_close_code: bool,
+    /// Measure the round-trip time using pings. When set, the time every outgoing ping is
+    /// written is remembered and matched against pongs with the same payload; the results are
+    /// available through `WebSocket::last_rtt` and `WebSocket::avg_rtt`. Use distinct payloads
+    /// for pings in flight at the same time. The default value is `false`.
+    pub measure_rtt: bool,
+    /// Tolerate known deviations from RFC 6455 in the server handshake response, for servers
+    /// that cannot be fixed: an `Upgrade` header listing more protocols than `websocket`, or a
+    /// missing `Connection` header. Only used by clients. The default value is `false`.
+    pub lenient_handshake: bool,
+    /// The maximum number of header lines in the handshake request. Requests with more are
+    /// rejected with `431 Request Header Fields Too Large`. Only used by servers. The default
+    /// value is 124.
+    pub max_headers: usize,
+    /// The maximum size of the handshake request head, the request line and headers, in bytes.
+    /// `None` means no limit. Larger requests are rejected with `431 Request Header Fields Too
+    /// Large` instead of being buffered. Only used by servers. The default value is 64 KiB.
+    pub max_head_size: Option<usize>,
+    /// The maximum length of the request line of the handshake request, i.e. mostly of the
+    /// URI, in bytes. `None` means no limit. Longer request lines are rejected with `414 URI
+    /// Too Long` as soon as they are seen. Only used by servers. The default value is 8 KiB.
+    pub max_request_line: Option<usize>,
+    /// The maximum length of a single header line of the handshake request in bytes. `None`
+    /// means no limit. Longer lines are rejected with `431 Request Header Fields Too Large` as
+    /// soon as they are seen. Only used by servers. The default value is 8 KiB.
+    pub max_header_line: Option<usize>,
     /// Per-message compression strategy.
     pub encoder: E,
 }
@@ -56,11 +157,7 @@ where
     Ext: WebSocketExtension,
 {
     fn default() -> Self {
-        WebSocketConfig {
-            max_send_queue: None,
-            max_frame_size: Some(16 << 20),
-            encoder: Ext::new(Some(MAX_MESSAGE_SIZE)),
-        }
+        WebSocketConfig::default_with_encoder(Ext::new(Some(MAX_MESSAGE_SIZE)))
     }
 }
 
@@ -73,12 +170,41 @@ where
     pub fn default_with_encoder(encoder: Ext) -> WebSocketConfig<Ext> {
         WebSocketConfig {
             max_send_queue: None,
+            send_queue_overflow: SendQueueOverflow::Reject,
             max_frame_size: Some(16 << 20),
+            max_fragments: None,
+            max_control_frames_per_second: None,
+            truncate_close_reason: false,
+            accept_any_close_code: false,
+            measure_rtt: false,
+            lenient_handshake: false,
+            max_headers: MAX_HEADERS,
+            max_head_size: Some(MAX_HEAD_SIZE),
+            max_request_line: Some(MAX_LINE_SIZE),
+            max_header_line: Some(MAX_LINE_SIZE),
             encoder,
         }
     }
 }
 
+#[cfg(feature = "__deflate")]
+impl WebSocketConfig<crate::extensions::deflate::DeflateExt> {
+    /// Creates a `WebSocketConfig` instance using the default configuration with
+    /// permessage-deflate compression (RFC 7692) set up by `config`.
+    ///
+    /// ```no_run
+    /// use tungstenite::extensions::deflate::DeflateConfig;
+    /// use tungstenite::protocol::WebSocketConfig;
+    ///
+    /// let config = WebSocketConfig::with_deflate(DeflateConfig::default());
+    /// let (socket, _) =
+    ///     tungstenite::client::connect_with_config("ws://localhost:3012/", Some(config)).unwrap();
+    /// ```
+    pub fn with_deflate(config: crate::extensions::deflate::DeflateConfig) -> Self {
+        WebSocketConfig::default_with_encoder(crate::extensions::deflate::DeflateExt::new(config))
+    }
+}
+
 /// WebSocket input-output stream.
 ///
 /// This is THE structure you want to create to be able to speak the WebSocket protocol.
@@ -131,11 +257,59 @@ where
         }
     }
 
+    /// Convert a connection upgraded by an HTTP server or client into a WebSocket.
+    ///
+    /// Use this when the HTTP part of the handshake was done by another library, e.g. `hyper`:
+    /// `leftover` holds any bytes read from the connection past the handshake, which are the
+    /// start of the WebSocket stream. A server can compute its `101 Switching Protocols`
+    /// response with `handshake::server::create_response`.
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use tungstenite::extensions::uncompressed::UncompressedExt;
+    /// use tungstenite::handshake::server::create_response;
+    /// use tungstenite::protocol::Role;
+    /// use tungstenite::WebSocket;
+    ///
+    /// let request = http::Request::builder()
+    ///     .uri("/socket")
+    ///     .header("Host", "example.com")
+    ///     .header("Connection", "Upgrade")
+    ///     .header("Upgrade", "websocket")
+    ///     .header("Sec-WebSocket-Version", "13")
+    ///     .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+    ///     .body(Vec::<u8>::new())
+    ///     .unwrap();
+    /// let response = create_response(&request).unwrap();
+    /// assert_eq!(response.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+    ///
+    /// // ... send the response and take over the connection ...
+    /// let stream = Cursor::new(Vec::<u8>::new());
+    /// let socket: WebSocket<_, UncompressedExt> =
+    ///     WebSocket::from_upgraded(stream, Role::Server, Vec::new(), None);
+    /// ```
+    pub fn from_upgraded(
+        stream: Stream,
+        role: Role,
+        leftover: Vec<u8>,
+        config: Option<WebSocketConfig<Ext>>,
+    ) -> Self {
+        WebSocket::from_partially_read(stream, leftover, role, config)
+    }
+
     /// Returns a shared reference to the inner stream.
     pub fn get_ref(&self) -> &Stream {
         &self.socket
     }
 
+    /// Extract the inner stream along with the data read from it but not processed yet.
+    ///
+    /// This includes anything the peer sent right after the handshake, so a wrapper taking over
+    /// the stream does not lose any data. Messages queued for sending are dropped.
+    pub fn into_inner(self) -> (Stream, Vec<u8>) {
+        (self.socket, self.context.into_buffer())
+    }
+
     /// Returns a mutable reference to the inner stream.
     pub fn get_mut(&mut self) -> &mut Stream {
         &mut self.socket
@@ -151,6 +325,65 @@ where
         self.context.get_config()
     }
 
+    /// Read the connection statistics.
+    pub fn stats(&self) -> &WebSocketStats {
+        self.context.stats()
+    }
+
+    /// The subprotocol agreed on during the handshake, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.context.protocol()
+    }
+
+    /// Record the subprotocol agreed on during the handshake.
+    pub(crate) fn set_protocol(&mut self, protocol: Option<String>) {
+        self.context.set_protocol(protocol)
+    }
+
+    /// The extension encoding the messages, with the settings negotiated, e.g. to find out
+    /// whether compression is on with `DeflateExt::enabled`.
+    pub fn extension(&self) -> &Ext {
+        self.context.extension()
+    }
+
+    /// The extensions agreed on during the handshake, as listed in the
+    /// `Sec-WebSocket-Extensions` header of the response, with their parameters. Empty for
+    /// sockets not created by a handshake.
+    pub fn negotiated_extensions(&self) -> &[Extension] {
+        self.context.negotiated_extensions()
+    }
+
+    /// Record the extensions agreed on during the handshake.
+    pub(crate) fn set_negotiated_extensions(&mut self, extensions: Vec<Extension>) {
+        self.context.set_negotiated_extensions(extensions)
+    }
+
+    /// The handshake request received from the client, with the URI path and query the client
+    /// asked for and all headers. Only available on the server side, for sockets created by a
+    /// server handshake.
+    pub fn request(&self) -> Option<&http::Request<()>> {
+        self.context.request()
+    }
+
+    /// Record the handshake request received from the client.
+    pub(crate) fn set_request(&mut self, request: http::Request<()>) {
+        self.context.set_request(request)
+    }
+
+    /// The round-trip time of the most recently answered ping.
+    ///
+    /// Always `None` unless `WebSocketConfig::measure_rtt` is set.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.context.last_rtt()
+    }
+
+    /// The smoothed average of the measured round-trip times.
+    ///
+    /// Always `None` unless `WebSocketConfig::measure_rtt` is set.
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        self.context.avg_rtt()
+    }
+
     /// Check if it is possible to read messages.
     ///
     /// Reading is impossible after receiving `Message::Close`. It is still possible after
@@ -210,7 +443,8 @@ where
     ///
     /// ## Errors
     /// - If the WebSocket's send queue is full, `SendQueueFull` will be returned
-    /// along with the passed message. Otherwise, the message is queued and Ok(()) is returned.
+    ///   along with the passed message. Otherwise, the message is queued and Ok(()) is returned.
+    ///   With `SendQueueOverflow::DropOldest`, the oldest queued data message is dropped instead.
     /// - If the connection is closed and should be dropped, this will return [Error::ConnectionClosed].
     /// - If you try again after [Error::ConnectionClosed] was returned either from here or from `read_message`,
     ///   [Error::AlreadyClosed] will be returned. This indicates a program error on your part.
@@ -221,11 +455,43 @@ where
         self.context.write_message(&mut self.socket, message)
     }
 
+    /// Send a message to stream, if possible, as `write_message` does, with the given options
+    /// for this message.
+    pub fn write_message_with_options(
+        &mut self,
+        message: Message,
+        options: WriteOptions,
+    ) -> Result<()> {
+        self.context
+            .write_message_with_options(&mut self.socket, message, options)
+    }
+
+    /// Send a message to stream right away, bypassing the send queue.
+    ///
+    /// Anything queued earlier is flushed first so that messages keep their order, then the
+    /// message is framed, written and flushed. A successful return means that the whole message
+    /// has been handed over to the underlying stream. Errors, including WouldBlock, are
+    /// returned as-is. On WouldBlock the message is kept, either buffered or, if the queue could
+    /// not be flushed, at the end of the queue, and goes out with the next call to
+    /// `write_pending`.
+    pub fn write_message_now(&mut self, message: Message) -> Result<()> {
+        self.context.write_message_now(&mut self.socket, message)
+    }
+
     /// Flush the pending send queue.
     pub fn write_pending(&mut self) -> Result<()> {
         self.context.write_pending(&mut self.socket)
     }
 
+    /// Send a raw frame exactly as given, skipping every check the library normally does.
+    ///
+    /// This is meant for tools that test how peers deal with invalid input. The frame is neither
+    /// validated, masked nor passed through the extension, and the connection state is left
+    /// untouched, so it is easy to violate the protocol with it. The frame jumps the send queue.
+    pub fn unchecked_send_frame(&mut self, frame: Frame) -> Result<()> {
+        self.context.unchecked_send_frame(&mut self.socket, frame)
+    }
+
     /// Close the connection.
     ///
     /// This function guarantees that the close frame will be queued.
@@ -247,6 +513,10 @@ where
     ///
     /// It is thus safe to drop the underlying connection as soon as [Error::ConnectionClosed]
     /// is returned from `read_message` or `write_pending`.
+    ///
+    /// A close reason longer than 123 bytes does not fit into a close frame. Depending on
+    /// `WebSocketConfig::truncate_close_reason` it is either truncated or [Error::Protocol]
+    /// is returned and nothing is queued.
     pub fn close(&mut self, code: Option<CloseFrame>) -> Result<()> {
         self.context.close(&mut self.socket, code)
     }
@@ -264,14 +534,26 @@ where
     frame: FrameCodec,
     /// The state of processing, either "active" or "closing".
     state: WebSocketState,
-    /// Receive: an incomplete message being processed.
-    incomplete: Option<IncompleteMessage>,
+    /// Receive: the number of frames of the incoming message received so far.
+    fragments: usize,
+    /// Receive: the start of the current one-second window and the ping/pong frames seen in it.
+    control_frames: (Instant, u32),
     /// Send: a data send queue.
     send_queue: VecDeque<Frame>,
     /// Send: an OOB pong message.
     pong: Option<Frame>,
     /// The configuration for the websocket session.
     config: WebSocketConfig<Ext>,
+    /// Connection statistics.
+    stats: WebSocketStats,
+    /// Round-trip time measurement.
+    rtt: RttEstimator,
+    /// The subprotocol agreed on during the handshake.
+    protocol: Option<String>,
+    /// The extensions agreed on during the handshake.
+    extensions: Vec<Extension>,
+    /// The handshake request received from the client, on the server side.
+    request: Option<http::Request<()>>,
 }
 
 impl<Ext> WebSocketContext<Ext>
@@ -280,16 +562,22 @@ where
 {
     /// Create a WebSocket context that manages a post-handshake stream.
     pub fn new(role: Role, config: Option<WebSocketConfig<Ext>>) -> Self {
-        let config = config.unwrap_or_else(Default::default);
+        let config = config.unwrap_or_default();
 
         WebSocketContext {
             role,
             frame: FrameCodec::new(),
             state: WebSocketState::Active,
-            incomplete: None,
+            fragments: 0,
+            control_frames: (Instant::now(), 0),
             send_queue: VecDeque::new(),
             pong: None,
             config,
+            stats: WebSocketStats::default(),
+            rtt: RttEstimator::default(),
+            protocol: None,
+            extensions: Vec::new(),
+            request: None,
         }
     }
 
@@ -299,10 +587,14 @@ where
         role: Role,
         config: Option<WebSocketConfig<Ext>>,
     ) -> Self {
-        WebSocketContext {
-            frame: FrameCodec::from_partially_read(part),
-            ..WebSocketContext::new(role, config)
-        }
+        let mut context = WebSocketContext::new(role, config);
+        context.frame = FrameCodec::from_partially_read(part);
+        context
+    }
+
+    /// Extract the data read from the stream but not processed yet.
+    pub fn into_buffer(mut self) -> Vec<u8> {
+        replace(&mut self.frame, FrameCodec::new()).into_buffer()
     }
 
     /// Change the configuration.
@@ -315,6 +607,57 @@ where
         &self.config
     }
 
+    /// Read the connection statistics.
+    pub fn stats(&self) -> &WebSocketStats {
+        &self.stats
+    }
+
+    /// The subprotocol agreed on during the handshake, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Record the subprotocol agreed on during the handshake.
+    pub(crate) fn set_protocol(&mut self, protocol: Option<String>) {
+        self.protocol = protocol;
+    }
+
+    /// The extension encoding the messages, with the settings negotiated.
+    pub fn extension(&self) -> &Ext {
+        &self.config.encoder
+    }
+
+    /// The extensions agreed on during the handshake, as listed in the
+    /// `Sec-WebSocket-Extensions` header of the response.
+    pub fn negotiated_extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    /// Record the extensions agreed on during the handshake.
+    pub(crate) fn set_negotiated_extensions(&mut self, extensions: Vec<Extension>) {
+        self.extensions = extensions;
+    }
+
+    /// The handshake request received from the client, on the server side.
+    pub fn request(&self) -> Option<&http::Request<()>> {
+        self.request.as_ref()
+    }
+
+    /// Record the handshake request received from the client.
+    pub(crate) fn set_request(&mut self, request: http::Request<()>) {
+        self.request = Some(request);
+    }
+
+    /// The round-trip time of the most recently answered ping.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.rtt.last
+    }
+
+    /// The smoothed average of the measured round-trip times.
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        self.rtt.average
+    }
+
     /// Check if it is possible to read messages.
     ///
     /// Reading is impossible after receiving `Message::Close`. It is still possible after
@@ -347,7 +690,10 @@ where
             self.write_pending(stream).no_block()?;
             // If we get here, either write blocks or we have nothing to write.
             // Thus if read blocks, just let it return WouldBlock.
-            if let Some(message) = self.read_message_frame(stream)? {
+            let message = self
+                .read_message_frame(stream)
+                .map_err(|e| self.fail_connection(stream, e))?;
+            if let Some(message) = message {
                 trace!("Received message {}", message);
                 return Ok(message);
             }
@@ -363,6 +709,22 @@ where
     /// Note that only the last pong frame is stored to be sent, and only the
     /// most recent pong frame is sent if multiple pong frames are queued.
     pub fn write_message<Stream>(&mut self, stream: &mut Stream, message: Message) -> Result<()>
+    where
+        Stream: Read + Write,
+    {
+        self.write_message_with_options(stream, message, WriteOptions::default())
+    }
+
+    /// Send a message to the provided stream, if possible, with the given options for this
+    /// message.
+    ///
+    /// See `write_message` for details.
+    pub fn write_message_with_options<Stream>(
+        &mut self,
+        stream: &mut Stream,
+        message: Message,
+        options: WriteOptions,
+    ) -> Result<()>
     where
         Stream: Read + Write,
     {
@@ -385,11 +747,25 @@ where
             }
 
             if self.send_queue.len() >= max_send_queue {
-                return Err(Error::SendQueueFull(message));
+                let oldest_data = match self.config.send_queue_overflow {
+                    SendQueueOverflow::Reject => None,
+                    SendQueueOverflow::DropOldest => self
+                        .send_queue
+                        .iter()
+                        .position(|frame| matches!(frame.header().opcode, OpCode::Data(_))),
+                };
+                match oldest_data {
+                    Some(index) => {
+                        trace!("Send queue full, dropping the oldest data message");
+                        self.send_queue.remove(index);
+                        self.stats.dropped_messages += 1;
+                    }
+                    None => return Err(Error::SendQueueFull(message)),
+                }
             }
         }
 
-        let frame = match message {
+        let mut frame = match message {
             Message::Text(data) => Frame::message(data.into(), OpCode::Data(OpData::Text), true),
             Message::Binary(data) => Frame::message(data, OpCode::Data(OpData::Binary), true),
             Message::Ping(data) => Frame::ping(data),
@@ -399,11 +775,50 @@ where
             }
             Message::Close(code) => return self.close(stream, code),
         };
+        frame.set_compress(options.compress);
+        frame.set_compression_level(options.compression_level);
 
         self.send_queue.push_back(frame);
         self.write_pending(stream)
     }
 
+    /// Send a message to the provided stream right away, bypassing the send queue.
+    ///
+    /// See `WebSocket::write_message_now` for details.
+    pub fn write_message_now<Stream>(&mut self, stream: &mut Stream, message: Message) -> Result<()>
+    where
+        Stream: Read + Write,
+    {
+        // When terminated, return AlreadyClosed.
+        self.state.check_active()?;
+
+        // Do not write after sending a close frame.
+        if !self.state.is_active() {
+            return Err(Error::Protocol(
+                "Sending after closing is not allowed".into(),
+            ));
+        }
+
+        let frame = match message {
+            Message::Text(data) => Frame::message(data.into(), OpCode::Data(OpData::Text), true),
+            Message::Binary(data) => Frame::message(data, OpCode::Data(OpData::Binary), true),
+            Message::Ping(data) => Frame::ping(data),
+            Message::Pong(data) => Frame::pong(data),
+            Message::Close(code) => return self.close(stream, code),
+        };
+
+        // Keep the order of messages: whatever was queued before goes out first. If that
+        // blocks, the message waits behind it rather than getting lost.
+        match self.write_pending(stream) {
+            Ok(()) => self.send_one_frame(stream, frame),
+            Err(Error::Io(e)) if e.kind() == IoErrorKind::WouldBlock => {
+                self.send_queue.push_back(frame);
+                Err(Error::Io(e))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     /// Flush the pending send queue.
     pub fn write_pending<Stream>(&mut self, stream: &mut Stream) -> Result<()>
     where
@@ -436,13 +851,24 @@ where
             // maximum segment lifetimes (2MSL), while there is no corresponding
             // server impact as a TIME_WAIT connection is immediately reopened upon
             // a new SYN with a higher seq number). (RFC 6455)
-            self.state = WebSocketState::Terminated;
+            self.terminate();
             Err(Error::ConnectionClosed)
         } else {
             Ok(())
         }
     }
 
+    /// Send a raw frame exactly as given, skipping every check the library normally does.
+    ///
+    /// See `WebSocket::unchecked_send_frame` for details.
+    pub fn unchecked_send_frame<Stream>(&mut self, stream: &mut Stream, frame: Frame) -> Result<()>
+    where
+        Stream: Read + Write,
+    {
+        trace!("Sending unchecked frame: {:?}", frame);
+        self.frame.write_frame(stream, frame)
+    }
+
     /// Close the connection.
     ///
     /// This function guarantees that the close frame will be queued.
@@ -453,7 +879,30 @@ where
         Stream: Read + Write,
     {
         if let WebSocketState::Active = self.state {
+            let code = match code {
+                Some(mut close) if close.reason.len() > MAX_CLOSE_REASON_SIZE => {
+                    if !self.config.truncate_close_reason {
+                        return Err(Error::Protocol(
+                            format!(
+                                "Close reason too long: {} > {}",
+                                close.reason.len(),
+                                MAX_CLOSE_REASON_SIZE
+                            )
+                            .into(),
+                        ));
+                    }
+                    let mut end = MAX_CLOSE_REASON_SIZE;
+                    while !close.reason.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    close.reason.to_mut().truncate(end);
+                    Some(close)
+                }
+                code => code,
+            };
+
             self.state = WebSocketState::ClosedByUs;
+            self.config.encoder.on_close(code.as_ref());
             let frame = Frame::close(code);
             self.send_queue.push_back(frame);
         } else {
@@ -502,8 +951,25 @@ where
                 }
             }
 
+            // Reserved bits MUST be 0 unless an extension is negotiated that defines meanings
+            // for them. (RFC 6455)
+            let reserved = ReservedBits::of(frame.header());
+            let allowed = match frame.header().opcode {
+                OpCode::Control(_) => ReservedBits::NONE,
+                OpCode::Data(_) => self.config.encoder.reserved_bits(),
+            };
+            if !allowed.contains(reserved) {
+                return Err(Error::Protocol(
+                    "Reserved bits are non-zero and not used by a negotiated extension".into(),
+                ));
+            }
+
             match frame.header().opcode {
                 OpCode::Control(ctl) => {
+                    if let OpCtl::Ping | OpCtl::Pong = ctl {
+                        self.check_control_frame_rate()?;
+                    }
+
                     match ctl {
                         // All control frames MUST have a payload length of 125 bytes or less
                         // and MUST NOT be fragmented. (RFC 6455)
@@ -525,18 +991,45 @@ where
                             }
                             Ok(Some(Message::Ping(data)))
                         }
-                        OpCtl::Pong => Ok(Some(Message::Pong(frame.into_data()))),
+                        OpCtl::Pong => {
+                            let data = frame.into_data();
+                            if self.config.measure_rtt {
+                                self.rtt.pong_received(&data);
+                            }
+                            Ok(Some(Message::Pong(data)))
+                        }
                     }
                 }
 
-                _ => match self.config.encoder.on_receive_frame(frame) {
-                    Ok(r) => Ok(r),
-                    Err(e) => Err(e.into()),
-                },
+                OpCode::Data(data) => {
+                    self.fragments = match data {
+                        OpData::Continue => self.fragments.saturating_add(1),
+                        _ => 1,
+                    };
+                    if let Some(max_fragments) = self.config.max_fragments {
+                        if self.fragments > max_fragments {
+                            return Err(Error::Policy(
+                                format!(
+                                    "Too many fragments: {} > {}",
+                                    self.fragments, max_fragments
+                                )
+                                .into(),
+                            ));
+                        }
+                    }
+                    if frame.header().is_final {
+                        self.fragments = 0;
+                    }
+
+                    match self.config.encoder.on_receive_frame(frame) {
+                        Ok(r) => Ok(r),
+                        Err(e) => Err(e.into()),
+                    }
+                }
             } // match opcode
         } else {
             // Connection closed by peer
-            match replace(&mut self.state, WebSocketState::Terminated) {
+            match self.terminate() {
                 WebSocketState::ClosedByPeer | WebSocketState::CloseAcknowledged => {
                     Err(Error::ConnectionClosed)
                 }
@@ -547,6 +1040,81 @@ where
         }
     }
 
+    /// Count a received ping or pong frame against `max_control_frames_per_second`.
+    fn check_control_frame_rate(&mut self) -> Result<()> {
+        if let Some(limit) = self.config.max_control_frames_per_second {
+            let now = Instant::now();
+            let (ref mut window_start, ref mut count) = self.control_frames;
+            if now.duration_since(*window_start) >= Duration::from_secs(1) {
+                *window_start = now;
+                *count = 0;
+            }
+
+            *count = count.saturating_add(1);
+            if *count > limit {
+                return Err(Error::Policy(
+                    format!("More than {} control frames per second", limit).into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail the connection after a read error.
+    ///
+    /// If the error has a matching close code (RFC 6455, section 7.4.1), a close frame carrying
+    /// it is queued and flushed on a best-effort basis so that the peer learns why the connection
+    /// is going away. The error itself is always handed back to the caller.
+    fn fail_connection<Stream>(&mut self, stream: &mut Stream, error: Error) -> Error
+    where
+        Stream: Read + Write,
+    {
+        let close = match error {
+            // An endpoint MUST _Fail the WebSocket Connection_ when it receives data
+            // that is not valid UTF-8 in a text message. (RFC 6455)
+            Error::Utf8 => CloseFrame {
+                code: CloseCode::Invalid,
+                reason: "Invalid UTF-8 payload".into(),
+            },
+            // What is left of the capacity errors here are about sizes, e.g. of a frame or of
+            // the decompressed message.
+            Error::MessageTooBig { .. } | Error::Capacity(_) => CloseFrame {
+                code: CloseCode::Size,
+                reason: "Message too big".into(),
+            },
+            Error::Policy(_) => CloseFrame {
+                code: CloseCode::Policy,
+                reason: "Policy violation".into(),
+            },
+            Error::ExtensionFailure { code, .. } => CloseFrame {
+                code,
+                reason: "Extension error".into(),
+            },
+            _ => return error,
+        };
+
+        if self.state.is_active() {
+            debug!("Failing the connection with {:?}", close);
+            self.state = WebSocketState::ClosedByUs;
+            self.config.encoder.on_close(Some(&close));
+            self.send_queue.push_back(Frame::close(Some(close)));
+            // The original error is what matters to the caller, not how the close went.
+            let _ = self.write_pending(stream);
+        }
+
+        error
+    }
+
+    /// Mark the connection as terminated and tear down the extension, returning the state
+    /// before.
+    fn terminate(&mut self) -> WebSocketState {
+        let state = replace(&mut self.state, WebSocketState::Terminated);
+        if state != WebSocketState::Terminated {
+            self.config.encoder.on_teardown();
+        }
+        state
+    }
+
     /// Received a close frame. Tells if we need to return a close frame to the user.
     #[allow(clippy::option_option)]
     fn do_close<'t>(&mut self, close: Option<CloseFrame<'t>>) -> Option<Option<CloseFrame<'t>>> {
@@ -555,8 +1123,9 @@ where
             WebSocketState::Active => {
                 let close_code = close.as_ref().map(|f| f.code);
                 self.state = WebSocketState::ClosedByPeer;
+                self.config.encoder.on_close(close.as_ref());
                 let reply = if let Some(code) = close_code {
-                    if code.is_allowed() {
+                    if code.is_allowed() || self.config.accept_any_close_code {
                         Frame::close(Some(CloseFrame {
                             code: CloseCode::Normal,
                             reason: "".into(),
@@ -593,6 +1162,10 @@ where
     where
         Stream: Read + Write,
     {
+        if self.config.measure_rtt && frame.header().opcode == OpCode::Control(OpCtl::Ping) {
+            self.rtt.ping_sent(frame.payload().clone());
+        }
+
         match self.role {
             Role::Server => {}
             Role::Client => {
@@ -603,35 +1176,51 @@ where
         }
 
         if frame.header().is_final {
-            frame = match self.config.encoder.on_send_frame(frame) {
-                Ok(frame) => frame,
-                Err(e) => return Err(e.into()),
-            };
+            frame = self
+                .config
+                .encoder
+                .on_send_frame(frame)
+                .map_err(Into::into)?;
         }
 
-        let max_frame_size = self.config.max_frame_size.unwrap_or_else(usize::max_value);
-        if frame.payload().len() > max_frame_size {
+        // Control frames MUST NOT be fragmented. (RFC 6455)
+        let max_frame_size = self.config.max_frame_size.unwrap_or(usize::MAX);
+        if frame.payload().len() > max_frame_size
+            && matches!(frame.header().opcode, OpCode::Data(_))
+        {
+            // The first frame keeps the reserved bits set by the extension; every frame has
+            // its own mask.
             let mut chunks = frame.payload().chunks(max_frame_size).peekable();
-            let data_frame = Frame::message(
+            let mut data_frame = Frame::message(
                 Vec::from(chunks.next().unwrap()),
                 frame.header().opcode,
                 false,
             );
+            let header = data_frame.header_mut();
+            header.rsv1 = frame.header().rsv1;
+            header.rsv2 = frame.header().rsv2;
+            header.rsv3 = frame.header().rsv3;
+            if frame.is_masked() {
+                data_frame.set_random_mask();
+            }
             self.frame
                 .write_frame(stream, data_frame)
                 .check_connection_reset(self.state)?;
 
             while let Some(chunk) = chunks.next() {
-                let frame = Frame::message(
+                let mut data_frame = Frame::message(
                     Vec::from(chunk),
                     OpCode::Data(Data::Continue),
                     chunks.peek().is_none(),
                 );
+                if frame.is_masked() {
+                    data_frame.set_random_mask();
+                }
 
-                trace!("Sending frame: {:?}", frame);
+                trace!("Sending frame: {:?}", data_frame);
 
                 self.frame
-                    .write_frame(stream, frame)
+                    .write_frame(stream, data_frame)
                     .check_connection_reset(self.state)?;
             }
 
@@ -645,6 +1234,55 @@ where
     }
 }
 
+/// Round-trip time estimation from pings and the matching pongs.
+#[derive(Debug, Default)]
+struct RttEstimator {
+    /// Payloads of the pings sent but not answered yet, oldest first, with their send times.
+    pending: VecDeque<(Vec<u8>, Instant)>,
+    /// The last measured round-trip time.
+    last: Option<Duration>,
+    /// The smoothed round-trip time.
+    average: Option<Duration>,
+}
+
+impl RttEstimator {
+    /// Remember an outgoing ping.
+    fn ping_sent(&mut self, payload: Vec<u8>) {
+        if self.pending.len() >= MAX_PENDING_PINGS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((payload, Instant::now()));
+    }
+
+    /// Match a received pong against the pending pings and update the estimate.
+    fn pong_received(&mut self, payload: &[u8]) {
+        let index = match self.pending.iter().position(|(ping, _)| ping == payload) {
+            Some(index) => index,
+            // An unsolicited pong, e.g. a heartbeat.
+            None => return,
+        };
+        // A peer may skip pings if it answers only the most recent one, so anything
+        // older than the matched ping will not be answered anymore.
+        let rtt = self.pending[index].1.elapsed();
+        self.pending.drain(..=index);
+        self.last = Some(rtt);
+        // Same smoothing as TCP uses (RFC 6298): SRTT = 7/8 SRTT + 1/8 RTT.
+        self.average = Some(match self.average {
+            Some(average) => (average * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+}
+
+impl<Ext> Drop for WebSocketContext<Ext>
+where
+    Ext: WebSocketExtension,
+{
+    fn drop(&mut self) {
+        self.terminate();
+    }
+}
+
 /// The current connection state.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum WebSocketState {
@@ -663,20 +1301,14 @@ enum WebSocketState {
 impl WebSocketState {
     /// Tell if we're allowed to process normal messages.
     fn is_active(self) -> bool {
-        match self {
-            WebSocketState::Active => true,
-            _ => false,
-        }
+        matches!(self, WebSocketState::Active)
     }
 
     /// Tell if we should process incoming data. Note that if we send a close frame
     /// but the remote hasn't confirmed, they might have sent data before they receive our
     /// close frame, so we should still pass those to client code, hence ClosedByUs is valid.
     fn can_read(self) -> bool {
-        match self {
-            WebSocketState::Active | WebSocketState::ClosedByUs => true,
-            _ => false,
-        }
+        matches!(self, WebSocketState::Active | WebSocketState::ClosedByUs)
     }
 
     /// Check if the state is active, return error if not.
@@ -710,13 +1342,16 @@ impl<T> CheckConnectionReset for Result<T> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Message, Role, WebSocket, WebSocketConfig};
+    use super::{CloseFrame, Message, Role, SendQueueOverflow, WebSocket, WebSocketConfig};
 
+    use crate::error::Error;
     use crate::extensions::uncompressed::UncompressedExt;
-    use crate::protocol::frame::coding::{Data, OpCode};
-    use crate::protocol::frame::Frame;
+    use crate::extensions::WebSocketExtension;
+    use crate::protocol::frame::coding::{CloseCode, Control, Data, OpCode};
+    use crate::protocol::frame::{Frame, FrameSocket};
     use std::io;
     use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
 
     struct WriteMoc<Stream>(Stream);
 
@@ -735,6 +1370,71 @@ mod tests {
         }
     }
 
+    struct ReadWriteMoc<Stream>(Stream, Vec<u8>);
+
+    impl<Stream> io::Write for ReadWriteMoc<Stream> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<Stream: io::Read> io::Read for ReadWriteMoc<Stream> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    struct BlockingWriteMoc {
+        blocked: bool,
+        written: Vec<u8>,
+    }
+
+    impl io::Write for BlockingWriteMoc {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.blocked {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.written.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Read for BlockingWriteMoc {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    /// An extension recording the lifecycle hooks called.
+    #[derive(Debug)]
+    struct LifecycleExt(UncompressedExt, Arc<Mutex<Vec<String>>>);
+
+    impl WebSocketExtension for LifecycleExt {
+        type Error = Error;
+
+        fn new(max_message_size: Option<usize>) -> Self {
+            LifecycleExt(UncompressedExt::new(max_message_size), Arc::default())
+        }
+
+        fn on_receive_frame(&mut self, frame: Frame) -> Result<Option<Message>, Self::Error> {
+            self.0.on_receive_frame(frame)
+        }
+
+        fn on_close(&mut self, frame: Option<&CloseFrame>) {
+            let reason = frame.map(|frame| frame.reason.to_string());
+            self.1.lock().unwrap().push(format!("close {:?}", reason));
+        }
+
+        fn on_teardown(&mut self) {
+            self.1.lock().unwrap().push("teardown".into());
+        }
+    }
+
     #[test]
     fn receive_messages() {
         let incoming = Cursor::new(vec![
@@ -756,36 +1456,369 @@ mod tests {
         );
     }
 
+    #[test]
+    fn invalid_utf8_closes_with_1007() {
+        let incoming = Cursor::new(vec![0x81, 0x02, 0xc3, 0x28]);
+        let mut socket = WebSocket::from_raw_socket(
+            ReadWriteMoc(incoming, Vec::new()),
+            Role::Client,
+            Some(WebSocketConfig::<UncompressedExt>::default()),
+        );
+        match socket.read_message() {
+            Err(Error::Utf8) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!socket.can_write());
+
+        let sent = Cursor::new(socket.get_ref().1.clone());
+        let mut frame = FrameSocket::new(sent).read_frame(None).unwrap().unwrap();
+        frame.apply_mask();
+        let close = frame.into_close().unwrap().unwrap();
+        assert_eq!(close.code, CloseCode::Invalid);
+    }
+
+    #[cfg(feature = "__deflate")]
+    #[test]
+    fn corrupt_compressed_message_closes_with_1007() {
+        use crate::extensions::deflate::{DeflateConfig, DeflateExt};
+
+        let mut ext = DeflateExt::new(DeflateConfig::default());
+        let response = http::Response::builder()
+            .header("Sec-WebSocket-Extensions", "permessage-deflate")
+            .body(())
+            .unwrap();
+        ext.on_response(&response).unwrap();
+
+        // A compressed binary frame with an invalid deflate block type.
+        let incoming = Cursor::new(vec![0xc2, 0x03, 0xff, 0xff, 0xff]);
+        let mut socket = WebSocket::from_raw_socket(
+            ReadWriteMoc(incoming, Vec::new()),
+            Role::Client,
+            Some(WebSocketConfig::default_with_encoder(ext)),
+        );
+        match socket.read_message() {
+            Err(Error::ExtensionFailure {
+                code: CloseCode::Invalid,
+                ..
+            }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let sent = Cursor::new(socket.get_ref().1.clone());
+        let mut frame = FrameSocket::new(sent).read_frame(None).unwrap().unwrap();
+        frame.apply_mask();
+        let close = frame.into_close().unwrap().unwrap();
+        assert_eq!(close.code, CloseCode::Invalid);
+    }
+
+    #[test]
+    fn reserved_bits_on_control_frame() {
+        // A ping with RSV1 set, which no extension may claim.
+        let incoming = Cursor::new(vec![0xc9, 0x00]);
+        let mut socket: WebSocket<_, UncompressedExt> =
+            WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, None);
+        match socket.read_message() {
+            Err(Error::Protocol(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_frame_closes_with_1009() {
+        let incoming = Cursor::new(vec![0x82, 0x03, 0x01, 0x02, 0x03]);
+        let limit = WebSocketConfig {
+            max_frame_size: Some(2),
+            ..WebSocketConfig::default_with_encoder(UncompressedExt::new(None))
+        };
+        let mut socket = WebSocket::from_raw_socket(
+            ReadWriteMoc(incoming, Vec::new()),
+            Role::Client,
+            Some(limit),
+        );
+        match socket.read_message() {
+            Err(Error::MessageTooBig { size, max_size }) => assert_eq!((size, max_size), (3, 2)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let sent = Cursor::new(socket.get_ref().1.clone());
+        let mut frame = FrameSocket::new(sent).read_frame(None).unwrap().unwrap();
+        frame.apply_mask();
+        let close = frame.into_close().unwrap().unwrap();
+        assert_eq!(close.code, CloseCode::Size);
+    }
+
+    #[test]
+    fn fragment_limiting() {
+        let incoming = Cursor::new(vec![
+            0x02, 0x01, 0x01, 0x00, 0x01, 0x02, 0x00, 0x01, 0x03, 0x80, 0x01, 0x04,
+        ]);
+        let limit = WebSocketConfig {
+            max_fragments: Some(3),
+            ..WebSocketConfig::default_with_encoder(UncompressedExt::new(None))
+        };
+        let mut socket = WebSocket::from_raw_socket(
+            ReadWriteMoc(incoming, Vec::new()),
+            Role::Client,
+            Some(limit),
+        );
+        assert_eq!(
+            socket.read_message().unwrap_err().to_string(),
+            "Policy violation: Too many fragments: 4 > 3"
+        );
+
+        // Too many fragments is not about the size of the message.
+        let sent = Cursor::new(socket.get_ref().1.clone());
+        let mut frame = FrameSocket::new(sent).read_frame(None).unwrap().unwrap();
+        frame.apply_mask();
+        let close = frame.into_close().unwrap().unwrap();
+        assert_eq!(close.code, CloseCode::Policy);
+    }
+
+    #[test]
+    fn control_frame_flood() {
+        let incoming = Cursor::new(vec![0x89, 0x00, 0x8a, 0x00, 0x89, 0x00]);
+        let limit = WebSocketConfig {
+            max_control_frames_per_second: Some(2),
+            ..WebSocketConfig::default_with_encoder(UncompressedExt::new(None))
+        };
+        let mut socket = WebSocket::from_raw_socket(
+            ReadWriteMoc(incoming, Vec::new()),
+            Role::Client,
+            Some(limit),
+        );
+        assert_eq!(socket.read_message().unwrap(), Message::Ping(vec![]));
+        assert_eq!(socket.read_message().unwrap(), Message::Pong(vec![]));
+        match socket.read_message() {
+            Err(Error::Policy(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut sent = FrameSocket::new(Cursor::new(socket.get_ref().1.clone()));
+        let pong = sent.read_frame(None).unwrap().unwrap();
+        assert_eq!(pong.header().opcode, OpCode::Control(Control::Pong));
+        let mut frame = sent.read_frame(None).unwrap().unwrap();
+        frame.apply_mask();
+        let close = frame.into_close().unwrap().unwrap();
+        assert_eq!(close.code, CloseCode::Policy);
+    }
+
+    #[test]
+    fn close_reason_too_long() {
+        let reason = "\u{e9}".repeat(62);
+        let mut socket: WebSocket<_, UncompressedExt> =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Server, None);
+        let close = CloseFrame {
+            code: CloseCode::Normal,
+            reason: reason.as_str().into(),
+        };
+        match socket.close(Some(close.clone())) {
+            Err(Error::Protocol(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(socket.can_write());
+
+        socket.set_config(|config| config.truncate_close_reason = true);
+        socket.close(Some(close)).unwrap();
+        socket.socket.set_position(0);
+        let frame = FrameSocket::new(&mut socket.socket)
+            .read_frame(None)
+            .unwrap()
+            .unwrap();
+        let close = frame.into_close().unwrap().unwrap();
+        assert_eq!(close.reason, "\u{e9}".repeat(61));
+    }
+
+    #[test]
+    fn lenient_close_codes() {
+        let incoming = Cursor::new(vec![0x88, 0x02, 0x03, 0xf8]);
+        let mut socket = WebSocket::from_raw_socket(
+            ReadWriteMoc(incoming, Vec::new()),
+            Role::Client,
+            Some(WebSocketConfig {
+                accept_any_close_code: true,
+                ..WebSocketConfig::default_with_encoder(UncompressedExt::new(None))
+            }),
+        );
+        match socket.read_message().unwrap() {
+            Message::Close(Some(close)) => assert_eq!(u16::from(close.code), 1016),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        socket.write_pending().unwrap();
+
+        let sent = Cursor::new(socket.get_ref().1.clone());
+        let mut frame = FrameSocket::new(sent).read_frame(None).unwrap().unwrap();
+        frame.apply_mask();
+        let close = frame.into_close().unwrap().unwrap();
+        assert_eq!(close.code, CloseCode::Normal);
+    }
+
+    #[test]
+    fn unchecked_send_frame() {
+        let mut socket: WebSocket<_, UncompressedExt> =
+            WebSocket::from_raw_socket(Cursor::new(Vec::new()), Role::Client, None);
+        let mut frame = Frame::ping(vec![0x01]);
+        frame.header_mut().is_final = false;
+        frame.header_mut().rsv2 = true;
+        socket.unchecked_send_frame(frame).unwrap();
+        assert_eq!(socket.get_ref().get_ref(), &vec![0x29, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn send_queue_drop_oldest() {
+        let config = WebSocketConfig {
+            max_send_queue: Some(2),
+            send_queue_overflow: SendQueueOverflow::DropOldest,
+            ..WebSocketConfig::default_with_encoder(UncompressedExt::new(None))
+        };
+        let stream = BlockingWriteMoc {
+            blocked: true,
+            written: Vec::new(),
+        };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+
+        // The first message is moved out of the queue into the codec's write buffer.
+        for message in [
+            Message::text("1"),
+            Message::text("2"),
+            Message::Ping(vec![]),
+            Message::text("3"),
+            Message::text("4"),
+        ] {
+            match socket.write_message(message) {
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(socket.stats().dropped_messages, 2);
+
+        socket.get_mut().blocked = false;
+        socket.write_pending().unwrap();
+
+        let mut sent = FrameSocket::new(Cursor::new(socket.get_ref().written.clone()));
+        let mut next = || sent.read_frame(None).unwrap().unwrap();
+        assert_eq!(next().into_data(), b"1");
+        assert_eq!(next().header().opcode, OpCode::Control(Control::Ping));
+        assert_eq!(next().into_data(), b"4");
+        assert!(sent.read_frame(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn send_queue_drop_oldest_keeps_control_frames() {
+        let config = WebSocketConfig {
+            max_send_queue: Some(2),
+            send_queue_overflow: SendQueueOverflow::DropOldest,
+            ..WebSocketConfig::default_with_encoder(UncompressedExt::new(None))
+        };
+        let stream = BlockingWriteMoc {
+            blocked: true,
+            written: Vec::new(),
+        };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+
+        for _ in 0..3 {
+            let _ = socket.write_message(Message::Ping(vec![]));
+        }
+        match socket.write_message(Message::text("data")) {
+            Err(Error::SendQueueFull(Message::Text(text))) => assert_eq!(text, "data"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(socket.stats().dropped_messages, 0);
+    }
+
+    #[test]
+    fn rtt_measurement() {
+        // An unsolicited pong followed by the answer to our ping.
+        let incoming = Cursor::new(vec![0x8a, 0x01, 0x02, 0x8a, 0x01, 0x01]);
+        let config = WebSocketConfig {
+            measure_rtt: true,
+            ..WebSocketConfig::default_with_encoder(UncompressedExt::new(None))
+        };
+        let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, Some(config));
+        assert_eq!(socket.last_rtt(), None);
+
+        socket.write_message(Message::Ping(vec![0x01])).unwrap();
+        assert_eq!(socket.read_message().unwrap(), Message::Pong(vec![0x02]));
+        assert_eq!(socket.last_rtt(), None);
+        assert_eq!(socket.read_message().unwrap(), Message::Pong(vec![0x01]));
+        assert!(socket.last_rtt().is_some());
+        assert_eq!(socket.avg_rtt(), socket.last_rtt());
+    }
+
+    #[test]
+    fn write_message_now() {
+        let stream = BlockingWriteMoc {
+            blocked: true,
+            written: Vec::new(),
+        };
+        let mut socket: WebSocket<_, UncompressedExt> =
+            WebSocket::from_raw_socket(stream, Role::Server, None);
+
+        match socket.write_message_now(Message::text("1")) {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        socket.write_message(Message::text("2")).unwrap_err();
+
+        socket.get_mut().blocked = false;
+        socket.write_message_now(Message::text("3")).unwrap();
+
+        let mut sent = FrameSocket::new(Cursor::new(socket.get_ref().written.clone()));
+        for expected in ["1", "2", "3"] {
+            let frame = sent.read_frame(None).unwrap().unwrap();
+            assert_eq!(frame.into_data(), expected.as_bytes());
+        }
+        assert!(sent.read_frame(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn write_message_now_behind_blocked_queue() {
+        let stream = BlockingWriteMoc {
+            blocked: true,
+            written: Vec::new(),
+        };
+        let mut socket: WebSocket<_, UncompressedExt> =
+            WebSocket::from_raw_socket(stream, Role::Server, None);
+
+        socket.write_message(Message::text("1")).unwrap_err();
+        socket.write_message(Message::text("2")).unwrap_err();
+        match socket.write_message_now(Message::text("3")) {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        socket.get_mut().blocked = false;
+        socket.write_pending().unwrap();
+
+        let mut sent = FrameSocket::new(Cursor::new(socket.get_ref().written.clone()));
+        for expected in ["1", "2", "3"] {
+            let frame = sent.read_frame(None).unwrap().unwrap();
+            assert_eq!(frame.into_data(), expected.as_bytes());
+        }
+        assert!(sent.read_frame(None).unwrap().is_none());
+    }
+
     #[test]
     fn size_limiting_text_fragmented() {
         let incoming = Cursor::new(vec![
             0x01, 0x07, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x80, 0x06, 0x57, 0x6f, 0x72,
             0x6c, 0x64, 0x21,
         ]);
-        let limit = WebSocketConfig {
-            max_send_queue: None,
-            max_frame_size: Some(16 << 20),
-            encoder: UncompressedExt::new(Some(10)),
-        };
+        let limit = WebSocketConfig::default_with_encoder(UncompressedExt::new(Some(10)));
         let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, Some(limit));
         assert_eq!(
             socket.read_message().unwrap_err().to_string(),
-            "Space limit exceeded: Message too big: 7 + 6 > 10"
+            "Space limit exceeded: Message too big: 13 > 10"
         );
     }
 
     #[test]
     fn size_limiting_binary() {
         let incoming = Cursor::new(vec![0x82, 0x03, 0x01, 0x02, 0x03]);
-        let limit = WebSocketConfig {
-            max_send_queue: None,
-            max_frame_size: Some(16 << 20),
-            encoder: UncompressedExt::new(Some(2)),
-        };
+        let limit = WebSocketConfig::default_with_encoder(UncompressedExt::new(Some(2)));
         let mut socket = WebSocket::from_raw_socket(WriteMoc(incoming), Role::Client, Some(limit));
         assert_eq!(
             socket.read_message().unwrap_err().to_string(),
-            "Space limit exceeded: Message too big: 0 + 3 > 2"
+            "Space limit exceeded: Message too big: 3 > 2"
         );
     }
 
@@ -795,9 +1828,8 @@ mod tests {
         let input_str = "hello unit test";
 
         let limit = WebSocketConfig {
-            max_send_queue: None,
             max_frame_size: Some(2),
-            encoder: UncompressedExt::new(Some(max_message_size)),
+            ..WebSocketConfig::default_with_encoder(UncompressedExt::new(Some(max_message_size)))
         };
 
         let mut socket =
@@ -815,10 +1847,12 @@ mod tests {
         let mut iter = vec
             .chunks(max_message_size)
             .map(|c| c.iter().collect::<String>())
-            .into_iter()
             .peekable();
 
-        let frame_eq = |expected: Frame, actual: Frame| {
+        let frame_eq = |expected: Frame, mut actual: Frame| {
+            // Every frame sent by a client is masked.
+            assert!(actual.is_masked());
+            actual.apply_mask();
             assert_eq!(expected.payload(), actual.payload());
             assert_eq!(expected.header().opcode, actual.header().opcode);
             assert_eq!(expected.header().rsv1, actual.header().rsv1);
@@ -850,4 +1884,41 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn extension_lifecycle() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config = |events: &Arc<Mutex<Vec<String>>>| {
+            let ext = LifecycleExt(UncompressedExt::new(None), events.clone());
+            Some(WebSocketConfig::default_with_encoder(ext))
+        };
+
+        // The server closes, the client replies and the server drops the connection.
+        let incoming = Cursor::new(vec![0x88, 0x04, 0x03, 0xe8, 0x6f, 0x6b]);
+        let mut socket = WebSocket::from_raw_socket(
+            ReadWriteMoc(incoming, Vec::new()),
+            Role::Client,
+            config(&events),
+        );
+        assert!(matches!(socket.read_message(), Ok(Message::Close(_))));
+        assert!(matches!(
+            socket.read_message(),
+            Err(Error::ConnectionClosed)
+        ));
+        assert_eq!(*events.lock().unwrap(), ["close Some(\"ok\")", "teardown"]);
+        drop(socket);
+        assert_eq!(events.lock().unwrap().len(), 2);
+
+        // Closing without waiting for the reply, the extension is torn down on drop.
+        events.lock().unwrap().clear();
+        let mut socket = WebSocket::from_raw_socket(
+            WriteMoc(Cursor::new(vec![])),
+            Role::Client,
+            config(&events),
+        );
+        socket.close(None).unwrap();
+        assert_eq!(*events.lock().unwrap(), ["close None"]);
+        drop(socket);
+        assert_eq!(*events.lock().unwrap(), ["close None", "teardown"]);
+    }
 }
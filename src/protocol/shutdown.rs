@@ -0,0 +1,225 @@
+//! Closing many connections at once, e.g. when a server shuts down.
+
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use super::frame::CloseFrame;
+use super::WebSocket;
+use crate::error::Error;
+use crate::extensions::WebSocketExtension;
+use crate::stream::SetTimeout;
+
+/// How long to wait for one connection at most before turning to the next one.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How closing a connection with `close_all` went.
+#[derive(Debug)]
+pub enum CloseOutcome {
+    /// The close handshake completed: the peer answered the close frame.
+    Closed,
+    /// The peer did not answer the close frame before the deadline.
+    TimedOut,
+    /// The connection failed while closing.
+    Failed(Error),
+}
+
+impl CloseOutcome {
+    /// Returns true if the close handshake completed.
+    pub fn is_closed(&self) -> bool {
+        matches!(self, CloseOutcome::Closed)
+    }
+}
+
+/// Close all `sockets` with `frame` and wait at most `timeout` for the peers to answer.
+///
+/// A close frame is queued on each socket, then the sockets are read in turn, discarding any
+/// messages still arriving, until every close handshake completed or the time is up. The
+/// outcome for each socket is returned in the order of `sockets`; the sockets can be dropped
+/// afterwards.
+///
+/// Reads wait at most a few milliseconds on one socket before turning to the next, using the
+/// read and write timeouts of the streams, which are reset afterwards. Non-blocking streams
+/// work as well: they are polled every few milliseconds, sleeping in between.
+pub fn close_all<S, Ext>(
+    sockets: &mut [WebSocket<S, Ext>],
+    frame: Option<CloseFrame>,
+    timeout: Duration,
+) -> Vec<CloseOutcome>
+where
+    S: Read + Write + SetTimeout,
+    Ext: WebSocketExtension,
+{
+    let deadline = Instant::now() + timeout;
+    let mut outcomes: Vec<Option<CloseOutcome>> = sockets
+        .iter_mut()
+        .map(|socket| match socket.close(frame.clone()) {
+            Ok(()) => None,
+            Err(e) => pending_or(e),
+        })
+        .collect();
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline || outcomes.iter().all(Option::is_some) {
+            break;
+        }
+        let interval = POLL_INTERVAL.min(deadline - now);
+        for (socket, outcome) in sockets.iter_mut().zip(outcomes.iter_mut()) {
+            if outcome.is_none() {
+                *outcome = poll_close(socket, interval);
+            }
+        }
+        // Reads from non-blocking streams return at once, so wait here instead of spinning.
+        let elapsed = now.elapsed();
+        if elapsed < interval && outcomes.iter().any(Option::is_none) {
+            thread::sleep(interval - elapsed);
+        }
+    }
+
+    for socket in sockets.iter_mut() {
+        let _ = socket.get_mut().set_timeout(None);
+    }
+    outcomes
+        .into_iter()
+        .map(|outcome| outcome.unwrap_or(CloseOutcome::TimedOut))
+        .collect()
+}
+
+/// Read from a closing socket for at most `interval`, returning the outcome if there is one.
+fn poll_close<S, Ext>(socket: &mut WebSocket<S, Ext>, interval: Duration) -> Option<CloseOutcome>
+where
+    S: Read + Write + SetTimeout,
+    Ext: WebSocketExtension,
+{
+    if let Err(e) = socket.get_mut().set_timeout(Some(interval)) {
+        return Some(CloseOutcome::Failed(e.into()));
+    }
+    loop {
+        match socket.read_message() {
+            Ok(message) => trace!("Discarding {:?} received while closing", message),
+            Err(e) => return pending_or(e),
+        }
+    }
+}
+
+/// The outcome for an error from a closing socket, `None` if it has to be waited for.
+fn pending_or(error: Error) -> Option<CloseOutcome> {
+    match error {
+        Error::ConnectionClosed | Error::AlreadyClosed => Some(CloseOutcome::Closed),
+        Error::Io(ref e)
+            if e.kind() == IoErrorKind::WouldBlock || e.kind() == IoErrorKind::TimedOut =>
+        {
+            None
+        }
+        e => {
+            debug!("Closing failed: {}", e);
+            Some(CloseOutcome::Failed(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{close_all, CloseOutcome};
+    use crate::extensions::uncompressed::UncompressedExt;
+    use crate::protocol::frame::coding::CloseCode;
+    use crate::protocol::frame::CloseFrame;
+    use crate::protocol::{Role, WebSocket};
+    use crate::stream::SetTimeout;
+    use crate::{Error, Message};
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn close_with_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut sockets = Vec::new();
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let stream = TcpStream::connect(addr).unwrap();
+            sockets.push(WebSocket::<_, UncompressedExt>::from_raw_socket(
+                stream,
+                Role::Server,
+                None,
+            ));
+            let (peer, _) = listener.accept().unwrap();
+            peers.push(peer);
+        }
+
+        // The first peer answers the close frame, the second one never reads.
+        let silent = peers.pop().unwrap();
+        let answering = peers.pop().unwrap();
+        let peer = thread::spawn(move || {
+            let mut socket =
+                WebSocket::<_, UncompressedExt>::from_raw_socket(answering, Role::Client, None);
+            let mut received = None;
+            loop {
+                match socket.read_message() {
+                    Ok(Message::Close(frame)) => received = frame.map(CloseFrame::into_owned),
+                    Ok(_) => {}
+                    Err(Error::ConnectionClosed) => return received,
+                    Err(e) => panic!("unexpected error: {}", e),
+                }
+            }
+        });
+
+        let frame = CloseFrame {
+            code: CloseCode::Away,
+            reason: "Shutting down".into(),
+        };
+        let outcomes = close_all(&mut sockets, Some(frame), Duration::from_millis(200));
+        assert!(outcomes[0].is_closed());
+        assert!(matches!(outcomes[1], CloseOutcome::TimedOut));
+        drop(sockets);
+        drop(silent);
+        assert_eq!(peer.join().unwrap().unwrap().code, CloseCode::Away);
+    }
+
+    #[test]
+    fn close_non_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (_silent, _) = listener.accept().unwrap();
+        let mut sockets = vec![WebSocket::<_, UncompressedExt>::from_raw_socket(
+            CountingReads(stream, 0),
+            Role::Server,
+            None,
+        )];
+
+        let outcomes = close_all(&mut sockets, None, Duration::from_millis(100));
+        assert!(matches!(outcomes[0], CloseOutcome::TimedOut));
+        // About one read every 10 ms, not a busy loop.
+        assert!(sockets[0].get_ref().1 <= 20);
+    }
+
+    struct CountingReads(TcpStream, usize);
+
+    impl Read for CountingReads {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 += 1;
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for CountingReads {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl SetTimeout for CountingReads {
+        fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+            self.0.set_timeout(timeout)
+        }
+    }
+}
@@ -2,14 +2,30 @@
 
 pub use crate::handshake::server::ServerHandshake;
 
+mod listener;
+mod proxy_protocol;
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+mod tls;
+
+pub use self::listener::Server;
+pub use self::proxy_protocol::{read_proxy_header, ProxyHeader};
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+pub use self::tls::{accept_tls, Acceptor};
+
 use crate::handshake::server::{Callback, NoCallback};
 use crate::handshake::HandshakeError;
 
 use crate::protocol::{WebSocket, WebSocketConfig};
+use crate::stream::{PeerAddr, SetTimeout};
+use http::HeaderMap;
+use log::*;
 
+#[cfg(feature = "__deflate")]
+use crate::extensions::deflate::{DeflateConfig, DeflateExt};
 use crate::extensions::uncompressed::UncompressedExt;
 use crate::extensions::WebSocketExtension;
 use std::io::{Read, Write};
+use std::time::Duration;
 
 /// Accept the given Stream as a WebSocket.
 ///
@@ -17,9 +33,9 @@ use std::io::{Read, Write};
 /// used by `accept()`.
 ///
 /// This function starts a server WebSocket handshake over the given stream.
-/// If you want TLS support, use `native_tls::TlsStream` or `openssl::ssl::SslStream`
-/// for the stream here. Any `Read + Write` streams are supported, including
-/// those from `Mio` and others.
+/// If you want TLS support, use `native_tls::TlsStream`, `rustls::StreamOwned` or
+/// `openssl::ssl::SslStream` for the stream here. Any `Read + Write` streams are supported,
+/// including those from `Mio` and others.
 pub fn accept_with_config<Stream, Ext>(
     stream: Stream,
     config: Option<WebSocketConfig<Ext>>,
@@ -34,9 +50,9 @@ where
 /// Accept the given Stream as a WebSocket.
 ///
 /// This function starts a server WebSocket handshake over the given stream.
-/// If you want TLS support, use `native_tls::TlsStream` or `openssl::ssl::SslStream`
-/// for the stream here. Any `Read + Write` streams are supported, including
-/// those from `Mio` and others.
+/// If you want TLS support, use `native_tls::TlsStream`, `rustls::StreamOwned` or
+/// `openssl::ssl::SslStream` for the stream here. Any `Read + Write` streams are supported,
+/// including those from `Mio` and others.
 pub fn accept<S: Read + Write>(
     stream: S,
 ) -> Result<
@@ -72,9 +88,147 @@ where
 /// This function does the same as `accept()` but accepts an extra callback
 /// for header processing. The callback receives headers of the incoming
 /// requests and is able to add extra headers to the reply.
+///
+/// The callback only borrows the request, but it is kept on the returned socket: use
+/// `WebSocket::request()` to consult its headers later on.
 pub fn accept_hdr<S: Read + Write, C: Callback>(
     stream: S,
     callback: C,
 ) -> Result<WebSocket<S, UncompressedExt>, HandshakeError<ServerHandshake<S, C, UncompressedExt>>> {
     accept_hdr_with_config(stream, callback, None)
 }
+
+/// Accept the given Stream as a WebSocket, for a connection arriving through a proxy or load
+/// balancer using the PROXY protocol.
+///
+/// The PROXY protocol header, version 1 or 2, is read first; the addresses it carries are
+/// returned along with the WebSocket, so the address of the actual client is known. The
+/// header is required, see `read_proxy_header`. Otherwise this does the same as `accept()`.
+pub fn accept_proxied<S: Read + Write>(
+    mut stream: S,
+) -> Result<
+    (WebSocket<S, UncompressedExt>, ProxyHeader),
+    HandshakeError<ServerHandshake<S, NoCallback, UncompressedExt>>,
+> {
+    let header = read_proxy_header(&mut stream)?;
+    let mut handshake = ServerHandshake::start(stream, NoCallback, None);
+    if let Some(addr) = header.source {
+        handshake = handshake.with_peer_addr(addr);
+    }
+    Ok((handshake.handshake()?, header))
+}
+
+/// Accept the given Stream as a WebSocket, telling the callback the address of the client.
+///
+/// This function does the same as `accept_hdr()`, and stores the address of the client in a
+/// `ConnectionInfo` in the extensions of the request, for the callback to log it or to decide
+/// on it:
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use tungstenite::handshake::server::{ConnectionInfo, Request, Response};
+/// use tungstenite::server::accept_hdr_with_peer_addr;
+///
+/// let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+/// for stream in server.incoming() {
+///     let callback = |request: &Request, response: Response| {
+///         let info = request.extensions().get::<ConnectionInfo>().unwrap();
+///         println!("Connection from {:?}", info.peer_addr);
+///         Ok(response)
+///     };
+///     let websocket = accept_hdr_with_peer_addr(stream.unwrap(), callback).unwrap();
+///     // ...
+/// }
+/// ```
+pub fn accept_hdr_with_peer_addr<S: Read + Write + PeerAddr, C: Callback>(
+    stream: S,
+    callback: C,
+) -> Result<WebSocket<S, UncompressedExt>, HandshakeError<ServerHandshake<S, C, UncompressedExt>>> {
+    let peer_addr = stream.peer_addr();
+    let handshake = ServerHandshake::start(stream, callback, None);
+    match peer_addr {
+        Ok(addr) => handshake.with_peer_addr(addr).handshake(),
+        Err(e) => {
+            debug!("Peer address unknown: {}", e);
+            handshake.handshake()
+        }
+    }
+}
+
+/// Accept the given Stream as a WebSocket, adding `headers` to the response.
+///
+/// This function does the same as `accept()`, for the common case of sending a few extra
+/// headers without writing a callback:
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use tungstenite::http::HeaderMap;
+/// use tungstenite::server::accept_with_headers;
+///
+/// let mut headers = HeaderMap::new();
+/// headers.insert("Server", "example/1.0".parse().unwrap());
+///
+/// let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+/// for stream in server.incoming() {
+///     let websocket = accept_with_headers(stream.unwrap(), headers.clone()).unwrap();
+///     // ...
+/// }
+/// ```
+pub fn accept_with_headers<S: Read + Write>(
+    stream: S,
+    headers: HeaderMap,
+) -> Result<
+    WebSocket<S, UncompressedExt>,
+    HandshakeError<ServerHandshake<S, HeaderMap, UncompressedExt>>,
+> {
+    accept_hdr(stream, headers)
+}
+
+/// Accept the given Stream as a WebSocket, accepting permessage-deflate compression (RFC 7692)
+/// with the given settings if the client offers it.
+///
+/// This function does the same as `accept()`, without having to spell out the extension type:
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use tungstenite::extensions::deflate::DeflateConfig;
+/// use tungstenite::server::accept_with_deflate;
+///
+/// let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+/// for stream in server.incoming() {
+///     let mut websocket = accept_with_deflate(stream.unwrap(), DeflateConfig::default()).unwrap();
+///     let message = websocket.read_message().unwrap();
+///     websocket.write_message(message).unwrap();
+/// }
+/// ```
+#[cfg(feature = "__deflate")]
+pub fn accept_with_deflate<S: Read + Write>(
+    stream: S,
+    deflate: DeflateConfig,
+) -> Result<WebSocket<S, DeflateExt>, HandshakeError<ServerHandshake<S, NoCallback, DeflateExt>>> {
+    accept_with_config(stream, Some(WebSocketConfig::with_deflate(deflate)))
+}
+
+/// Accept the given Stream as a WebSocket, giving the client at most `timeout` to complete the
+/// handshake.
+///
+/// This function does the same as `accept_hdr_with_config()`, but fails with an
+/// `io::ErrorKind::TimedOut` error if the handshake is not done in time. This keeps clients
+/// that send their request very slowly, or not at all, from tying up the server. The deadline
+/// is enforced with the read and write timeouts of the stream, which are reset afterwards.
+///
+/// To observe timed out handshakes, use `MidHandshake::handshake_with_timeout` on a
+/// `ServerHandshake` with an observer instead.
+pub fn accept_hdr_with_timeout<S, C, Ext>(
+    stream: S,
+    callback: C,
+    config: Option<WebSocketConfig<Ext>>,
+    timeout: Duration,
+) -> Result<WebSocket<S, Ext>, HandshakeError<ServerHandshake<S, C, Ext>>>
+where
+    S: Read + Write + SetTimeout,
+    C: Callback,
+    Ext: WebSocketExtension,
+{
+    ServerHandshake::start(stream, callback, config).handshake_with_timeout(timeout)
+}
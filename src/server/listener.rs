@@ -0,0 +1,174 @@
+//! A small blocking server, accepting WebSocket connections on a TCP listener.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use super::{accept_hdr_with_config, accept_hdr_with_timeout};
+use crate::extensions::uncompressed::UncompressedExt;
+use crate::extensions::WebSocketExtension;
+use crate::handshake::server::{ConnectionInfo, NoCallback};
+use crate::protocol::{WebSocket, WebSocketConfig};
+
+/// A function making the configuration for a new connection.
+type MakeConfig<Ext> = Arc<dyn Fn() -> WebSocketConfig<Ext> + Send + Sync>;
+
+/// A blocking WebSocket server owning a `TcpListener`.
+///
+/// It accepts connections, does the handshake with each client and passes the WebSockets to a
+/// handler, along with the address of the client:
+///
+/// ```no_run
+/// use tungstenite::server::Server;
+///
+/// Server::bind("127.0.0.1:3012")
+///     .unwrap()
+///     .thread_per_connection(true)
+///     .serve(|mut websocket, info| {
+///         println!("Connection from {:?}", info.peer_addr);
+///         while let Ok(message) = websocket.read_message() {
+///             if message.is_text() || message.is_binary() {
+///                 let _ = websocket.write_message(message);
+///             }
+///         }
+///     });
+/// ```
+///
+/// Connections on which the handshake fails are logged and dropped.
+pub struct Server<Ext = UncompressedExt>
+where
+    Ext: WebSocketExtension,
+{
+    listener: TcpListener,
+    config: Option<MakeConfig<Ext>>,
+    handshake_timeout: Option<Duration>,
+    thread_per_connection: bool,
+}
+
+impl<Ext: WebSocketExtension> fmt::Debug for Server<Ext> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("listener", &self.listener)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("thread_per_connection", &self.thread_per_connection)
+            .finish()
+    }
+}
+
+impl Server {
+    /// Create a server listening on `addr`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Self::from_listener(TcpListener::bind(addr)?))
+    }
+
+    /// Create a server accepting connections on `listener`.
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Server {
+            listener,
+            config: None,
+            handshake_timeout: None,
+            thread_per_connection: false,
+        }
+    }
+}
+
+impl<Ext> Server<Ext>
+where
+    Ext: WebSocketExtension,
+{
+    /// Use the configuration made by `config` for every connection.
+    ///
+    /// The configuration holds the state of the extensions of a connection, so a new one is
+    /// made for each.
+    pub fn with_config<E, F>(self, config: F) -> Server<E>
+    where
+        E: WebSocketExtension,
+        F: Fn() -> WebSocketConfig<E> + Send + Sync + 'static,
+    {
+        Server {
+            listener: self.listener,
+            config: Some(Arc::new(config)),
+            handshake_timeout: self.handshake_timeout,
+            thread_per_connection: self.thread_per_connection,
+        }
+    }
+
+    /// Give each client at most `timeout` to complete the handshake, see
+    /// `accept_hdr_with_timeout`. Without a timeout a client which never sends its request
+    /// holds up the server, or its thread, indefinitely.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Do the handshake and run the handler for each connection on a thread of its own,
+    /// instead of one connection after another on the thread calling `serve`.
+    pub fn thread_per_connection(mut self, enabled: bool) -> Self {
+        self.thread_per_connection = enabled;
+        self
+    }
+
+    /// Returns the local address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections forever, passing each WebSocket to `handler`.
+    pub fn serve<F>(self, handler: F) -> !
+    where
+        Ext: Send + 'static,
+        F: Fn(WebSocket<TcpStream, Ext>, ConnectionInfo) + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        loop {
+            let (stream, peer_addr) = match self.listener.accept() {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let config = self.config.as_ref().map(|config| config());
+            let timeout = self.handshake_timeout;
+            if self.thread_per_connection {
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    handle(stream, peer_addr, config, timeout, &*handler);
+                });
+            } else {
+                handle(stream, peer_addr, config, timeout, &*handler);
+            }
+        }
+    }
+}
+
+/// Do the handshake on a connection and pass the WebSocket to `handler`.
+fn handle<Ext, F>(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    config: Option<WebSocketConfig<Ext>>,
+    timeout: Option<Duration>,
+    handler: &F,
+) where
+    Ext: WebSocketExtension,
+    F: Fn(WebSocket<TcpStream, Ext>, ConnectionInfo),
+{
+    let result = match timeout {
+        Some(timeout) => accept_hdr_with_timeout(stream, NoCallback, config, timeout),
+        None => accept_hdr_with_config(stream, NoCallback, config),
+    };
+    match result {
+        Ok(websocket) => {
+            let info = ConnectionInfo {
+                peer_addr: Some(peer_addr),
+            };
+            handler(websocket, info)
+        }
+        Err(e) => debug!("Handshake with {} failed: {}", peer_addr, e),
+    }
+}
@@ -0,0 +1,205 @@
+//! The PROXY protocol, versions 1 and 2, used by load balancers such as HAProxy to pass on the
+//! address of the client.
+
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+use log::*;
+
+use crate::error::{Error, Result};
+
+/// The signature starting a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a version 1 header, including the final CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// The addresses of a connection passed on by a proxy in a PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The address of the client, `None` if the proxy did not tell, e.g. for its own health
+    /// checks or for address families other than IPv4 and IPv6.
+    pub source: Option<SocketAddr>,
+    /// The address the client connected to, `None` if the proxy did not tell.
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// A header without addresses.
+    fn unknown() -> Self {
+        ProxyHeader {
+            source: None,
+            destination: None,
+        }
+    }
+}
+
+/// Read the PROXY protocol header, version 1 or 2, from the start of the stream.
+///
+/// The header is required: a connection without one fails with `Error::Protocol`, as a client
+/// must not be able to pass itself off as a proxy. Exactly the header is read, so the stream
+/// can be passed on to `accept()` and friends afterwards. The stream has to be blocking.
+pub fn read_proxy_header<S: Read>(stream: &mut S) -> Result<ProxyHeader> {
+    // Both versions are longer than the version 2 signature, so read that much to tell them
+    // apart without reading past the header.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start)?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        let mut byte = [0];
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(Error::Protocol("PROXY protocol header too long".into()));
+            }
+            stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(Error::Protocol("No PROXY protocol header".into()))
+    }
+}
+
+/// Parse a version 1 header, without the final CRLF.
+fn parse_v1(line: &[u8]) -> Result<ProxyHeader> {
+    let invalid = || Error::Protocol("Invalid PROXY protocol header".into());
+    let line = str::from_utf8(line).map_err(|_| invalid())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::unknown()),
+        ["PROXY", family @ "TCP4", source, destination, source_port, destination_port]
+        | ["PROXY", family @ "TCP6", source, destination, source_port, destination_port] => {
+            let address = |ip: &str, port: &str| -> Result<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(invalid());
+                }
+                Ok(SocketAddr::new(ip, port.parse().map_err(|_| invalid())?))
+            };
+            let header = ProxyHeader {
+                source: Some(address(source, source_port)?),
+                destination: Some(address(destination, destination_port)?),
+            };
+            debug!("PROXY protocol v1 header: {:?}", header);
+            Ok(header)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Read a version 2 header, after the signature.
+fn read_v2<S: Read>(stream: &mut S) -> Result<ProxyHeader> {
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed)?;
+    let [version_command, family, length @ ..] = fixed;
+    let mut payload = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut payload)?;
+
+    if version_command >> 4 != 2 {
+        return Err(Error::Protocol("Unsupported PROXY protocol version".into()));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the connection was made by the proxy itself.
+        0 => return Ok(ProxyHeader::unknown()),
+        1 => {}
+        _ => return Err(Error::Protocol("Invalid PROXY protocol command".into())),
+    }
+
+    let too_short = || Error::Protocol("PROXY protocol header too short".into());
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    // The addresses may be followed by TLVs, which are ignored.
+    let header = match family >> 4 {
+        // AF_INET
+        1 => {
+            let p = payload.get(..12).ok_or_else(too_short)?;
+            let ip = |bytes: &[u8]| Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+            ProxyHeader {
+                source: Some(SocketAddr::new(ip(&p[0..4]).into(), port(&p[8..10]))),
+                destination: Some(SocketAddr::new(ip(&p[4..8]).into(), port(&p[10..12]))),
+            }
+        }
+        // AF_INET6
+        2 => {
+            let p = payload.get(..36).ok_or_else(too_short)?;
+            let ip = |bytes: &[u8]| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+                Ipv6Addr::from(octets)
+            };
+            ProxyHeader {
+                source: Some(SocketAddr::new(ip(&p[0..16]).into(), port(&p[32..34]))),
+                destination: Some(SocketAddr::new(ip(&p[16..32]).into(), port(&p[34..36]))),
+            }
+        }
+        // AF_UNSPEC, AF_UNIX
+        _ => ProxyHeader::unknown(),
+    };
+    debug!("PROXY protocol v2 header: {:?}", header);
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_proxy_header, ProxyHeader};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn version_1() {
+        let mut stream = Cursor::new(
+            &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET / HTTP/1.1\r\n"[..],
+        );
+        let header = read_proxy_header(&mut stream).unwrap();
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("192.168.0.11:443".parse().unwrap())
+        );
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        let mut stream = Cursor::new(&b"PROXY TCP6 ::1 ::2 1 2\r\n"[..]);
+        let header = read_proxy_header(&mut stream).unwrap();
+        assert_eq!(header.source, Some("[::1]:1".parse().unwrap()));
+
+        let mut stream = Cursor::new(&b"PROXY UNKNOWN\r\n"[..]);
+        assert_eq!(
+            read_proxy_header(&mut stream).unwrap(),
+            ProxyHeader {
+                source: None,
+                destination: None
+            }
+        );
+
+        let mut stream = Cursor::new(&b"PROXY TCP4 ::1 ::2 1 2\r\n"[..]);
+        assert!(read_proxy_header(&mut stream).is_err());
+        let mut stream = Cursor::new(&b"GET / HTTP/1.1\r\nHost: foo.com\r\n\r\n"[..]);
+        assert!(read_proxy_header(&mut stream).is_err());
+    }
+
+    #[test]
+    fn version_2() {
+        let mut data = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0f".to_vec();
+        data.extend_from_slice(&[192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x01, 0xbb]);
+        // A TLV to be skipped.
+        data.extend_from_slice(&[0x04, 0x00, 0x00]);
+        data.extend_from_slice(b"GET");
+        let mut stream = Cursor::new(data);
+        let header = read_proxy_header(&mut stream).unwrap();
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("192.168.0.11:443".parse().unwrap())
+        );
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET");
+
+        // LOCAL command.
+        let mut stream = Cursor::new(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00".to_vec());
+        assert_eq!(read_proxy_header(&mut stream).unwrap().source, None);
+    }
+}
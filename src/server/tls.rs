@@ -0,0 +1,125 @@
+//! Accepting WebSocket connections over TLS in one call.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+#[cfg(feature = "__rustls-tls")]
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::extensions::WebSocketExtension;
+use crate::handshake::server::{NoCallback, ServerHandshake};
+use crate::handshake::HandshakeError;
+use crate::protocol::{WebSocket, WebSocketConfig};
+use crate::stream::TlsStream;
+
+use super::accept_with_config;
+
+/// A configured TLS acceptor for `accept_tls`.
+///
+/// There is a variant for each TLS backend enabled with the cargo features, so matching on
+/// them needs a wildcard.
+#[non_exhaustive]
+#[derive(Clone)]
+pub enum Acceptor {
+    /// A `native_tls` acceptor.
+    #[cfg(feature = "tls")]
+    NativeTls(native_tls::TlsAcceptor),
+    /// A `rustls` server configuration.
+    #[cfg(feature = "__rustls-tls")]
+    Rustls(Arc<rustls::ServerConfig>),
+    /// An `openssl` acceptor.
+    #[cfg(feature = "openssl-tls")]
+    OpenSsl(openssl::ssl::SslAcceptor),
+}
+
+impl fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "tls")]
+            Acceptor::NativeTls(_) => f.write_str("Acceptor::NativeTls(..)"),
+            #[cfg(feature = "__rustls-tls")]
+            Acceptor::Rustls(_) => f.write_str("Acceptor::Rustls(..)"),
+            #[cfg(feature = "openssl-tls")]
+            Acceptor::OpenSsl(_) => f.write_str("Acceptor::OpenSsl(..)"),
+        }
+    }
+}
+
+/// The error for a TLS handshake which did not complete because the stream is non-blocking.
+fn tls_would_block() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::WouldBlock,
+        "TLS handshake interrupted, the stream has to be blocking",
+    ))
+}
+
+/// Do the TLS handshake on `stream` with `acceptor`.
+fn tls_handshake<S: Read + Write>(stream: S, acceptor: &Acceptor) -> Result<TlsStream<S>, Error> {
+    match *acceptor {
+        #[cfg(feature = "tls")]
+        Acceptor::NativeTls(ref acceptor) => match acceptor.accept(stream) {
+            Ok(stream) => Ok(TlsStream::NativeTls(stream)),
+            Err(native_tls::HandshakeError::Failure(e)) => Err(Error::Tls(e)),
+            Err(native_tls::HandshakeError::WouldBlock(_)) => Err(tls_would_block()),
+        },
+        #[cfg(feature = "__rustls-tls")]
+        Acceptor::Rustls(ref config) => {
+            let connection = rustls::ServerConnection::new(config.clone())?;
+            let mut stream = rustls::StreamOwned::new(connection, stream);
+            match crate::stream::complete_rustls_handshake(&mut *stream.conn, &mut stream.sock) {
+                Ok(()) => Ok(TlsStream::RustlsServer(Box::new(stream))),
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    Err(tls_would_block())
+                }
+                Err(e) => Err(e),
+            }
+        }
+        #[cfg(feature = "openssl-tls")]
+        Acceptor::OpenSsl(ref acceptor) => match acceptor.accept(stream) {
+            Ok(stream) => Ok(TlsStream::OpenSsl(stream)),
+            Err(openssl::ssl::HandshakeError::SetupFailure(e)) => Err(e.into()),
+            Err(openssl::ssl::HandshakeError::Failure(mid)) => Err(mid.into_error().into()),
+            Err(openssl::ssl::HandshakeError::WouldBlock(_)) => Err(tls_would_block()),
+        },
+    }
+}
+
+/// Accept the given stream as a WebSocket over TLS, doing the TLS handshake with `acceptor`
+/// first.
+///
+/// A failed TLS handshake is returned as the error of the backend, e.g. `Error::Tls` for
+/// native-tls, so both handshakes fail the same way. The stream has to be blocking. Passing
+/// `None` as configuration uses the default one.
+///
+/// ```no_run
+/// # #[cfg(feature = "tls")]
+/// # {
+/// use std::net::TcpListener;
+/// use native_tls::{Identity, TlsAcceptor};
+/// use tungstenite::extensions::uncompressed::UncompressedExt;
+/// use tungstenite::server::{accept_tls, Acceptor};
+///
+/// let identity = Identity::from_pkcs12(&std::fs::read("identity.p12").unwrap(), "").unwrap();
+/// let acceptor = Acceptor::NativeTls(TlsAcceptor::new(identity).unwrap());
+/// let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+/// for stream in server.incoming() {
+///     let websocket = accept_tls::<_, UncompressedExt>(stream.unwrap(), &acceptor, None);
+///     // ...
+/// }
+/// # }
+/// ```
+pub fn accept_tls<S, Ext>(
+    stream: S,
+    acceptor: &Acceptor,
+    config: Option<WebSocketConfig<Ext>>,
+) -> Result<
+    WebSocket<TlsStream<S>, Ext>,
+    HandshakeError<ServerHandshake<TlsStream<S>, NoCallback, Ext>>,
+>
+where
+    S: Read + Write,
+    Ext: WebSocketExtension,
+{
+    let stream = tls_handshake(stream, acceptor)?;
+    accept_with_config(stream, config)
+}
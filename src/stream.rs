@@ -1,15 +1,20 @@
 //! Convenience wrapper for streams to switch between plain TCP and TLS at runtime.
 //!
 //!  There is no dependency on actual TLS implementations. Everything like
-//! `native_tls` or `openssl` will work as long as there is a TLS stream supporting standard
-//! `Read + Write` traits.
+//! `native_tls`, `rustls` or `openssl` will work as long as there is a TLS stream supporting
+//! standard `Read + Write` traits.
 
 use std::io::{Read, Result as IoResult, Write};
 
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
 
 #[cfg(feature = "tls")]
-use native_tls::TlsStream;
+use native_tls::TlsStream as NativeTlsStream;
+#[cfg(feature = "openssl-tls")]
+use openssl::ssl::SslStream;
+#[cfg(feature = "__rustls-tls")]
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
 
 /// Stream mode, either plain TCP or TLS.
 #[derive(Clone, Copy, Debug)]
@@ -33,12 +38,298 @@ impl NoDelay for TcpStream {
 }
 
 #[cfg(feature = "tls")]
-impl<S: Read + Write + NoDelay> NoDelay for TlsStream<S> {
+impl<S: Read + Write + NoDelay> NoDelay for NativeTlsStream<S> {
+    fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()> {
+        self.get_mut().set_nodelay(nodelay)
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<C, S: Read + Write + NoDelay> NoDelay for StreamOwned<C, S> {
+    fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()> {
+        self.sock.set_nodelay(nodelay)
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl<S: Read + Write + NoDelay> NoDelay for SslStream<S> {
     fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()> {
         self.get_mut().set_nodelay(nodelay)
     }
 }
 
+/// Trait to limit the time blocking reads and writes may take.
+pub trait SetTimeout {
+    /// Set the read and write timeouts to the given value. `None` means blocking indefinitely.
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()>;
+}
+
+impl SetTimeout for TcpStream {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Read + Write + SetTimeout> SetTimeout for NativeTlsStream<S> {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        self.get_mut().set_timeout(timeout)
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<C, S: Read + Write + SetTimeout> SetTimeout for StreamOwned<C, S> {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        self.sock.set_timeout(timeout)
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl<S: Read + Write + SetTimeout> SetTimeout for SslStream<S> {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        self.get_mut().set_timeout(timeout)
+    }
+}
+
+/// Trait to get the address of the remote peer of a connection.
+pub trait PeerAddr {
+    /// Returns the socket address of the remote peer.
+    fn peer_addr(&self) -> IoResult<SocketAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Read + Write + PeerAddr> PeerAddr for NativeTlsStream<S> {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<C, S: Read + Write + PeerAddr> PeerAddr for StreamOwned<C, S> {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.sock.peer_addr()
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl<S: Read + Write + PeerAddr> PeerAddr for SslStream<S> {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+}
+
+/// Trait to get the application protocol negotiated with ALPN during the TLS handshake.
+pub trait NegotiatedAlpn {
+    /// Returns the protocol the peers agreed on, `None` if ALPN was not used or the stream is
+    /// not encrypted.
+    fn negotiated_alpn(&self) -> Option<Vec<u8>>;
+}
+
+impl NegotiatedAlpn for TcpStream {
+    fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Read + Write> NegotiatedAlpn for NativeTlsStream<S> {
+    fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        NativeTlsStream::negotiated_alpn(self).ok().flatten()
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<S: Read + Write> NegotiatedAlpn for StreamOwned<ClientConnection, S> {
+    fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        self.conn.alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<S: Read + Write> NegotiatedAlpn for StreamOwned<ServerConnection, S> {
+    fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        self.conn.alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl<S: Read + Write> NegotiatedAlpn for SslStream<S> {
+    fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        self.ssl().selected_alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+/// Trait to get the certificates the peer presented during the TLS handshake.
+pub trait PeerCertificates {
+    /// Returns the DER-encoded certificates of the peer, its own first, or none if the stream
+    /// is not encrypted. With `native-tls` only the certificate of the peer itself is available,
+    /// without the rest of the chain.
+    fn peer_certificates(&self) -> Vec<Vec<u8>>;
+}
+
+impl PeerCertificates for TcpStream {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Read + Write> PeerCertificates for NativeTlsStream<S> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        match self.peer_certificate() {
+            Ok(Some(certificate)) => certificate.to_der().into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<S: Read + Write> PeerCertificates for StreamOwned<ClientConnection, S> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        let certificates = self.conn.peer_certificates().unwrap_or_default();
+        certificates.iter().map(|c| c.to_vec()).collect()
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<S: Read + Write> PeerCertificates for StreamOwned<ServerConnection, S> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        let certificates = self.conn.peer_certificates().unwrap_or_default();
+        certificates.iter().map(|c| c.to_vec()).collect()
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl<S: Read + Write> PeerCertificates for SslStream<S> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        // The chain includes the certificate of the peer on the client side only.
+        let mut certificates: Vec<Vec<u8>> = self
+            .ssl()
+            .peer_certificate()
+            .and_then(|certificate| certificate.to_der().ok())
+            .into_iter()
+            .collect();
+        for certificate in self.ssl().peer_cert_chain().into_iter().flatten() {
+            if let Ok(der) = certificate.to_der() {
+                if certificates.first() != Some(&der) {
+                    certificates.push(der);
+                }
+            }
+        }
+        certificates
+    }
+}
+
+/// Details of an established TLS session, e.g. for audit logging.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsSessionInfo {
+    /// The protocol version, e.g. `TLSv1.3`. `None` with `native-tls`, which does not tell.
+    pub protocol_version: Option<String>,
+    /// The cipher suite, named the way the backend names it, e.g. `TLS13_AES_128_GCM_SHA256`
+    /// with rustls. `None` with `native-tls`, which does not tell.
+    pub cipher_suite: Option<String>,
+    /// The certificates of the peer, see `PeerCertificates`.
+    pub peer_certificates: Vec<Vec<u8>>,
+    /// The protocol negotiated with ALPN, see `NegotiatedAlpn`.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+/// Trait to get the details of the TLS session of a connection.
+pub trait TlsSession {
+    /// Returns the details of the TLS session, `None` if the stream is not encrypted.
+    fn tls_session(&self) -> Option<TlsSessionInfo>;
+}
+
+impl TlsSession for TcpStream {
+    fn tls_session(&self) -> Option<TlsSessionInfo> {
+        None
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Read + Write> TlsSession for NativeTlsStream<S> {
+    fn tls_session(&self) -> Option<TlsSessionInfo> {
+        Some(TlsSessionInfo {
+            peer_certificates: self.peer_certificates(),
+            alpn_protocol: NegotiatedAlpn::negotiated_alpn(self),
+            ..TlsSessionInfo::default()
+        })
+    }
+}
+
+/// The details of a rustls session.
+#[cfg(feature = "__rustls-tls")]
+fn rustls_session<D>(conn: &rustls::ConnectionCommon<D>) -> TlsSessionInfo {
+    TlsSessionInfo {
+        protocol_version: conn
+            .protocol_version()
+            .map(|v| format!("{:?}", v).replace('_', ".")),
+        cipher_suite: conn
+            .negotiated_cipher_suite()
+            .map(|s| format!("{:?}", s.suite())),
+        peer_certificates: conn
+            .peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .map(|c| c.to_vec())
+            .collect(),
+        alpn_protocol: conn.alpn_protocol().map(<[u8]>::to_vec),
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<S: Read + Write> TlsSession for StreamOwned<ClientConnection, S> {
+    fn tls_session(&self) -> Option<TlsSessionInfo> {
+        Some(rustls_session(&self.conn))
+    }
+}
+
+#[cfg(feature = "__rustls-tls")]
+impl<S: Read + Write> TlsSession for StreamOwned<ServerConnection, S> {
+    fn tls_session(&self) -> Option<TlsSessionInfo> {
+        Some(rustls_session(&self.conn))
+    }
+}
+
+#[cfg(feature = "openssl-tls")]
+impl<S: Read + Write> TlsSession for SslStream<S> {
+    fn tls_session(&self) -> Option<TlsSessionInfo> {
+        Some(TlsSessionInfo {
+            protocol_version: Some(self.ssl().version_str().to_owned()),
+            cipher_suite: self.ssl().current_cipher().map(|c| c.name().to_owned()),
+            peer_certificates: self.peer_certificates(),
+            alpn_protocol: self.negotiated_alpn(),
+        })
+    }
+}
+
+/// Complete the handshake of a rustls connection, blocking until it is done.
+///
+/// rustls reports its own errors wrapped in I/O errors; these are returned as `Error::Rustls`.
+#[cfg(feature = "__rustls-tls")]
+pub(crate) fn complete_rustls_handshake<D, S: Read + Write>(
+    conn: &mut rustls::ConnectionCommon<D>,
+    sock: &mut S,
+) -> crate::error::Result<()> {
+    while conn.is_handshaking() {
+        conn.complete_io(sock).map_err(|e| {
+            let rustls_error = e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>());
+            match rustls_error {
+                Some(rustls_error) => crate::error::Error::Rustls(rustls_error.clone()),
+                None => crate::error::Error::Io(e),
+            }
+        })?;
+    }
+    Ok(())
+}
+
 /// Stream, either plain TCP or TLS.
 #[derive(Debug)]
 pub enum Stream<S, T> {
@@ -80,3 +371,213 @@ impl<S: NoDelay, T: NoDelay> NoDelay for Stream<S, T> {
         }
     }
 }
+
+impl<S: SetTimeout, T: SetTimeout> SetTimeout for Stream<S, T> {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.set_timeout(timeout),
+            Stream::Tls(ref mut s) => s.set_timeout(timeout),
+        }
+    }
+}
+
+impl<S: PeerAddr, T: PeerAddr> PeerAddr for Stream<S, T> {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        match *self {
+            Stream::Plain(ref s) => s.peer_addr(),
+            Stream::Tls(ref s) => s.peer_addr(),
+        }
+    }
+}
+
+impl<S: NegotiatedAlpn, T: NegotiatedAlpn> NegotiatedAlpn for Stream<S, T> {
+    fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        match *self {
+            Stream::Plain(ref s) => s.negotiated_alpn(),
+            Stream::Tls(ref s) => s.negotiated_alpn(),
+        }
+    }
+}
+
+impl<S: PeerCertificates, T: PeerCertificates> PeerCertificates for Stream<S, T> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        match *self {
+            Stream::Plain(ref s) => s.peer_certificates(),
+            Stream::Tls(ref s) => s.peer_certificates(),
+        }
+    }
+}
+
+impl<S: TlsSession, T: TlsSession> TlsSession for Stream<S, T> {
+    fn tls_session(&self) -> Option<TlsSessionInfo> {
+        match *self {
+            Stream::Plain(ref s) => s.tls_session(),
+            Stream::Tls(ref s) => s.tls_session(),
+        }
+    }
+}
+
+/// A TLS stream of any of the enabled backends, as used by `client::connect()` and
+/// `server::accept_tls()`.
+///
+/// The variants depend on the enabled cargo features, so matching on them needs a wildcard.
+/// The traits of this module and `get_ref()`/`get_mut()` work the same for all of them.
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum TlsStream<S: Read + Write> {
+    /// A `native-tls` stream.
+    #[cfg(feature = "tls")]
+    NativeTls(NativeTlsStream<S>),
+    /// A `rustls` stream of a client.
+    #[cfg(feature = "__rustls-tls")]
+    RustlsClient(Box<StreamOwned<ClientConnection, S>>),
+    /// A `rustls` stream of a server.
+    #[cfg(feature = "__rustls-tls")]
+    RustlsServer(Box<StreamOwned<ServerConnection, S>>),
+    /// An `openssl` stream.
+    #[cfg(feature = "openssl-tls")]
+    OpenSsl(SslStream<S>),
+}
+
+/// Evaluate `$body` with `$s` bound to the stream of whichever backend `$stream` uses.
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+macro_rules! with_backend {
+    ($stream:expr, $s:ident => $body:expr) => {
+        match $stream {
+            #[cfg(feature = "tls")]
+            TlsStream::NativeTls($s) => $body,
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsClient($s) => $body,
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsServer($s) => $body,
+            #[cfg(feature = "openssl-tls")]
+            TlsStream::OpenSsl($s) => $body,
+        }
+    };
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write> TlsStream<S> {
+    /// Returns a shared reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        match self {
+            #[cfg(feature = "tls")]
+            TlsStream::NativeTls(s) => s.get_ref(),
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsClient(s) => &s.sock,
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsServer(s) => &s.sock,
+            #[cfg(feature = "openssl-tls")]
+            TlsStream::OpenSsl(s) => s.get_ref(),
+        }
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    ///
+    /// Reading from or writing to it directly corrupts the TLS session.
+    pub fn get_mut(&mut self) -> &mut S {
+        match self {
+            #[cfg(feature = "tls")]
+            TlsStream::NativeTls(s) => s.get_mut(),
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsClient(s) => &mut s.sock,
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsServer(s) => &mut s.sock,
+            #[cfg(feature = "openssl-tls")]
+            TlsStream::OpenSsl(s) => s.get_mut(),
+        }
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        with_backend!(self, s => s.read(buf))
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        with_backend!(self, s => s.write(buf))
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        with_backend!(self, s => s.flush())
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write + NoDelay> NoDelay for TlsStream<S> {
+    fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()> {
+        with_backend!(self, s => s.set_nodelay(nodelay))
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write + SetTimeout> SetTimeout for TlsStream<S> {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        with_backend!(self, s => s.set_timeout(timeout))
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write + PeerAddr> PeerAddr for TlsStream<S> {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        with_backend!(self, s => s.peer_addr())
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write> NegotiatedAlpn for TlsStream<S> {
+    fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        match self {
+            // `native_tls::TlsStream` has an inherent method of the same name.
+            #[cfg(feature = "tls")]
+            TlsStream::NativeTls(s) => NegotiatedAlpn::negotiated_alpn(s),
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsClient(s) => s.negotiated_alpn(),
+            #[cfg(feature = "__rustls-tls")]
+            TlsStream::RustlsServer(s) => s.negotiated_alpn(),
+            #[cfg(feature = "openssl-tls")]
+            TlsStream::OpenSsl(s) => s.negotiated_alpn(),
+        }
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write> PeerCertificates for TlsStream<S> {
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        with_backend!(self, s => s.peer_certificates())
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write> TlsSession for TlsStream<S> {
+    fn tls_session(&self) -> Option<TlsSessionInfo> {
+        with_backend!(self, s => s.tls_session())
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+impl<S: Read + Write> Stream<S, TlsStream<S>> {
+    /// Returns a shared reference to the underlying stream, e.g. the `TcpStream`, whether TLS
+    /// is used or not.
+    pub fn get_ref(&self) -> &S {
+        match *self {
+            Stream::Plain(ref s) => s,
+            Stream::Tls(ref s) => s.get_ref(),
+        }
+    }
+
+    /// Returns a mutable reference to the underlying stream, e.g. the `TcpStream`, whether TLS
+    /// is used or not.
+    ///
+    /// Reading from or writing to it directly corrupts the TLS session.
+    pub fn get_mut(&mut self) -> &mut S {
+        match *self {
+            Stream::Plain(ref mut s) => s,
+            Stream::Tls(ref mut s) => s.get_mut(),
+        }
+    }
+}
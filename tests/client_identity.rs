@@ -0,0 +1,44 @@
+//! Verifies that a client identity is loaded before connecting over TLS.
+
+#![cfg(feature = "tls")]
+
+use std::io;
+use std::net::TcpListener;
+
+use tungstenite::client::{connect_with_options, ClientIdentity, ConnectOptions};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::Error;
+
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+#[test]
+fn test_invalid_client_identity() {
+    let options = ConnectOptions {
+        identity: Some(ClientIdentity::from_pem(CERT, b"not a key")),
+        ..ConnectOptions::default()
+    };
+    // Nothing listens on the port: the identity is rejected before connecting.
+    let url = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("wss://localhost:{}", listener.local_addr().unwrap().port())
+    };
+    let result = connect_with_options::<_, UncompressedExt>(&url, None, options);
+    match result {
+        Err(Error::Tls(_)) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unexpected success"),
+    }
+
+    // A valid identity gets as far as connecting, which is refused.
+    let options = ConnectOptions {
+        identity: Some(ClientIdentity::from_pem(CERT, KEY)),
+        ..ConnectOptions::default()
+    };
+    let result = connect_with_options::<_, UncompressedExt>(&url, None, options);
+    match result {
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unexpected success"),
+    }
+}
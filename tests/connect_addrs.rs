@@ -0,0 +1,43 @@
+//! Verifies that connecting to given addresses tries them in order without resolving names.
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::connect_addrs;
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::{accept_hdr, Message};
+
+#[test]
+fn test_connect_addrs() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    // Nothing listens at the first address, so the connection is refused.
+    let refused = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addrs = [refused, server.local_addr().unwrap()];
+    let server_thread = spawn(move || {
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| {
+            assert_eq!(request.headers()["Host"], "service.invalid");
+            Ok(response)
+        };
+        let mut socket = accept_hdr(server.incoming().next().unwrap().unwrap(), callback).unwrap();
+        socket.write_message(Message::text("hello")).unwrap();
+    });
+
+    let (mut client, _) =
+        connect_addrs::<_, UncompressedExt>(&addrs, "ws://service.invalid/socket", None).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("hello"));
+
+    server_thread.join().unwrap();
+}
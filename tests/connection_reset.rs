@@ -6,13 +6,13 @@ use std::process::exit;
 use std::thread::{sleep, spawn};
 use std::time::Duration;
 
-use native_tls::TlsStream;
 use net2::TcpStreamExt;
+use tungstenite::client::AutoStream;
 use tungstenite::extensions::uncompressed::UncompressedExt;
-use tungstenite::{accept, connect, stream::Stream, Error, Message, WebSocket};
+use tungstenite::{accept, connect, Error, Message, WebSocket};
 use url::Url;
 
-type Sock<Ext> = WebSocket<Stream<TcpStream, TlsStream<TcpStream>>, Ext>;
+type Sock<Ext> = WebSocket<AutoStream, Ext>;
 
 fn do_test<CT, ST>(port: u16, client_task: CT, server_task: ST)
 where
@@ -110,10 +110,7 @@ fn test_evil_server_close() {
             let message = srv_sock.read_message().unwrap(); // receive acknowledgement
             assert!(message.is_close());
             // and now just drop the connection without waiting for `ConnectionClosed`
-            srv_sock
-                .get_mut()
-                .set_linger(Some(Duration::from_secs(0)))
-                .unwrap();
+            TcpStreamExt::set_linger(srv_sock.get_mut(), Some(Duration::from_secs(0))).unwrap();
             drop(srv_sock);
         },
     );
@@ -0,0 +1,51 @@
+//! Verifies that the endpoints are tried in order until one of them accepts the connection.
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::{connect_failover, ConnectOptions};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::{accept, Message};
+
+#[test]
+fn test_failover() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    // Nothing listens at the primary endpoint, so the connection is refused.
+    let primary = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("ws://{}/feed", listener.local_addr().unwrap())
+    };
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let secondary = format!("ws://{}/feed", server.local_addr().unwrap());
+    let server_thread = spawn(move || {
+        let mut socket = accept(server.incoming().next().unwrap().unwrap()).unwrap();
+        socket.write_message(Message::text("hello")).unwrap();
+    });
+
+    let endpoints = vec![primary, secondary];
+    let options = ConnectOptions {
+        connect_timeout: Some(Duration::from_secs(1)),
+        ..ConnectOptions::default()
+    };
+    let (mut client, _, index) =
+        connect_failover::<_, UncompressedExt, _>(endpoints, || None, options).unwrap();
+    assert_eq!(index, 1);
+    assert_eq!(client.read_message().unwrap(), Message::text("hello"));
+
+    server_thread.join().unwrap();
+
+    let no_endpoints: Vec<String> = Vec::new();
+    assert!(connect_failover::<_, UncompressedExt, _>(
+        no_endpoints,
+        || None,
+        ConnectOptions::default()
+    )
+    .is_err());
+}
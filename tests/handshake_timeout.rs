@@ -0,0 +1,75 @@
+//! Verifies that a client handshake with a server that never answers times out.
+
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use tungstenite::client::{connect_with_options, ConnectOptions};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::Error;
+
+#[test]
+fn test_handshake_timeout() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, the handshake did not time out");
+        exit(1);
+    });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+
+    let options = ConnectOptions {
+        handshake_timeout: Some(Duration::from_millis(200)),
+        ..ConnectOptions::default()
+    };
+    let start = Instant::now();
+    let result = connect_with_options::<_, UncompressedExt>(
+        format!("ws://localhost:{}/socket", port),
+        None,
+        options,
+    );
+    match result {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unexpected success"),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    drop(server);
+}
+
+#[cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+#[test]
+fn test_tls_handshake_timeout() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, the TLS handshake did not time out");
+        exit(1);
+    });
+
+    // The server accepts the connection but never answers the ClientHello.
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+
+    let options = ConnectOptions {
+        handshake_timeout: Some(Duration::from_millis(200)),
+        ..ConnectOptions::default()
+    };
+    let start = Instant::now();
+    let result = connect_with_options::<_, UncompressedExt>(
+        format!("wss://127.0.0.1:{}/socket", port),
+        None,
+        options,
+    );
+    match result {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unexpected success"),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    drop(server);
+}
@@ -0,0 +1,36 @@
+//! Verifies that clients can connect to IPv6 literal addresses.
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::{accept, connect, Message};
+
+#[test]
+fn test_ipv6_literal() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let server = match TcpListener::bind("[::1]:0") {
+        Ok(server) => server,
+        Err(e) => {
+            println!("IPv6 is not available, skipping: {}", e);
+            return;
+        }
+    };
+
+    let url = format!("ws://{}/socket", server.local_addr().unwrap());
+    let server_thread = spawn(move || {
+        let mut socket = accept(server.incoming().next().unwrap().unwrap()).unwrap();
+        socket.write_message(Message::text("hello")).unwrap();
+    });
+
+    let (mut client, _) = connect(url).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("hello"));
+
+    server_thread.join().unwrap();
+}
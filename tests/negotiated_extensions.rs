@@ -0,0 +1,69 @@
+//! Verifies that both ends of a connection can tell which extensions were negotiated.
+
+#![cfg(feature = "__deflate")]
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::connect_with_config;
+use tungstenite::extensions::deflate::{DeflateConfig, DeflateConfigBuilder, DeflateExt};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::extensions::WebSocketExtension;
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::server::accept_with_config;
+
+#[test]
+fn negotiated_extensions() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://localhost:{}", server.local_addr().unwrap().port());
+    let server_thread = spawn(move || {
+        let stream = server.incoming().next().unwrap().unwrap();
+        let config = DeflateConfig::default();
+        let websocket =
+            accept_with_config(stream, Some(WebSocketConfig::with_deflate(config.clone())))
+                .unwrap();
+        assert!(websocket.extension().enabled());
+        assert!(websocket.extension().config().compress_reset());
+        let extensions = websocket.negotiated_extensions();
+        assert_eq!(extensions.len(), 1);
+        assert!(extensions[0].is("permessage-deflate"));
+        assert_eq!(
+            extensions[0].param("server_no_context_takeover"),
+            Some(None)
+        );
+
+        // Without compression on the client side, nothing is negotiated.
+        let stream = server.incoming().next().unwrap().unwrap();
+        let websocket =
+            accept_with_config(stream, Some(WebSocketConfig::with_deflate(config))).unwrap();
+        assert!(!websocket.extension().enabled());
+        assert!(websocket.negotiated_extensions().is_empty());
+    });
+
+    let config = DeflateConfigBuilder::default()
+        .request_no_context_takeover(true)
+        .build();
+    let config = WebSocketConfig::default_with_encoder(DeflateExt::new(config));
+    let (websocket, _) = connect_with_config(&url, Some(config)).unwrap();
+    assert!(websocket.extension().enabled());
+    assert!(websocket.extension().config().decompress_reset());
+    assert_eq!(
+        websocket.negotiated_extensions()[0].name,
+        "permessage-deflate"
+    );
+    drop(websocket);
+
+    let (websocket, _) = connect_with_config::<_, UncompressedExt>(&url, None).unwrap();
+    assert!(websocket.negotiated_extensions().is_empty());
+    drop(websocket);
+
+    server_thread.join().unwrap();
+}
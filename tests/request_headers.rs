@@ -0,0 +1,42 @@
+//! Verifies that the client's handshake request stays available on the accepted socket.
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::IntoClientRequest;
+use tungstenite::{accept, connect, Message};
+
+#[test]
+fn test_request_headers() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/socket?room=1", server.local_addr().unwrap());
+    let server_thread = spawn(move || {
+        let mut socket = accept(server.incoming().next().unwrap().unwrap()).unwrap();
+        {
+            let request = socket.request().expect("No handshake request");
+            assert_eq!(request.uri().path(), "/socket");
+            assert_eq!(request.uri().query(), Some("room=1"));
+            assert_eq!(request.headers()["authorization"], "Bearer token");
+            assert_eq!(request.headers()["user-agent"], "request-headers-test");
+        }
+        socket.write_message(Message::text("hello")).unwrap();
+    });
+
+    let mut request = url.into_client_request().unwrap();
+    let headers = request.headers_mut();
+    headers.insert("authorization", "Bearer token".parse().unwrap());
+    headers.insert("user-agent", "request-headers-test".parse().unwrap());
+    let (mut client, _) = connect(request).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("hello"));
+    assert!(client.request().is_none());
+
+    server_thread.join().unwrap();
+}
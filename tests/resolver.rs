@@ -0,0 +1,43 @@
+//! Verifies that host names are resolved with a custom resolver if one is given.
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::{connect_with_options, ConnectOptions, Resolver};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::{accept, Message};
+
+#[test]
+fn test_custom_resolver() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let server_thread = spawn(move || {
+        let mut socket = accept(server.incoming().next().unwrap().unwrap()).unwrap();
+        socket.write_message(Message::text("hello")).unwrap();
+    });
+
+    let options = ConnectOptions {
+        resolver: Some(Resolver::new(move |host, port| {
+            assert_eq!((host, port), ("service.invalid", 9999));
+            Ok(vec![addr])
+        })),
+        ..ConnectOptions::default()
+    };
+    let (mut client, _) = connect_with_options::<_, UncompressedExt>(
+        "ws://service.invalid:9999/socket",
+        None,
+        options,
+    )
+    .unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("hello"));
+
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,71 @@
+//! Verifies that a server handshake with a client that never sends its request times out.
+
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::process::exit;
+use std::sync::mpsc;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::HandshakeError;
+use tungstenite::server::accept_hdr_with_timeout;
+use tungstenite::Error;
+
+#[test]
+fn test_server_handshake_timeout() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, the handshake did not time out");
+        exit(1);
+    });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+
+    let stream = server.incoming().next().unwrap().unwrap();
+    let start = Instant::now();
+    let result = accept_hdr_with_timeout::<_, _, UncompressedExt>(
+        stream,
+        NoCallback,
+        None,
+        Duration::from_millis(200),
+    );
+    match result {
+        Err(HandshakeError::Failure(Error::Io(e))) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unexpected success"),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    drop(client);
+}
+
+#[test]
+fn test_server_handshake_timeout_observed() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, the handshake did not time out");
+        exit(1);
+    });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+
+    let stream = server.incoming().next().unwrap().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let result = ServerHandshake::<_, _, UncompressedExt>::start(stream, NoCallback, None)
+        .with_observer(move |report| {
+            let kind = match report.error {
+                Some(Error::Io(e)) => Some(e.kind()),
+                _ => None,
+            };
+            tx.send((report.status, kind)).unwrap();
+        })
+        .handshake_with_timeout(Duration::from_millis(200));
+    assert!(result.is_err());
+    assert_eq!(rx.try_recv().unwrap(), (None, Some(ErrorKind::TimedOut)));
+
+    drop(client);
+}
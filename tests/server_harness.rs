@@ -0,0 +1,45 @@
+//! Verifies that `Server` hands WebSockets and the addresses of the clients to the handler.
+
+use std::net::SocketAddr;
+use std::process::exit;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::server::Server;
+use tungstenite::{connect, Message};
+
+#[test]
+fn test_server_harness() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .thread_per_connection(true);
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel::<SocketAddr>();
+    let tx = Mutex::new(tx);
+    spawn(move || {
+        server.serve(move |mut websocket, info| {
+            tx.lock().unwrap().send(info.peer_addr.unwrap()).unwrap();
+            let message = websocket.read_message().unwrap();
+            websocket.write_message(message).unwrap();
+        })
+    });
+
+    let mut peer_addrs = Vec::new();
+    for _ in 0..2 {
+        let (mut client, _) = connect(&url).unwrap();
+        client.write_message(Message::text("Hello")).unwrap();
+        assert_eq!(client.read_message().unwrap(), Message::text("Hello"));
+        let peer_addr = rx.recv().unwrap();
+        assert!(peer_addr.ip().is_loopback());
+        peer_addrs.push(peer_addr);
+    }
+    assert_ne!(peer_addrs[0], peer_addrs[1]);
+}
@@ -0,0 +1,70 @@
+//! Verifies that `accept_tls` does the TLS and the WebSocket handshakes in one call.
+
+#![cfg(feature = "tls")]
+
+use std::net::{TcpListener, TcpStream};
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use tungstenite::client::{connect_tls_with_connector, Connector};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::handshake::HandshakeError;
+use tungstenite::server::{accept_tls, Acceptor};
+use tungstenite::stream::TlsSession;
+use tungstenite::{Error, Message};
+
+const CA: &[u8] = include_bytes!("tls/ca.crt");
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+#[test]
+fn test_server_tls() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let identity = Identity::from_pkcs8(CERT, KEY).unwrap();
+    let acceptor = Acceptor::NativeTls(TlsAcceptor::new(identity).unwrap());
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let server_thread = spawn(move || {
+        // The first client does not speak TLS at all.
+        let stream = server.incoming().next().unwrap().unwrap();
+        match accept_tls::<_, UncompressedExt>(stream, &acceptor, None) {
+            Err(HandshakeError::Failure(Error::Tls(_))) => {}
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        let stream = server.incoming().next().unwrap().unwrap();
+        let mut websocket = accept_tls::<_, UncompressedExt>(stream, &acceptor, None).unwrap();
+        let message = websocket.read_message().unwrap();
+        websocket.write_message(message).unwrap();
+    });
+
+    let mut plain = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    std::io::Write::write_all(&mut plain, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+    let connector = TlsConnector::builder()
+        .add_root_certificate(Certificate::from_pem(CA).unwrap())
+        .build()
+        .unwrap();
+    let (mut client, _) = connect_tls_with_connector(
+        format!("wss://localhost:{}", port),
+        Connector::NativeTls(connector),
+    )
+    .unwrap();
+    let tcp = client.get_ref().get_ref();
+    assert_eq!(tcp.peer_addr().unwrap().port(), port);
+    let session = client.get_ref().tls_session().unwrap();
+    let certificate = Certificate::from_pem(CERT).unwrap().to_der().unwrap();
+    assert_eq!(session.peer_certificates, vec![certificate]);
+    client.write_message(Message::text("Hello")).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("Hello"));
+
+    server_thread.join().unwrap();
+}
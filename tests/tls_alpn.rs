@@ -0,0 +1,60 @@
+//! Verifies that the protocol negotiated with ALPN can be read on both ends.
+
+#![cfg(feature = "tls")]
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use tungstenite::client::{connect_tls_with_connector, Connector};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::server::{accept_tls, Acceptor};
+use tungstenite::stream::NegotiatedAlpn;
+
+const CA: &[u8] = include_bytes!("tls/ca.crt");
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+#[test]
+fn test_tls_alpn() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let acceptor = TlsAcceptor::builder(Identity::from_pkcs8(CERT, KEY).unwrap())
+        .accept_alpn(&["http/1.1"])
+        .build()
+        .unwrap();
+    let acceptor = Acceptor::NativeTls(acceptor);
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let server_thread = spawn(move || {
+        let stream = server.incoming().next().unwrap().unwrap();
+        let websocket = accept_tls::<_, UncompressedExt>(stream, &acceptor, None).unwrap();
+        assert_eq!(
+            websocket.get_ref().negotiated_alpn(),
+            Some(b"http/1.1".to_vec())
+        );
+    });
+
+    let connector = TlsConnector::builder()
+        .add_root_certificate(Certificate::from_pem(CA).unwrap())
+        .request_alpns(&["h2", "http/1.1"])
+        .build()
+        .unwrap();
+    let (client, _) = connect_tls_with_connector(
+        format!("wss://localhost:{}", port),
+        Connector::NativeTls(connector),
+    )
+    .unwrap();
+    assert_eq!(
+        client.get_ref().negotiated_alpn(),
+        Some(b"http/1.1".to_vec())
+    );
+
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,167 @@
+//! Verifies that all TLS backends work the same way: with a connector trusting the test
+//! authority, and picked with `ConnectOptions::tls_backend`.
+
+#![cfg(any(feature = "tls", feature = "__rustls-tls", feature = "openssl-tls"))]
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::{connect_with_options, ConnectOptions, Connector, TlsBackend};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::server::{accept_tls, Acceptor};
+use tungstenite::stream::{TlsSession, TlsSessionInfo};
+use tungstenite::Message;
+
+const CA: &[u8] = include_bytes!("tls/ca.crt");
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+fn watchdog() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+}
+
+/// Echo a message over TLS, returning the session as seen by the client and by the server.
+fn echo(acceptor: Acceptor, options: ConnectOptions) -> (TlsSessionInfo, TlsSessionInfo) {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let server_thread = spawn(move || {
+        let stream = server.incoming().next().unwrap().unwrap();
+        let mut websocket = accept_tls::<_, UncompressedExt>(stream, &acceptor, None).unwrap();
+        let message = websocket.read_message().unwrap();
+        websocket.write_message(message).unwrap();
+        websocket.get_ref().tls_session().unwrap()
+    });
+
+    let url = format!("wss://localhost:{}", port);
+    let (mut client, _) = connect_with_options::<_, UncompressedExt>(url, None, options).unwrap();
+    client.write_message(Message::text("Hello")).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("Hello"));
+    let session = client.get_ref().tls_session().unwrap();
+    (session, server_thread.join().unwrap())
+}
+
+/// Options connecting with `backend` without verifying the certificate of the server.
+fn insecure(backend: TlsBackend) -> ConnectOptions {
+    ConnectOptions {
+        tls_backend: Some(backend),
+        danger_accept_invalid_certs: true,
+        ..ConnectOptions::default()
+    }
+}
+
+/// The DER encoding of the certificate of the server.
+fn server_certificate() -> Vec<u8> {
+    let pem = std::str::from_utf8(CERT).unwrap();
+    let base64: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    base64::decode(base64).unwrap()
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn native_tls() {
+    use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+
+    watchdog();
+    let acceptor = || {
+        let identity = Identity::from_pkcs8(CERT, KEY).unwrap();
+        Acceptor::NativeTls(TlsAcceptor::new(identity).unwrap())
+    };
+    let connector = TlsConnector::builder()
+        .add_root_certificate(Certificate::from_pem(CA).unwrap())
+        .build()
+        .unwrap();
+    let options = ConnectOptions {
+        connector: Some(Connector::NativeTls(connector)),
+        ..ConnectOptions::default()
+    };
+    let (client, server) = echo(acceptor(), options);
+    assert_eq!(client.peer_certificates, vec![server_certificate()]);
+    assert!(server.peer_certificates.is_empty());
+
+    echo(acceptor(), insecure(TlsBackend::NativeTls));
+}
+
+#[cfg(feature = "__rustls-tls")]
+#[test]
+fn rustls() {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use std::sync::Arc;
+
+    watchdog();
+    let provider = || Arc::new(rustls::crypto::ring::default_provider());
+    let acceptor = || {
+        let chain = CertificateDer::pem_slice_iter(CERT)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(KEY).unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .unwrap();
+        Acceptor::Rustls(Arc::new(config))
+    };
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_slice(CA).unwrap())
+        .unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let options = ConnectOptions {
+        connector: Some(Connector::Rustls(Arc::new(config))),
+        ..ConnectOptions::default()
+    };
+    let (client, server) = echo(acceptor(), options);
+    assert_eq!(client.peer_certificates, vec![server_certificate()]);
+    assert!(server.peer_certificates.is_empty());
+    assert_eq!(client.protocol_version.as_deref(), Some("TLSv1.3"));
+    assert_eq!(client.cipher_suite, server.cipher_suite);
+
+    echo(acceptor(), insecure(TlsBackend::Rustls));
+}
+
+#[cfg(feature = "openssl-tls")]
+#[test]
+fn openssl() {
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod};
+    use openssl::x509::X509;
+
+    watchdog();
+    let acceptor = || {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        let cert = X509::from_pem(CERT).unwrap();
+        builder.set_certificate(&cert).unwrap();
+        let key = PKey::private_key_from_pem(KEY).unwrap();
+        builder.set_private_key(&key).unwrap();
+        Acceptor::OpenSsl(builder.build())
+    };
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    let ca = X509::from_pem(CA).unwrap();
+    connector.cert_store_mut().add_cert(ca).unwrap();
+    let options = ConnectOptions {
+        connector: Some(Connector::OpenSsl(connector.build())),
+        ..ConnectOptions::default()
+    };
+    let (client, server) = echo(acceptor(), options);
+    assert_eq!(client.peer_certificates, vec![server_certificate()]);
+    assert!(server.peer_certificates.is_empty());
+    assert_eq!(client.protocol_version.as_deref(), Some("TLSv1.3"));
+    assert_eq!(client.cipher_suite, server.cipher_suite);
+
+    echo(acceptor(), insecure(TlsBackend::OpenSsl));
+}
@@ -0,0 +1,56 @@
+//! Verifies that `connect_tls_with_connector` does TLS with the connector it is given, here one
+//! trusting the test certificate authority.
+
+#![cfg(feature = "tls")]
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use tungstenite::client::{connect_tls_with_connector, Connector};
+use tungstenite::{accept, connect, Message};
+
+const CA: &[u8] = include_bytes!("tls/ca.crt");
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+#[test]
+fn test_tls_connector() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let acceptor = TlsAcceptor::new(Identity::from_pkcs8(CERT, KEY).unwrap()).unwrap();
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let server_thread = spawn(move || {
+        // The first client does not trust the certificate and gives up during the TLS handshake.
+        let stream = server.incoming().next().unwrap().unwrap();
+        assert!(acceptor.accept(stream).is_err());
+
+        let stream = server.incoming().next().unwrap().unwrap();
+        let mut websocket = accept(acceptor.accept(stream).unwrap()).unwrap();
+        let message = websocket.read_message().unwrap();
+        websocket.write_message(message).unwrap();
+    });
+
+    assert!(connect(format!("wss://localhost:{}", port)).is_err());
+
+    let connector = TlsConnector::builder()
+        .add_root_certificate(Certificate::from_pem(CA).unwrap())
+        .build()
+        .unwrap();
+    let (mut client, _) = connect_tls_with_connector(
+        format!("wss://localhost:{}", port),
+        Connector::NativeTls(connector),
+    )
+    .unwrap();
+    client.write_message(Message::text("Hello")).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("Hello"));
+
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,68 @@
+//! Verifies the options to accept invalid server certificates.
+
+#![cfg(feature = "tls")]
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use native_tls::{Identity, TlsAcceptor};
+use tungstenite::client::{connect_with_options, ConnectOptions};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::server::{accept_tls, Acceptor};
+
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+#[test]
+fn test_tls_danger() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let identity = Identity::from_pkcs8(CERT, KEY).unwrap();
+    let acceptor = Acceptor::NativeTls(TlsAcceptor::new(identity).unwrap());
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let server_thread = spawn(move || {
+        // The first two clients reject the certificate.
+        for _ in 0..2 {
+            let stream = server.incoming().next().unwrap().unwrap();
+            assert!(accept_tls::<_, UncompressedExt>(stream, &acceptor, None).is_err());
+        }
+        let stream = server.incoming().next().unwrap().unwrap();
+        accept_tls::<_, UncompressedExt>(stream, &acceptor, None).unwrap();
+    });
+
+    // The certificate is neither for the IP address nor from a trusted authority.
+    let result = connect_with_options::<_, UncompressedExt>(
+        format!("wss://127.0.0.1:{}", port),
+        None,
+        ConnectOptions::default(),
+    );
+    assert!(result.is_err());
+
+    // Accepting other names does not make the authority trusted.
+    let options = ConnectOptions {
+        danger_accept_invalid_hostnames: true,
+        ..ConnectOptions::default()
+    };
+    let result = connect_with_options::<_, UncompressedExt>(
+        format!("wss://localhost:{}", port),
+        None,
+        options,
+    );
+    assert!(result.is_err());
+
+    let options = ConnectOptions {
+        danger_accept_invalid_certs: true,
+        ..ConnectOptions::default()
+    };
+    connect_with_options::<_, UncompressedExt>(format!("wss://127.0.0.1:{}", port), None, options)
+        .unwrap();
+
+    server_thread.join().unwrap();
+}
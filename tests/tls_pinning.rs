@@ -0,0 +1,80 @@
+//! Verifies that a certificate verifier can reject the server before the WebSocket handshake.
+
+#![cfg(feature = "tls")]
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use tungstenite::client::{connect_with_options, CertificateVerifier, ConnectOptions, Connector};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::server::{accept_tls, Acceptor};
+use tungstenite::Error;
+
+const CA: &[u8] = include_bytes!("tls/ca.crt");
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+fn options(pinned: Vec<u8>) -> ConnectOptions {
+    let connector = TlsConnector::builder()
+        .add_root_certificate(Certificate::from_pem(CA).unwrap())
+        .build()
+        .unwrap();
+    let verifier = CertificateVerifier::new(move |certificates| {
+        if certificates.first() == Some(&pinned) {
+            Ok(())
+        } else {
+            Err("Not the pinned certificate".into())
+        }
+    });
+    ConnectOptions {
+        connector: Some(Connector::NativeTls(connector)),
+        certificate_verifier: Some(verifier),
+        ..ConnectOptions::default()
+    }
+}
+
+#[test]
+fn test_tls_pinning() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let identity = Identity::from_pkcs8(CERT, KEY).unwrap();
+    let acceptor = Acceptor::NativeTls(TlsAcceptor::new(identity).unwrap());
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let server_thread = spawn(move || {
+        let stream = server.incoming().next().unwrap().unwrap();
+        accept_tls::<_, UncompressedExt>(stream, &acceptor, None).unwrap();
+        // The second client rejects the certificate and hangs up without a request.
+        let stream = server.incoming().next().unwrap().unwrap();
+        assert!(accept_tls::<_, UncompressedExt>(stream, &acceptor, None).is_err());
+    });
+
+    let pinned = Certificate::from_pem(CERT).unwrap().to_der().unwrap();
+    connect_with_options::<_, UncompressedExt>(
+        format!("wss://localhost:{}", port),
+        None,
+        options(pinned),
+    )
+    .unwrap();
+
+    let pinned = Certificate::from_pem(CA).unwrap().to_der().unwrap();
+    let result = connect_with_options::<_, UncompressedExt>(
+        format!("wss://localhost:{}", port),
+        None,
+        options(pinned),
+    );
+    match result {
+        Err(Error::CertificateRejected(_)) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unexpected success"),
+    }
+
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,67 @@
+//! Verifies that the TLS server name can differ from the host connected to.
+
+#![cfg(feature = "tls")]
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use tungstenite::client::{connect_with_options, ConnectOptions, Connector};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::server::{accept_tls, Acceptor};
+
+const CA: &[u8] = include_bytes!("tls/ca.crt");
+const CERT: &[u8] = include_bytes!("tls/localhost.crt");
+const KEY: &[u8] = include_bytes!("tls/localhost.key");
+
+fn options(server_name: Option<&str>) -> ConnectOptions {
+    let connector = TlsConnector::builder()
+        .add_root_certificate(Certificate::from_pem(CA).unwrap())
+        .build()
+        .unwrap();
+    ConnectOptions {
+        connector: Some(Connector::NativeTls(connector)),
+        server_name: server_name.map(String::from),
+        ..ConnectOptions::default()
+    }
+}
+
+#[test]
+fn test_tls_server_name() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let identity = Identity::from_pkcs8(CERT, KEY).unwrap();
+    let acceptor = Acceptor::NativeTls(TlsAcceptor::new(identity).unwrap());
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let server_thread = spawn(move || {
+        // The first client rejects the certificate.
+        let stream = server.incoming().next().unwrap().unwrap();
+        assert!(accept_tls::<_, UncompressedExt>(stream, &acceptor, None).is_err());
+        let stream = server.incoming().next().unwrap().unwrap();
+        accept_tls::<_, UncompressedExt>(stream, &acceptor, None).unwrap();
+    });
+
+    // The certificate is for `localhost`, not for the IP address.
+    let result = connect_with_options::<_, UncompressedExt>(
+        format!("wss://127.0.0.1:{}", port),
+        None,
+        options(None),
+    );
+    assert!(result.is_err());
+
+    connect_with_options::<_, UncompressedExt>(
+        format!("wss://127.0.0.1:{}", port),
+        None,
+        options(Some("localhost")),
+    )
+    .unwrap();
+
+    server_thread.join().unwrap();
+}
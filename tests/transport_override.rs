@@ -0,0 +1,36 @@
+//! Verifies that the transport security may be chosen independently of the URL scheme.
+
+use std::net::TcpListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::{connect_with_options, ConnectOptions};
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::stream::Mode;
+use tungstenite::{accept, Message};
+
+#[test]
+fn test_plain_transport_for_wss() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("wss://{}/", server.local_addr().unwrap());
+    let server_thread = spawn(move || {
+        let mut socket = accept(server.incoming().next().unwrap().unwrap()).unwrap();
+        socket.write_message(Message::text("hello")).unwrap();
+    });
+
+    let options = ConnectOptions {
+        mode: Some(Mode::Plain),
+        ..ConnectOptions::default()
+    };
+    let (mut client, _) = connect_with_options::<_, UncompressedExt>(url, None, options).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("hello"));
+
+    server_thread.join().unwrap();
+}
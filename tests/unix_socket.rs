@@ -0,0 +1,44 @@
+//! Verifies that clients can connect to servers listening on Unix domain sockets.
+#![cfg(unix)]
+
+use std::os::unix::net::UnixListener;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use tungstenite::client::connect_unix;
+use tungstenite::extensions::uncompressed::UncompressedExt;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::{accept_hdr, Message};
+
+#[test]
+fn test_unix_socket() {
+    spawn(|| {
+        sleep(Duration::from_secs(5));
+        println!("Unit test executed too long, perhaps stuck on WOULDBLOCK...");
+        exit(1);
+    });
+
+    let path = std::env::temp_dir().join(format!("tungstenite-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = UnixListener::bind(&path).unwrap();
+
+    let server_thread = spawn(move || {
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| {
+            assert_eq!(request.uri(), "/events");
+            assert_eq!(request.headers()["Host"], "localhost");
+            Ok(response)
+        };
+        let stream = server.incoming().next().unwrap().unwrap();
+        let mut socket = accept_hdr(stream, callback).unwrap();
+        socket.write_message(Message::text("hello")).unwrap();
+    });
+
+    let (mut client, _) =
+        connect_unix::<_, _, UncompressedExt>(&path, "ws://localhost/events", None).unwrap();
+    assert_eq!(client.read_message().unwrap(), Message::text("hello"));
+
+    server_thread.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}